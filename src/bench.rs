@@ -0,0 +1,186 @@
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use crate::chip8::dynarec::Dynarec;
+use crate::chip8::error::Chip8Error;
+use crate::chip8::state::{Chip8Options, Chip8State};
+use crate::options::BenchOptions;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Clone, Copy)]
+enum Engine {
+    Uncached,
+    Cached,
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    Dynarec,
+}
+
+const ENGINES: &[(&str, Engine)] = &[
+    ("uncached", Engine::Uncached),
+    ("cached", Engine::Cached),
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    ("dynarec", Engine::Dynarec),
+];
+
+#[derive(Default)]
+struct BenchResult {
+    instructions: u64,
+    seconds: f64,
+    error: Option<String>,
+}
+
+impl BenchResult {
+    fn instructions_per_second(&self) -> f64 {
+        self.instructions as f64 / self.seconds.max(1e-9)
+    }
+}
+
+// Runs up to count instructions, stopping while waiting for a key. Returns the number run.
+fn interpret(state: &mut Chip8State, count: usize) -> Result<usize, Chip8Error> {
+    let mut executed = 0;
+    while executed < count && !state.is_waiting_for_key() {
+        state.tick()?;
+        executed += 1;
+    }
+
+    Ok(executed)
+}
+
+// Runs a ROM as fast as possible, pressing a different key each frame it waits for one
+fn run_rom(rom: &[u8], options: &BenchOptions, engine: Engine) -> BenchResult {
+    let mut state = Chip8State::with_options(
+        rom.to_vec(),
+        Chip8Options {
+            decode_cache: !matches!(engine, Engine::Uncached),
+            ..Chip8Options::default()
+        },
+    );
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    let mut dynarec = match engine {
+        Engine::Dynarec => match Dynarec::new(&state, false) {
+            Ok(dynarec) => Some(dynarec),
+            Err(e) => {
+                return BenchResult {
+                    error: Some(e.to_string()),
+                    ..BenchResult::default()
+                }
+            }
+        },
+        _ => None,
+    };
+
+    let mut instructions = 0;
+    let mut error = None;
+    let start = Instant::now();
+
+    for frame in 0..options.frames {
+        if state.is_waiting_for_key() {
+            let key = num::FromPrimitive::from_u64(frame % 16).unwrap();
+            state.on_key_pressed(key);
+        }
+
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        let result = match &mut dynarec {
+            Some(dynarec) => dynarec
+                .run(&mut state, options.instructions_per_frame)
+                .map(|_| dynarec.executed()),
+            None => interpret(&mut state, options.instructions_per_frame),
+        };
+        #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+        let result = interpret(&mut state, options.instructions_per_frame);
+
+        match result {
+            Ok(executed) => instructions += executed as u64,
+            Err(e) => {
+                error = Some(e.to_string());
+                break;
+            }
+        }
+
+        state.tick_timers();
+    }
+
+    BenchResult {
+        instructions,
+        seconds: start.elapsed().as_secs_f64(),
+        error,
+    }
+}
+
+fn rom_paths(options: &BenchOptions) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if !options.rom_paths.is_empty() {
+        return Ok(options.rom_paths.iter().map(PathBuf::from).collect());
+    }
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir("roms").map_err(|e| format!("Failed to list roms: {}", e))? {
+        let path = entry?.path();
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn print_row(name: &str, results: &[BenchResult]) {
+    let mut line = format!("{:16} {:12}", name, results[0].instructions);
+    for result in results {
+        line.push_str(&format!(" {:14.0}", result.instructions_per_second()));
+    }
+
+    let last = &results[results.len() - 1];
+    line.push_str(&format!(
+        " {:7.2}x",
+        last.instructions_per_second() / results[0].instructions_per_second()
+    ));
+
+    if let Some(e) = results.iter().find_map(|result| result.error.as_ref()) {
+        line.push_str(&format!("  (stopped: {})", e));
+    }
+
+    println!("{}", line);
+}
+
+// Compares the interpreter with and without the decoded instruction cache, and the dynarec
+// where it is supported, the speedup being the one of the last engine
+pub fn run(options: &BenchOptions) -> Result<(), Box<dyn Error>> {
+    println!(
+        "{} frames of {} instructions per ROM",
+        options.frames, options.instructions_per_frame
+    );
+
+    let mut header = format!("{:16} {:>12}", "ROM", "instructions");
+    for (name, _) in ENGINES {
+        header.push_str(&format!(" {:>14}", format!("{}/s", name)));
+    }
+    println!("{} {:>8}", header, "speedup");
+
+    let mut totals: Vec<BenchResult> = ENGINES.iter().map(|_| BenchResult::default()).collect();
+
+    for path in rom_paths(options)? {
+        let rom = fs::read(&path)?;
+        let name = Path::new(&path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("?");
+
+        let results: Vec<BenchResult> = ENGINES
+            .iter()
+            .map(|&(_, engine)| run_rom(&rom, options, engine))
+            .collect();
+        print_row(name, &results);
+
+        for (total, result) in totals.iter_mut().zip(&results) {
+            total.instructions += result.instructions;
+            total.seconds += result.seconds;
+        }
+    }
+
+    print_row("total", &totals);
+
+    Ok(())
+}
@@ -0,0 +1,241 @@
+use crate::chip8::state::{GRID_HEIGHT, GRID_WIDTH};
+use crate::palette::Palette;
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+pub fn grid_to_ascii(grid: &[bool]) -> String {
+    let mut output = String::with_capacity((GRID_WIDTH + 1) * GRID_HEIGHT);
+
+    for row in grid.chunks(GRID_WIDTH) {
+        for &cell in row {
+            output.push(if cell { '#' } else { '.' });
+        }
+        output.push('\n');
+    }
+
+    output
+}
+
+// Expands the grid into one byte per pixel, each cell becoming a scale x scale block
+fn grid_to_indices(grid: &[bool], scale: usize) -> Vec<u8> {
+    let width = GRID_WIDTH * scale;
+    let height = GRID_HEIGHT * scale;
+
+    let mut indices = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            indices.push(grid[(y / scale) * GRID_WIDTH + x / scale] as u8);
+        }
+    }
+
+    indices
+}
+
+fn color_to_rgb(color: u32) -> [u8; 3] {
+    [(color >> 16) as u8, (color >> 8) as u8, color as u8]
+}
+
+pub fn save_png(
+    grid: &[bool],
+    scale: usize,
+    palette: &Palette,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let data: Vec<u8> = grid_to_indices(grid, scale)
+        .into_iter()
+        .flat_map(|index| color_to_rgb(palette.color(index)).to_vec())
+        .collect();
+
+    let file = BufWriter::new(File::create(path)?);
+
+    let mut encoder = png::Encoder::new(
+        file,
+        (GRID_WIDTH * scale) as u32,
+        (GRID_HEIGHT * scale) as u32,
+    );
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+
+    Ok(())
+}
+
+fn palette_to_rgb(palette: &Palette) -> Vec<u8> {
+    palette
+        .colors()
+        .iter()
+        .flat_map(|&color| color_to_rgb(color).to_vec())
+        .collect()
+}
+
+// Records one frame per emulated 60Hz tick, identical consecutive frames are merged
+// into a single longer GIF frame
+pub struct GifRecorder {
+    encoder: gif::Encoder<BufWriter<File>>,
+    scale: usize,
+    palette: Palette,
+    current_frame: Option<(Vec<bool>, Palette)>,
+    current_frame_start: u64,
+    frame_count: u64,
+}
+
+impl GifRecorder {
+    pub fn new(
+        path: &Path,
+        scale: usize,
+        palette: &Palette,
+    ) -> Result<GifRecorder, Box<dyn Error>> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = gif::Encoder::new(
+            file,
+            (GRID_WIDTH * scale) as u16,
+            (GRID_HEIGHT * scale) as u16,
+            &palette_to_rgb(palette),
+        )?;
+        encoder.write_extension(gif::ExtensionData::Repetitions(gif::Repeat::Infinite))?;
+
+        Ok(GifRecorder {
+            encoder,
+            scale,
+            palette: palette.clone(),
+            current_frame: None,
+            current_frame_start: 0,
+            frame_count: 0,
+        })
+    }
+
+    pub fn add_frame(&mut self, grid: &[bool], palette: &Palette) -> Result<(), Box<dyn Error>> {
+        let unchanged = match &self.current_frame {
+            Some((current_grid, current_palette)) => {
+                current_grid.as_slice() == grid && current_palette == palette
+            }
+            None => false,
+        };
+
+        if !unchanged {
+            self.flush_frame()?;
+            self.current_frame = Some((grid.to_vec(), palette.clone()));
+            self.current_frame_start = self.frame_count;
+        }
+
+        self.frame_count += 1;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        self.flush_frame()
+    }
+
+    fn flush_frame(&mut self) -> Result<(), Box<dyn Error>> {
+        let (grid, palette) = match self.current_frame.take() {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+
+        // GIF delays are in hundredths of a second, round frame boundaries to avoid drifting
+        let to_centiseconds = |frame: u64| (frame * 100 + 30) / 60;
+        let delay = to_centiseconds(self.frame_count) - to_centiseconds(self.current_frame_start);
+
+        let frame = gif::Frame {
+            width: (GRID_WIDTH * self.scale) as u16,
+            height: (GRID_HEIGHT * self.scale) as u16,
+            delay: delay.min(u16::MAX as u64) as u16,
+            buffer: Cow::Owned(grid_to_indices(&grid, self.scale)),
+            // Palette switches during the recording are stored as local color tables
+            palette: if palette == self.palette {
+                None
+            } else {
+                Some(palette_to_rgb(&palette))
+            },
+            ..gif::Frame::default()
+        };
+
+        self.encoder.write_frame(&frame)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gif::SetParameter;
+    use std::env;
+    use std::fs;
+
+    fn grid_with(cells: &[(usize, usize)]) -> Vec<bool> {
+        let mut grid = vec![false; GRID_WIDTH * GRID_HEIGHT];
+        for &(x, y) in cells {
+            grid[y * GRID_WIDTH + x] = true;
+        }
+        grid
+    }
+
+    #[test]
+    fn ascii_has_a_line_per_row() {
+        let ascii = grid_to_ascii(&grid_with(&[(0, 0), (63, 0), (2, 31)]));
+        let lines: Vec<_> = ascii.lines().collect();
+
+        assert_eq!(lines.len(), GRID_HEIGHT);
+        assert!(lines.iter().all(|line| line.len() == GRID_WIDTH));
+        assert_eq!(lines[0], format!("#{}#", ".".repeat(62)));
+        assert_eq!(lines[1], ".".repeat(64));
+        assert!(lines[31].starts_with("..#."));
+        assert!(ascii.ends_with('\n'));
+    }
+
+    #[test]
+    fn indices_expand_cells_into_blocks() {
+        let grid = grid_with(&[(1, 0), (63, 31)]);
+
+        let indices = grid_to_indices(&grid, 1);
+        assert_eq!(indices.len(), GRID_WIDTH * GRID_HEIGHT);
+        assert_eq!(indices[..3], [0, 1, 0]);
+
+        let indices = grid_to_indices(&grid, 3);
+        let width = GRID_WIDTH * 3;
+        assert_eq!(indices.len(), width * GRID_HEIGHT * 3);
+        for row in 0..3 {
+            assert_eq!(indices[row * width..row * width + 7], [0, 0, 0, 1, 1, 1, 0]);
+        }
+        assert_eq!(indices[3 * width + 3], 0);
+        assert_eq!(indices[indices.len() - 3..], [1, 1, 1]);
+        assert_eq!(indices.iter().filter(|&&index| index == 1).count(), 18);
+    }
+
+    #[test]
+    fn merged_frames_keep_their_total_duration() {
+        let path = env::temp_dir().join(format!("chip8-capture-{}.gif", std::process::id()));
+        let palette = Palette::default();
+        let first = grid_with(&[(0, 0)]);
+        let second = grid_with(&[(1, 1)]);
+
+        let mut recorder = GifRecorder::new(&path, 1, &palette).unwrap();
+        for grid in [&first, &second, &second, &second, &first, &first] {
+            recorder.add_frame(grid, &palette).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let mut decoder = gif::Decoder::new(File::open(&path).unwrap());
+        decoder.set(gif::ColorOutput::Indexed);
+        let mut reader = decoder.read_info().unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.read_next_frame().unwrap() {
+            frames.push((frame.delay, frame.buffer.to_vec()));
+        }
+        fs::remove_file(&path).unwrap();
+
+        // 1, 3 and 2 frames at 60Hz, frame boundaries rounded to 0, 2, 7 and 10 centiseconds
+        let delays: Vec<_> = frames.iter().map(|(delay, _)| *delay).collect();
+        assert_eq!(delays, [2, 5, 3]);
+        assert_eq!(frames[0].1, grid_to_indices(&first, 1));
+        assert_eq!(frames[1].1, grid_to_indices(&second, 1));
+    }
+}
@@ -0,0 +1,470 @@
+use crate::chip8::opcodes::Opcode;
+use crate::chip8::state::Chip8State;
+use crate::options::CfgOptions;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+const PROGRAM_START: usize = 0x200;
+
+pub struct Instruction {
+    pub address: usize,
+    pub opcode: Opcode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    // Skip instructions, whose condition held or not
+    Skipped,
+    NotSkipped,
+    Call,
+    // From a call to the instruction following it, once the subroutine returns
+    AfterCall,
+}
+
+pub struct Edge {
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+// Straight-line code, ending with a jump, call, return or skip, before the target of another
+// one, or where the ROM ends
+pub struct Block {
+    pub instructions: Vec<Instruction>,
+    pub end: usize,
+    pub edges: Vec<Edge>,
+}
+
+pub struct Cfg {
+    pub blocks: BTreeMap<usize, Block>,
+    // Blocks reached from the start of the program and from each called address, without
+    // following the calls
+    pub routines: BTreeMap<usize, Vec<usize>>,
+    // ROM bytes which are not part of any block, data or dead code
+    pub unreachable: Vec<(usize, usize)>,
+}
+
+fn read_opcode(rom: &[u8], address: usize) -> Option<u16> {
+    let offset = address.checked_sub(PROGRAM_START)?;
+    let bytes = rom.get(offset..offset + 2)?;
+    Some(u16::from(bytes[0]) << 8 | u16::from(bytes[1]))
+}
+
+fn edge(target: usize, kind: EdgeKind) -> Edge {
+    Edge { target, kind }
+}
+
+// Instructions which do not continue with the next one, and where they may go
+pub fn successors(opcode: &Opcode, address: usize) -> Option<Vec<Edge>> {
+    match *opcode {
+        Opcode::Goto { address: target } => Some(vec![edge(target as usize, EdgeKind::Jump)]),
+        Opcode::CallSubroutine { address: target } => Some(vec![
+            edge(target as usize, EdgeKind::Call),
+            edge(address + 2, EdgeKind::AfterCall),
+        ]),
+        Opcode::CondEq { .. }
+        | Opcode::CondNe { .. }
+        | Opcode::CondVxVyEq { .. }
+        | Opcode::CondVxVyNe { .. }
+        | Opcode::CondKeyPressed { .. }
+        | Opcode::CondKeyReleased { .. } => Some(vec![
+            edge(address + 2, EdgeKind::NotSkipped),
+            edge(address + 4, EdgeKind::Skipped),
+        ]),
+        // Blocks stop while waiting for a key
+        Opcode::WaitKeyPressed { .. } => Some(vec![edge(address + 2, EdgeKind::Fallthrough)]),
+        // Computed jumps cannot be followed, invalid opcodes stop the program
+        Opcode::Return | Opcode::Jump { .. } | Opcode::Invalid { .. } => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+impl Cfg {
+    // Follows the control flow from the start of the program
+    pub fn new(rom: &[u8]) -> Cfg {
+        let mut instructions = BTreeMap::new();
+        let mut leaders = BTreeSet::new();
+        let mut pending = vec![PROGRAM_START];
+        leaders.insert(PROGRAM_START);
+
+        while let Some(address) = pending.pop() {
+            if instructions.contains_key(&address) {
+                continue;
+            }
+            let opcode = match read_opcode(rom, address) {
+                Some(opcode) => Chip8State::decode_instruction(opcode),
+                None => continue,
+            };
+
+            match successors(&opcode, address) {
+                Some(edges) => {
+                    leaders.extend(edges.iter().map(|edge| edge.target));
+                    pending.extend(edges.iter().map(|edge| edge.target));
+                }
+                None => pending.push(address + 2),
+            }
+            instructions.insert(address, opcode);
+        }
+
+        let mut blocks = BTreeMap::new();
+        for &start in leaders
+            .iter()
+            .filter(|start| instructions.contains_key(start))
+        {
+            let mut block = Block {
+                instructions: Vec::new(),
+                end: start,
+                edges: Vec::new(),
+            };
+
+            while let Some(&opcode) = instructions.get(&block.end) {
+                let address = block.end;
+                block.instructions.push(Instruction { address, opcode });
+                block.end += 2;
+
+                if let Some(edges) = successors(&opcode, address) {
+                    block.edges = edges;
+                    break;
+                }
+                if leaders.contains(&block.end) {
+                    block.edges.push(edge(block.end, EdgeKind::Fallthrough));
+                    break;
+                }
+            }
+
+            blocks.insert(start, block);
+        }
+
+        let mut cfg = Cfg {
+            blocks,
+            routines: BTreeMap::new(),
+            unreachable: Vec::new(),
+        };
+        cfg.find_routines();
+        cfg.find_unreachable(rom.len());
+
+        cfg
+    }
+
+    fn find_routines(&mut self) {
+        let mut entries: BTreeSet<usize> = self
+            .blocks
+            .values()
+            .flat_map(|block| &block.edges)
+            .filter(|edge| edge.kind == EdgeKind::Call && self.blocks.contains_key(&edge.target))
+            .map(|edge| edge.target)
+            .collect();
+        entries.insert(PROGRAM_START);
+
+        for entry in entries {
+            let mut reached = BTreeSet::new();
+            let mut pending = vec![entry];
+
+            while let Some(start) = pending.pop() {
+                let block = match self.blocks.get(&start) {
+                    Some(block) if reached.insert(start) => block,
+                    _ => continue,
+                };
+
+                pending.extend(
+                    block
+                        .edges
+                        .iter()
+                        .filter(|edge| edge.kind != EdgeKind::Call)
+                        .map(|edge| edge.target),
+                );
+            }
+
+            self.routines.insert(entry, reached.into_iter().collect());
+        }
+    }
+
+    // Merged ranges of memory covered by blocks
+    pub fn code_ranges(&self) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for (&start, block) in &self.blocks {
+            match ranges.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(block.end),
+                _ => ranges.push((start, block.end)),
+            }
+        }
+
+        ranges
+    }
+
+    fn find_unreachable(&mut self, rom_size: usize) {
+        let mut address = PROGRAM_START;
+        for (start, end) in self.code_ranges() {
+            if address < start {
+                self.unreachable.push((address, start));
+            }
+            address = end;
+        }
+
+        if address < PROGRAM_START + rom_size {
+            self.unreachable.push((address, PROGRAM_START + rom_size));
+        }
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.blocks
+            .values()
+            .map(|block| block.instructions.len())
+            .sum()
+    }
+
+    // Routine whose cluster holds each block, its own for an entry and otherwise the first one
+    // reaching it
+    fn owners(&self) -> BTreeMap<usize, usize> {
+        let mut owners: BTreeMap<usize, usize> = self
+            .routines
+            .keys()
+            .filter(|entry| self.blocks.contains_key(entry))
+            .map(|&entry| (entry, entry))
+            .collect();
+        for (&entry, blocks) in &self.routines {
+            for &start in blocks {
+                owners.entry(start).or_insert(entry);
+            }
+        }
+
+        owners
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn write_block(output: &mut String, start: usize, block: &Block) {
+    let mut label = String::new();
+    for Instruction { address, opcode } in &block.instructions {
+        write!(label, "{:03X}: {}\\l", address, escape(&opcode.to_string())).unwrap();
+    }
+
+    writeln!(output, "        b{:03X} [label=\"{}\"];", start, label).unwrap();
+}
+
+pub fn to_dot(cfg: &Cfg, name: &str) -> String {
+    let mut output = String::new();
+    writeln!(output, "digraph \"{}\" {{", escape(name)).unwrap();
+    writeln!(output, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+    let owners = cfg.owners();
+    for &entry in cfg.routines.keys() {
+        let blocks: Vec<usize> = owners
+            .iter()
+            .filter(|&(_, &owner)| owner == entry)
+            .map(|(&start, _)| start)
+            .collect();
+        if blocks.is_empty() {
+            continue;
+        }
+
+        let label = if entry == PROGRAM_START {
+            String::from("program")
+        } else {
+            format!("subroutine {:03X}", entry)
+        };
+
+        writeln!(output, "\n    subgraph cluster_{:03X} {{", entry).unwrap();
+        writeln!(output, "        label=\"{}\";", label).unwrap();
+        for start in blocks {
+            write_block(&mut output, start, &cfg.blocks[&start]);
+        }
+        writeln!(output, "    }}").unwrap();
+    }
+
+    writeln!(output).unwrap();
+    for &(start, end) in &cfg.unreachable {
+        writeln!(
+            output,
+            "    u{:03X} [label=\"{:03X}-{:03X}: unreachable, {} bytes\", style=dashed, color=gray];",
+            start,
+            start,
+            end - 1,
+            end - start
+        )
+        .unwrap();
+    }
+
+    // Jumps and calls outside the ROM
+    let outside: BTreeSet<usize> = cfg
+        .blocks
+        .values()
+        .flat_map(|block| &block.edges)
+        .map(|edge| edge.target)
+        .filter(|target| !cfg.blocks.contains_key(target))
+        .collect();
+    for target in outside {
+        writeln!(
+            output,
+            "    b{:03X} [label=\"{:03X}: outside the ROM\", shape=ellipse];",
+            target, target
+        )
+        .unwrap();
+    }
+
+    writeln!(output).unwrap();
+    for (&start, block) in &cfg.blocks {
+        for edge in &block.edges {
+            let attributes = match edge.kind {
+                EdgeKind::Fallthrough | EdgeKind::Jump => "",
+                EdgeKind::Skipped => " [label=\"skip\"]",
+                EdgeKind::NotSkipped => " [label=\"no skip\"]",
+                EdgeKind::Call => " [label=\"call\", style=dashed]",
+                EdgeKind::AfterCall => " [style=dotted]",
+            };
+
+            writeln!(
+                output,
+                "    b{:03X} -> b{:03X}{};",
+                start, edge.target, attributes
+            )
+            .unwrap();
+        }
+    }
+
+    writeln!(output, "}}").unwrap();
+
+    output
+}
+
+pub fn run(options: &CfgOptions) -> Result<(), Box<dyn Error>> {
+    let rom = fs::read(&options.rom_path).map_err(|e| format!("Failed to open file: {}", e))?;
+
+    let cfg = Cfg::new(&rom);
+    let name = Path::new(&options.rom_path)
+        .file_name()
+        .map_or(options.rom_path.clone(), |name| {
+            name.to_string_lossy().into_owned()
+        });
+    let dot = to_dot(&cfg, &name);
+
+    if options.output_path == "-" {
+        io::stdout().write_all(dot.as_bytes())?;
+        return Ok(());
+    }
+
+    fs::write(&options.output_path, dot)
+        .map_err(|e| format!("Failed to write {}: {}", options.output_path, e))?;
+
+    let unreachable: usize = cfg.unreachable.iter().map(|(start, end)| end - start).sum();
+    println!(
+        "{} blocks ({} instructions), {} subroutines, {} unreachable bytes in {} regions, written to {}",
+        cfg.blocks.len(),
+        cfg.instruction_count(),
+        cfg.routines.len() - 1,
+        unreachable,
+        cfg.unreachable.len(),
+        options.output_path
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(opcodes: &[u16]) -> Vec<u8> {
+        opcodes
+            .iter()
+            .flat_map(|opcode| opcode.to_be_bytes())
+            .collect()
+    }
+
+    fn edges(cfg: &Cfg, start: usize) -> Vec<(usize, EdgeKind)> {
+        cfg.blocks[&start]
+            .edges
+            .iter()
+            .map(|edge| (edge.target, edge.kind))
+            .collect()
+    }
+
+    #[test]
+    fn skip_instructions_branch_to_both_following_instructions() {
+        for &skip in &[0x3005, 0x4005, 0x5010, 0x9010, 0xE09E, 0xE0A1] {
+            let cfg = Cfg::new(&rom(&[skip, 0x6001, 0x6102, 0x1206]));
+
+            assert_eq!(
+                edges(&cfg, 0x200),
+                [(0x202, EdgeKind::NotSkipped), (0x204, EdgeKind::Skipped)],
+                "{:04X}",
+                skip
+            );
+            assert_eq!(edges(&cfg, 0x202), [(0x204, EdgeKind::Fallthrough)]);
+            assert_eq!(edges(&cfg, 0x204), [(0x206, EdgeKind::Fallthrough)]);
+            assert_eq!(edges(&cfg, 0x206), [(0x206, EdgeKind::Jump)]);
+        }
+    }
+
+    #[test]
+    fn calls_continue_after_the_subroutine() {
+        // CALL 206, JP 202, data, LD V0 01, RET
+        let cfg = Cfg::new(&rom(&[0x2206, 0x1202, 0x0000, 0x6001, 0x00EE]));
+
+        assert_eq!(
+            edges(&cfg, 0x200),
+            [(0x206, EdgeKind::Call), (0x202, EdgeKind::AfterCall)]
+        );
+        assert!(edges(&cfg, 0x206).is_empty());
+        assert_eq!(cfg.routines[&0x200], [0x200, 0x202]);
+        assert_eq!(cfg.routines[&0x206], [0x206]);
+        assert_eq!(cfg.blocks[&0x206].instructions.len(), 2);
+    }
+
+    #[test]
+    fn unreachable_ranges_cover_the_bytes_outside_blocks() {
+        // JP 206, data, LD V0 01, RET and a trailing byte
+        let mut bytes = rom(&[0x1206, 0x1234, 0x5678, 0x6001, 0x00EE]);
+        bytes.push(0xFF);
+        let cfg = Cfg::new(&bytes);
+
+        assert_eq!(cfg.code_ranges(), [(0x200, 0x202), (0x206, 0x20A)]);
+        assert_eq!(cfg.unreachable, [(0x202, 0x206), (0x20A, 0x20B)]);
+    }
+
+    #[test]
+    fn targets_outside_the_rom_have_no_block() {
+        // SE V0 00, JP 400, CALL 100
+        let cfg = Cfg::new(&rom(&[0x3000, 0x1400, 0x2100]));
+
+        assert_eq!(edges(&cfg, 0x202), [(0x400, EdgeKind::Jump)]);
+        assert_eq!(
+            edges(&cfg, 0x204),
+            [(0x100, EdgeKind::Call), (0x206, EdgeKind::AfterCall)]
+        );
+        assert!(!cfg.blocks.contains_key(&0x400));
+        assert!(!cfg.blocks.contains_key(&0x100));
+        assert!(!cfg.routines.contains_key(&0x100));
+        assert!(cfg.unreachable.is_empty());
+
+        let dot = to_dot(&cfg, "test");
+        assert!(dot.contains("b400 [label=\"400: outside the ROM\", shape=ellipse];"));
+        assert!(dot.contains("b100 [label=\"100: outside the ROM\", shape=ellipse];"));
+        assert!(dot.contains("b202 -> b400;"));
+    }
+
+    #[test]
+    fn subroutines_jumped_to_by_the_program_keep_their_cluster() {
+        // CALL 206, JP 206, data, RET
+        let cfg = Cfg::new(&rom(&[0x2206, 0x1206, 0x0000, 0x00EE]));
+        let dot = to_dot(&cfg, "test");
+
+        let cluster = &dot[dot.find("subgraph cluster_206").unwrap()..];
+        let cluster = &cluster[..cluster.find('}').unwrap()];
+        assert!(cluster.contains("b206 [label=\"206: RET\\l\"];"));
+
+        // Every cluster holds a block
+        for cluster in dot.split("subgraph cluster_").skip(1) {
+            let body = &cluster[..cluster.find('}').unwrap()];
+            assert!(body.contains("[label="), "{}", body);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use super::error::Chip8Error;
+use super::opcodes::Opcode;
+use super::state::Chip8State;
+use std::ops::Range;
+
+pub const MEMORY_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    // Fetching an instruction, two accesses per instruction
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryAccess {
+    // Address of the instruction making the access
+    pub pc: usize,
+    pub address: usize,
+    pub kind: AccessKind,
+    // Value read or written
+    pub value: u8,
+    // Writing to an address executed before, usually self-modifying code
+    pub modifies_code: bool,
+}
+
+pub type MemoryObserver = Box<dyn FnMut(&MemoryAccess)>;
+
+// Memory as seen by CHIP-8 programs: accesses are bounds checked, reported to the observers
+// and kept until the next instruction starts
+pub struct MemoryBus {
+    memory: [u8; MEMORY_SIZE],
+    // Instructions decoded at each address, dropped when their memory is written
+    decoded: Option<Vec<Option<(u16, Opcode)>>>,
+    executed: Vec<bool>,
+    observers: Vec<MemoryObserver>,
+    accesses: Vec<MemoryAccess>,
+}
+
+impl MemoryBus {
+    pub fn new(memory: [u8; MEMORY_SIZE], decode_cache: bool) -> MemoryBus {
+        MemoryBus {
+            memory,
+            decoded: if decode_cache {
+                Some(vec![None; MEMORY_SIZE])
+            } else {
+                None
+            },
+            executed: vec![false; MEMORY_SIZE],
+            observers: Vec::new(),
+            accesses: Vec::new(),
+        }
+    }
+
+    pub fn add_observer(&mut self, observer: MemoryObserver) {
+        self.observers.push(observer);
+    }
+
+    pub(super) fn checked_address(pc: usize, address: usize) -> Result<usize, Chip8Error> {
+        if address < MEMORY_SIZE {
+            Ok(address)
+        } else {
+            Err(Chip8Error::MemoryOutOfBounds {
+                address: pc,
+                access: address,
+            })
+        }
+    }
+
+    // Instructions start at any address, so a byte belongs to two of them
+    pub(super) fn invalidate(&mut self, range: Range<usize>) {
+        if let Some(decoded) = &mut self.decoded {
+            for entry in &mut decoded[range.start.saturating_sub(1)..range.end] {
+                *entry = None;
+            }
+        }
+    }
+
+    fn notify(&mut self, pc: usize, address: usize, kind: AccessKind, value: u8) {
+        let access = MemoryAccess {
+            pc,
+            address,
+            kind,
+            value,
+            modifies_code: kind == AccessKind::Write && self.executed[address],
+        };
+
+        for observer in &mut self.observers {
+            observer(&access);
+        }
+        self.accesses.push(access);
+    }
+
+    pub fn read(&mut self, pc: usize, address: usize) -> Result<u8, Chip8Error> {
+        let address = MemoryBus::checked_address(pc, address)?;
+        let value = self.memory[address];
+        self.notify(pc, address, AccessKind::Read, value);
+
+        Ok(value)
+    }
+
+    pub fn write(&mut self, pc: usize, address: usize, value: u8) -> Result<(), Chip8Error> {
+        let address = MemoryBus::checked_address(pc, address)?;
+        self.memory[address] = value;
+        self.invalidate(address..address + 1);
+        self.notify(pc, address, AccessKind::Write, value);
+
+        Ok(())
+    }
+
+    // Starts a new instruction, forgetting the accesses of the previous one
+    pub fn fetch(&mut self, pc: usize) -> Result<(u16, Opcode), Chip8Error> {
+        self.accesses.clear();
+
+        let cached = self
+            .decoded
+            .as_ref()
+            .and_then(|decoded| decoded.get(pc).copied().flatten());
+        let (opcode, decoded) = match cached {
+            Some(entry) => entry,
+            None => {
+                let opcode = self.peek_opcode(pc)?;
+                let entry = (opcode, Chip8State::decode_instruction(opcode));
+                if let Some(decoded) = &mut self.decoded {
+                    decoded[pc] = Some(entry);
+                }
+
+                entry
+            }
+        };
+
+        self.executed[pc] = true;
+        self.executed[pc + 1] = true;
+        self.notify(pc, pc, AccessKind::Execute, (opcode >> 8) as u8);
+        self.notify(pc, pc + 1, AccessKind::Execute, opcode as u8);
+
+        Ok((opcode, decoded))
+    }
+
+    // Reads an instruction without notifying the observers
+    pub fn peek_opcode(&self, pc: usize) -> Result<u16, Chip8Error> {
+        let address = MemoryBus::checked_address(pc, pc + 1)? - 1;
+
+        Ok(u16::from(self.memory[address]) << 8 | u16::from(self.memory[address + 1]))
+    }
+
+    // Accesses made by the current instruction
+    pub fn accesses(&self) -> &[MemoryAccess] {
+        &self.accesses
+    }
+
+    // Direct access, for the interpreter state stored in memory and for tools
+    pub fn memory(&self) -> &[u8; MEMORY_SIZE] {
+        &self.memory
+    }
+
+    pub fn memory_range_mut(&mut self, range: Range<usize>) -> &mut [u8] {
+        self.invalidate(range.clone());
+        &mut self.memory[range]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn bus(program: &[u8], decode_cache: bool) -> MemoryBus {
+        let mut memory = [0; MEMORY_SIZE];
+        memory[0x200..0x200 + program.len()].copy_from_slice(program);
+
+        MemoryBus::new(memory, decode_cache)
+    }
+
+    // PC, address, kind and value of each access
+    type Accesses = Rc<RefCell<Vec<(usize, usize, AccessKind, u8)>>>;
+
+    fn observe(bus: &mut MemoryBus) -> Accesses {
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let observed = Rc::clone(&accesses);
+        bus.add_observer(Box::new(move |access| {
+            observed
+                .borrow_mut()
+                .push((access.pc, access.address, access.kind, access.value));
+        }));
+
+        accesses
+    }
+
+    fn is_decoded(bus: &MemoryBus, address: usize) -> bool {
+        bus.decoded.as_ref().unwrap()[address].is_some()
+    }
+
+    #[test]
+    fn observers_see_every_access() {
+        let mut bus = bus(&[0xF1, 0x65, 0x42], false);
+        let accesses = observe(&mut bus);
+
+        assert_eq!(bus.fetch(0x200).unwrap().0, 0xF165);
+        assert_eq!(bus.read(0x200, 0x202).unwrap(), 0x42);
+        bus.write(0x200, 0x300, 0x07).unwrap();
+
+        assert_eq!(
+            *accesses.borrow(),
+            [
+                (0x200, 0x200, AccessKind::Execute, 0xF1),
+                (0x200, 0x201, AccessKind::Execute, 0x65),
+                (0x200, 0x202, AccessKind::Read, 0x42),
+                (0x200, 0x300, AccessKind::Write, 0x07),
+            ]
+        );
+        assert_eq!(bus.memory()[0x300], 0x07);
+    }
+
+    #[test]
+    fn accesses_are_kept_until_the_next_fetch() {
+        let mut bus = bus(&[0x60, 0x01, 0x60, 0x02], false);
+
+        bus.fetch(0x200).unwrap();
+        bus.read(0x200, 0x300).unwrap();
+        assert_eq!(bus.accesses().len(), 3);
+
+        bus.fetch(0x202).unwrap();
+        let addresses: Vec<_> = bus.accesses().iter().map(|access| access.address).collect();
+        assert_eq!(addresses, [0x202, 0x203]);
+    }
+
+    #[test]
+    fn out_of_bounds_accesses_fail_unobserved() {
+        let mut bus = bus(&[], false);
+        let accesses = observe(&mut bus);
+
+        let error = Chip8Error::MemoryOutOfBounds {
+            address: 0x200,
+            access: MEMORY_SIZE,
+        };
+        assert_eq!(bus.read(0x200, MEMORY_SIZE), Err(error.clone()));
+        assert_eq!(bus.write(0x200, MEMORY_SIZE, 0), Err(error));
+        assert_eq!(
+            bus.fetch(MEMORY_SIZE - 1).unwrap_err(),
+            Chip8Error::MemoryOutOfBounds {
+                address: MEMORY_SIZE - 1,
+                access: MEMORY_SIZE,
+            }
+        );
+        assert!(accesses.borrow().is_empty());
+    }
+
+    #[test]
+    fn writes_drop_the_instructions_covering_them() {
+        let mut bus = bus(&[0x60, 0x01, 0x61, 0x02, 0x62, 0x03], true);
+        for address in 0x200..0x205 {
+            bus.fetch(address).unwrap();
+        }
+
+        // 0x201 straddles the written byte
+        bus.write(0x204, 0x202, 0x71).unwrap();
+        assert!(is_decoded(&bus, 0x200));
+        assert!(!is_decoded(&bus, 0x201));
+        assert!(!is_decoded(&bus, 0x202));
+        assert!(is_decoded(&bus, 0x203));
+
+        assert_eq!(bus.fetch(0x201).unwrap().0, 0x0171);
+        assert_eq!(bus.fetch(0x202).unwrap().0, 0x7102);
+    }
+
+    #[test]
+    fn direct_writes_drop_the_instructions_covering_them() {
+        let mut bus = bus(&[0x60, 0x01, 0x61, 0x02, 0x62, 0x03], true);
+        for address in 0x200..0x205 {
+            bus.fetch(address).unwrap();
+        }
+
+        bus.memory_range_mut(0x202..0x204)
+            .copy_from_slice(&[0x71, 0x05]);
+        assert!(is_decoded(&bus, 0x200));
+        assert!(!is_decoded(&bus, 0x201));
+        assert!(!is_decoded(&bus, 0x202));
+        assert!(!is_decoded(&bus, 0x203));
+        assert!(is_decoded(&bus, 0x204));
+
+        assert_eq!(bus.fetch(0x202).unwrap().0, 0x7105);
+    }
+
+    #[test]
+    fn the_decode_cache_is_optional() {
+        let mut bus = bus(&[0x60, 0x01], false);
+
+        bus.fetch(0x200).unwrap();
+        assert!(bus.decoded.is_none());
+
+        bus.memory_range_mut(0x201..0x202)[0] = 0x02;
+        assert_eq!(bus.fetch(0x200).unwrap().0, 0x6002);
+    }
+}
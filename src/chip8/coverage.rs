@@ -0,0 +1,355 @@
+use super::bus::{AccessKind, MemoryAccess};
+use super::opcodes::Opcode;
+use super::state::Chip8State;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+const PROGRAM_START: usize = 0x200;
+
+// Byte flags
+const EXECUTED: u8 = 1;
+const READ: u8 = 2;
+const WRITTEN: u8 = 4;
+// First byte of an executed instruction
+const INSTRUCTION: u8 = 8;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CoverageFormat {
+    // Annotated disassembly of the ROM
+    Listing,
+    // lcov tracefile, addresses standing for line numbers
+    Lcov,
+}
+
+enum Line {
+    Instruction { address: usize, executions: u64 },
+    Data { address: usize },
+}
+
+// Records how each byte of memory was accessed during a run
+pub struct Coverage {
+    output: BufWriter<File>,
+    format: CoverageFormat,
+    rom_path: String,
+    rom: Vec<u8>,
+    flags: Vec<u8>,
+    executions: Vec<u64>,
+    // Times each skip instruction did not skip and skipped
+    branches: BTreeMap<usize, [u64; 2]>,
+}
+
+impl Coverage {
+    pub fn create(
+        path: &str,
+        format: CoverageFormat,
+        rom_path: &str,
+        rom: &[u8],
+    ) -> io::Result<Coverage> {
+        Ok(Coverage {
+            output: BufWriter::new(File::create(path)?),
+            format,
+            rom_path: rom_path.to_owned(),
+            rom: rom.to_vec(),
+            flags: vec![0; 4096],
+            executions: vec![0; 4096],
+            branches: BTreeMap::new(),
+        })
+    }
+
+    // Called after the instruction, next_address being the address of the next one
+    pub fn record(
+        &mut self,
+        address: usize,
+        opcode: &Opcode,
+        accesses: &[MemoryAccess],
+        next_address: usize,
+    ) {
+        self.flags[address] |= INSTRUCTION;
+        self.executions[address] += 1;
+
+        for access in accesses {
+            self.flags[access.address] |= match access.kind {
+                AccessKind::Read => READ,
+                AccessKind::Write => WRITTEN,
+                AccessKind::Execute => EXECUTED,
+            };
+        }
+
+        if matches!(
+            opcode,
+            Opcode::CondEq { .. }
+                | Opcode::CondNe { .. }
+                | Opcode::CondVxVyEq { .. }
+                | Opcode::CondVxVyNe { .. }
+                | Opcode::CondKeyPressed { .. }
+                | Opcode::CondKeyReleased { .. }
+        ) {
+            let skipped = next_address == address + 4;
+            self.branches.entry(address).or_default()[skipped as usize] += 1;
+        }
+    }
+
+    fn flags(&self, address: usize) -> u8 {
+        self.flags.get(address).copied().unwrap_or(0)
+    }
+
+    // Executed instructions, followed by what was never executed: pairs of bytes which are
+    // neither read nor written are counted as instructions, the rest as data
+    fn lines(&self) -> Vec<Line> {
+        let end = PROGRAM_START + self.rom.len();
+        let mut lines = Vec::new();
+
+        let mut address = PROGRAM_START;
+        while address < end {
+            let flags = self.flags(address);
+            let next_flags = if address + 1 < end {
+                self.flags(address + 1)
+            } else {
+                READ
+            };
+
+            if flags & INSTRUCTION != 0 || (flags | next_flags) & (EXECUTED | READ | WRITTEN) == 0 {
+                lines.push(Line::Instruction {
+                    address,
+                    executions: self.executions[address],
+                });
+                address += 2;
+            } else {
+                lines.push(Line::Data { address });
+                address += 1;
+            }
+        }
+
+        lines
+    }
+
+    fn rom_byte(&self, address: usize) -> u8 {
+        self.rom.get(address - PROGRAM_START).copied().unwrap_or(0)
+    }
+
+    fn access_markers(&self, address: usize) -> String {
+        let flags = self.flags(address);
+        [(EXECUTED, 'x'), (READ, 'r'), (WRITTEN, 'w')]
+            .iter()
+            .map(|&(flag, marker)| if flags & flag != 0 { marker } else { '-' })
+            .collect()
+    }
+
+    fn write_listing(&mut self) -> io::Result<()> {
+        let lines = self.lines();
+
+        let instructions = lines
+            .iter()
+            .filter(|line| matches!(line, Line::Instruction { .. }))
+            .count();
+        let executed = lines
+            .iter()
+            .filter(|line| matches!(line, Line::Instruction { executions, .. } if *executions > 0))
+            .count();
+        let branches = self.branches.len() * 2;
+        let taken_branches: usize = self
+            .branches
+            .values()
+            .map(|counts| counts.iter().filter(|&&count| count > 0).count())
+            .sum();
+
+        writeln!(self.output, "; {}", self.rom_path)?;
+        writeln!(
+            self.output,
+            "; {} of {} instructions executed, {} of {} skip outcomes reached",
+            executed, instructions, taken_branches, branches
+        )?;
+        writeln!(
+            self.output,
+            "; x: executed, r: read, w: written, !: skip outcome never reached"
+        )?;
+        writeln!(self.output)?;
+
+        for line in &lines {
+            match *line {
+                Line::Instruction {
+                    address,
+                    executions,
+                } => {
+                    let opcode = u16::from(self.rom_byte(address)) << 8
+                        | u16::from(self.rom_byte(address + 1));
+                    let branch = match self.branches.get(&address) {
+                        Some(counts) if counts.contains(&0) => "!",
+                        _ => " ",
+                    };
+                    let count = if executions > 0 {
+                        executions.to_string()
+                    } else {
+                        String::from("-")
+                    };
+
+                    writeln!(
+                        self.output,
+                        "{:03X}  {:04X}  {}{} {:>8}  {}",
+                        address,
+                        opcode,
+                        self.access_markers(address),
+                        branch,
+                        count,
+                        Chip8State::decode_instruction(opcode)
+                    )?;
+                }
+                Line::Data { address } => {
+                    let value = self.rom_byte(address);
+                    writeln!(
+                        self.output,
+                        "{:03X}  {:02X}    {}  {:>8}  DB #{:02X}",
+                        address,
+                        value,
+                        self.access_markers(address),
+                        "",
+                        value
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_lcov(&mut self) -> io::Result<()> {
+        let lines = self.lines();
+
+        writeln!(self.output, "TN:")?;
+        writeln!(self.output, "SF:{}", self.rom_path)?;
+
+        let mut found = 0;
+        let mut hit = 0;
+        for line in &lines {
+            if let Line::Instruction {
+                address,
+                executions,
+            } = *line
+            {
+                writeln!(self.output, "DA:{},{}", address, executions)?;
+                found += 1;
+                if executions > 0 {
+                    hit += 1;
+                }
+            }
+        }
+
+        let mut branches_hit = 0;
+        for (address, counts) in &self.branches {
+            for (branch, count) in counts.iter().enumerate() {
+                writeln!(self.output, "BRDA:{},0,{},{}", address, branch, count)?;
+                if *count > 0 {
+                    branches_hit += 1;
+                }
+            }
+        }
+
+        writeln!(self.output, "BRF:{}", self.branches.len() * 2)?;
+        writeln!(self.output, "BRH:{}", branches_hit)?;
+        writeln!(self.output, "LF:{}", found)?;
+        writeln!(self.output, "LH:{}", hit)?;
+        writeln!(self.output, "end_of_record")?;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.format {
+            CoverageFormat::Listing => self.write_listing()?,
+            CoverageFormat::Lcov => self.write_lcov()?,
+        }
+
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::state::Chip8Options;
+    use std::env;
+    use std::fs;
+
+    const PROGRAM: [u16; 9] = [
+        0xA20E, // LD I, 20E
+        0x3000, // SE V0, 00, always skips
+        0x6001, // LD V0, 01, never executed
+        0xF165, // LD V1, [I], reads 20E and 20F
+        0xA210, // LD I, 210
+        0xF055, // LD [I], V0, writes 210
+        0x120C, // JP 20C
+        0x1234, // data
+        0x0000, // data
+    ];
+
+    fn coverage(format: CoverageFormat) -> String {
+        let path = env::temp_dir()
+            .join(format!(
+                "chip8-coverage-{}-{}",
+                format == CoverageFormat::Lcov,
+                std::process::id()
+            ))
+            .to_str()
+            .unwrap()
+            .to_owned();
+
+        let rom: Vec<u8> = PROGRAM
+            .iter()
+            .flat_map(|opcode| opcode.to_be_bytes())
+            .collect();
+        let mut state = Chip8State::with_options(rom.clone(), Chip8Options::default());
+        state.set_coverage(Coverage::create(&path, format, "test.ch8", &rom).unwrap());
+        for _ in 0..7 {
+            state.tick().unwrap();
+        }
+        state.take_coverage().unwrap().finish().unwrap();
+
+        let output = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        output
+    }
+
+    #[test]
+    fn listing_marks_accessed_bytes() {
+        let listing = coverage(CoverageFormat::Listing);
+        let line = |address: &str| {
+            listing
+                .lines()
+                .find(|line| line.starts_with(address))
+                .unwrap_or_else(|| panic!("no line at {} in {}", address, listing))
+        };
+
+        assert!(listing.contains("; 6 of 7 instructions executed, 1 of 2 skip outcomes reached"));
+        assert!(line("200").starts_with("200  A20E  x--         1  LD I, #20E"));
+        // The instruction was skipped, the other outcome was never reached
+        assert!(line("202").starts_with("202  3000  x--!        1"));
+        assert!(line("204").starts_with("204  6001  ---         -"));
+        assert!(line("20C").starts_with("20C  120C  x--         2"));
+        assert!(line("20E").starts_with("20E  12    -r-"));
+        assert!(line("20F").starts_with("20F  34    -r-"));
+        assert!(line("210").starts_with("210  00    --w"));
+        assert!(line("211").starts_with("211  00    ---"));
+    }
+
+    #[test]
+    fn lcov_lists_executed_addresses() {
+        let lcov = coverage(CoverageFormat::Lcov);
+        let lines: Vec<_> = lcov.lines().collect();
+
+        assert_eq!(lines[1], "SF:test.ch8");
+        // Addresses in decimal, 0x200 being 512
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|line| line.starts_with("DA:"))
+                .copied()
+                .collect::<Vec<_>>(),
+            ["DA:512,1", "DA:514,1", "DA:516,0", "DA:518,1", "DA:520,1", "DA:522,1", "DA:524,2",]
+        );
+        assert!(lines.contains(&"BRDA:514,0,0,0"));
+        assert!(lines.contains(&"BRDA:514,0,1,1"));
+        assert!(lines.contains(&"LF:7"));
+        assert!(lines.contains(&"LH:6"));
+    }
+}
@@ -0,0 +1,74 @@
+use std::io;
+use std::ptr;
+
+// Executable memory, writable only while code is being copied into it
+pub struct CodeBuffer {
+    memory: *mut u8,
+    size: usize,
+    used: usize,
+}
+
+impl CodeBuffer {
+    pub fn new(size: usize) -> io::Result<CodeBuffer> {
+        let memory = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if memory == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(CodeBuffer {
+            memory: memory as *mut u8,
+            size,
+            used: 0,
+        })
+    }
+
+    fn protect(&mut self, protection: libc::c_int) -> io::Result<()> {
+        if unsafe { libc::mprotect(self.memory as *mut libc::c_void, self.size, protection) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    // Returns the address of the copied code, or None once the buffer is full
+    pub fn push(&mut self, code: &[u8]) -> io::Result<Option<*const u8>> {
+        if self.used + code.len() > self.size {
+            return Ok(None);
+        }
+
+        self.protect(libc::PROT_READ | libc::PROT_WRITE)?;
+        let address = unsafe {
+            let address = self.memory.add(self.used);
+            ptr::copy_nonoverlapping(code.as_ptr(), address, code.len());
+            address
+        };
+        self.protect(libc::PROT_READ | libc::PROT_EXEC)?;
+
+        // Keeps the code of the next block aligned
+        self.used = (self.used + code.len() + 15) & !15;
+
+        Ok(Some(address))
+    }
+
+    // Forgets all the code, which must not be run anymore
+    pub fn clear(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.memory as *mut libc::c_void, self.size);
+        }
+    }
+}
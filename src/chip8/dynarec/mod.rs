@@ -0,0 +1,418 @@
+use super::bus::{AccessKind, MEMORY_SIZE};
+use super::error::Chip8Error;
+use super::state::Chip8State;
+use std::io;
+use std::mem;
+use std::ops::Range;
+
+mod code_buffer;
+mod x86_64;
+
+use self::code_buffer::CodeBuffer;
+use self::x86_64::Assembler;
+
+// Longer blocks are split, a block only running when the instruction budget allows it
+const MAX_BLOCK_LENGTH: usize = 64;
+
+const CODE_BUFFER_SIZE: usize = 4 << 20;
+
+// Machine state used by the translated code, the registers coming first so that their
+// offset is their index
+#[repr(C)]
+struct Context {
+    registers: [u8; 16],
+    index_register: u16,
+    delay_timer: u8,
+    stack_size: u32,
+    stack: *mut u16,
+    memory: *mut u8,
+    // Set by the blocks when they return
+    program_counter: u32,
+    executed: u32,
+    // Memory written since the last check, empty when the start is not below the end
+    dirty_start: u32,
+    dirty_end: u32,
+}
+
+const INDEX_REGISTER: u8 = mem::offset_of!(Context, index_register) as u8;
+const DELAY_TIMER: u8 = mem::offset_of!(Context, delay_timer) as u8;
+const STACK_SIZE: u8 = mem::offset_of!(Context, stack_size) as u8;
+const STACK: u8 = mem::offset_of!(Context, stack) as u8;
+const MEMORY: u8 = mem::offset_of!(Context, memory) as u8;
+const PROGRAM_COUNTER: u8 = mem::offset_of!(Context, program_counter) as u8;
+const EXECUTED: u8 = mem::offset_of!(Context, executed) as u8;
+const DIRTY_START: u8 = mem::offset_of!(Context, dirty_start) as u8;
+const DIRTY_END: u8 = mem::offset_of!(Context, dirty_end) as u8;
+
+type BlockFunction = unsafe extern "sysv64" fn(*mut Context);
+
+#[derive(Clone, Copy)]
+enum Entry {
+    Unknown,
+    // The instruction at this address is run by the interpreter
+    Interpreted,
+    Native {
+        function: BlockFunction,
+        length: usize,
+        end: usize,
+    },
+}
+
+// Translates basic blocks to x86-64 code the first time they run, falling back to the
+// interpreter for the instructions it does not translate. Blocks are dropped when their
+// memory is written.
+pub struct Dynarec {
+    code: CodeBuffer,
+    // Block starting at each address
+    entries: Vec<Entry>,
+    context: Context,
+    stack: Vec<u16>,
+    // Runs the interpreter after every block and compares their states, the blocks
+    // writing to a copy of memory
+    lockstep: bool,
+    lockstep_memory: Vec<u8>,
+    executed: usize,
+}
+
+impl Dynarec {
+    pub fn new(state: &Chip8State, lockstep: bool) -> io::Result<Dynarec> {
+        if state.options.stack_in_memory {
+            return Err(io::Error::other("the stack cannot be kept in memory"));
+        }
+
+        let mut stack = vec![0; state.options.stack_depth];
+
+        Ok(Dynarec {
+            code: CodeBuffer::new(CODE_BUFFER_SIZE)?,
+            entries: vec![Entry::Unknown; MEMORY_SIZE],
+            context: Context {
+                registers: [0; 16],
+                index_register: 0,
+                delay_timer: 0,
+                stack_size: 0,
+                stack: stack.as_mut_ptr(),
+                memory: std::ptr::null_mut(),
+                program_counter: 0,
+                executed: 0,
+                dirty_start: u32::MAX,
+                dirty_end: 0,
+            },
+            stack,
+            lockstep,
+            lockstep_memory: vec![0; MEMORY_SIZE],
+            executed: 0,
+        })
+    }
+
+    fn load_context(&mut self, state: &mut Chip8State) {
+        let context = &mut self.context;
+        context.registers = state.registers;
+        context.index_register = state.index_register;
+        context.delay_timer = state.delay_timer;
+        context.stack_size = state.stack.len() as u32;
+        self.stack[..state.stack.len()].copy_from_slice(&state.stack);
+        context.program_counter = state.program_counter as u32;
+
+        context.memory = if self.lockstep {
+            self.lockstep_memory.copy_from_slice(state.bus.memory());
+            self.lockstep_memory.as_mut_ptr()
+        } else {
+            // Blocks write through the pointer, so nothing the bus decoded may be kept
+            state.bus.memory_range_mut(0..MEMORY_SIZE).as_mut_ptr()
+        };
+    }
+
+    fn store_context(&self, state: &mut Chip8State) {
+        let context = &self.context;
+        state.registers = context.registers;
+        state.index_register = context.index_register;
+        state.delay_timer = context.delay_timer;
+        state.stack.clear();
+        state
+            .stack
+            .extend_from_slice(&self.stack[..context.stack_size as usize]);
+        state.program_counter = context.program_counter as usize;
+    }
+
+    // Drops the blocks overlapping a range of memory
+    fn invalidate(&mut self, range: Range<usize>) {
+        let first = range.start.saturating_sub(2 * MAX_BLOCK_LENGTH);
+        for address in first..range.end.min(MEMORY_SIZE) {
+            let end = match self.entries[address] {
+                Entry::Unknown => continue,
+                Entry::Interpreted => address + 2,
+                Entry::Native { end, .. } => end,
+            };
+
+            if end > range.start {
+                self.entries[address] = Entry::Unknown;
+            }
+        }
+    }
+
+    fn invalidate_written_memory(&mut self, state: &mut Chip8State) {
+        let context = &mut self.context;
+        if context.dirty_start < context.dirty_end {
+            let range = context.dirty_start as usize..context.dirty_end as usize;
+            context.dirty_start = u32::MAX;
+            context.dirty_end = 0;
+
+            state.bus.invalidate(range.clone());
+            self.invalidate(range);
+        }
+    }
+
+    fn compile(&mut self, state: &Chip8State, address: usize) -> io::Result<Entry> {
+        let mut opcodes = Vec::new();
+        let mut end = address;
+        while opcodes.len() < MAX_BLOCK_LENGTH {
+            let opcode = match state.bus.peek_opcode(end) {
+                Ok(opcode) => Chip8State::decode_instruction(opcode),
+                Err(_) => break,
+            };
+            if !x86_64::is_translated(&opcode) {
+                break;
+            }
+
+            opcodes.push(opcode);
+            end += 2;
+
+            if x86_64::ends_block(&opcode) {
+                break;
+            }
+        }
+
+        let last = match opcodes.last() {
+            Some(opcode) => *opcode,
+            None => return Ok(Entry::Interpreted),
+        };
+
+        let mut assembler = Assembler::new(address, end, self.stack.len());
+        for (i, opcode) in opcodes.iter().enumerate() {
+            assembler.instruction(opcode, address + 2 * i, i);
+        }
+        if !x86_64::ends_block(&last) {
+            assembler.exit(end, opcodes.len());
+        }
+        let code = assembler.finish();
+
+        let function = match self.code.push(&code)? {
+            Some(function) => function,
+            None => {
+                // Starts over once the buffer is full of blocks, most of them invalidated
+                self.code.clear();
+                self.entries
+                    .iter_mut()
+                    .for_each(|entry| *entry = Entry::Unknown);
+                self.code
+                    .push(&code)?
+                    .ok_or_else(|| io::Error::other("block too large"))?
+            }
+        };
+
+        Ok(Entry::Native {
+            function: unsafe { mem::transmute::<*const u8, BlockFunction>(function) },
+            length: opcodes.len(),
+            end,
+        })
+    }
+
+    fn entry(&mut self, state: &Chip8State, address: usize) -> Entry {
+        match self.entries.get(address) {
+            Some(Entry::Unknown) => {
+                // Failing to make the code executable leaves it to the interpreter
+                let entry = self.compile(state, address).unwrap_or(Entry::Interpreted);
+                self.entries[address] = entry;
+                entry
+            }
+            Some(entry) => *entry,
+            None => Entry::Interpreted,
+        }
+    }
+
+    fn compare(&self, state: &Chip8State) -> Option<String> {
+        let context = &self.context;
+        let mut differences = Vec::new();
+
+        if context.program_counter as usize != state.program_counter {
+            differences.push(format!(
+                "PC {:03X}, interpreter {:03X}",
+                context.program_counter, state.program_counter
+            ));
+        }
+
+        for (index, (value, expected)) in context
+            .registers
+            .iter()
+            .zip(state.registers.iter())
+            .enumerate()
+        {
+            if value != expected {
+                differences.push(format!(
+                    "V{:X} {:02X}, interpreter {:02X}",
+                    index, value, expected
+                ));
+            }
+        }
+
+        if context.index_register != state.index_register {
+            differences.push(format!(
+                "I {:03X}, interpreter {:03X}",
+                context.index_register, state.index_register
+            ));
+        }
+
+        if context.delay_timer != state.delay_timer {
+            differences.push(format!(
+                "DT {:02X}, interpreter {:02X}",
+                context.delay_timer, state.delay_timer
+            ));
+        }
+
+        if self.stack[..context.stack_size as usize] != state.stack[..] {
+            differences.push(format!(
+                "stack {:X?}, interpreter {:X?}",
+                &self.stack[..context.stack_size as usize],
+                state.stack
+            ));
+        }
+
+        let memory = state.bus.memory();
+        if let Some(address) = (0..MEMORY_SIZE).find(|&i| self.lockstep_memory[i] != memory[i]) {
+            differences.push(format!(
+                "[{:03X}] {:02X}, interpreter {:02X}",
+                address, self.lockstep_memory[address], memory[address]
+            ));
+        }
+
+        if differences.is_empty() {
+            None
+        } else {
+            Some(differences.join(", "))
+        }
+    }
+
+    // Returns the number of instructions the block ran
+    fn run_block(
+        &mut self,
+        state: &mut Chip8State,
+        address: usize,
+        function: BlockFunction,
+    ) -> Result<usize, Chip8Error> {
+        unsafe { function(&mut self.context) };
+        let executed = self.context.executed as usize;
+
+        if self.lockstep {
+            for _ in 0..executed {
+                state.tick()?;
+            }
+
+            if let Some(details) = self.compare(state) {
+                return Err(Chip8Error::DynarecMismatch { address, details });
+            }
+        }
+
+        state.draw_flag = false;
+        self.invalidate_written_memory(state);
+
+        if self.lockstep {
+            self.load_context(state);
+        }
+
+        Ok(executed)
+    }
+
+    // Runs count instructions, as many calls to Chip8State::tick would.
+    // Returns true if the screen was drawn to.
+    pub fn run(&mut self, state: &mut Chip8State, count: usize) -> Result<bool, Chip8Error> {
+        let mut drawn = false;
+        let mut remaining = count;
+
+        self.load_context(state);
+
+        while remaining > 0 && !state.is_waiting_for_key() {
+            let address = self.context.program_counter as usize;
+
+            if let Entry::Native {
+                function, length, ..
+            } = self.entry(state, address)
+            {
+                if length <= remaining {
+                    let executed = self.run_block(state, address, function)?;
+                    remaining -= executed;
+
+                    // Otherwise the block stopped before an instruction it cannot run
+                    if executed == length || remaining == 0 {
+                        continue;
+                    }
+                }
+            }
+
+            self.store_context(state);
+            state.tick()?;
+            drawn |= state.has_drawn();
+
+            // The instruction may write to memory when a block stopped after writing to itself
+            for access in state.bus.accesses() {
+                if access.kind == AccessKind::Write {
+                    self.context.dirty_start = self.context.dirty_start.min(access.address as u32);
+                    self.context.dirty_end = self.context.dirty_end.max(access.address as u32 + 1);
+                }
+            }
+            self.invalidate_written_memory(state);
+
+            self.load_context(state);
+            remaining -= 1;
+        }
+
+        self.store_context(state);
+        self.executed = count - remaining;
+
+        Ok(drawn)
+    }
+
+    // Instructions run by the last call to run, fewer than asked once waiting for a key
+    pub fn executed(&self) -> usize {
+        self.executed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn bundled_roms_run_in_lockstep_with_the_interpreter() {
+        let roms = Path::new(env!("CARGO_MANIFEST_DIR")).join("roms");
+        let mut paths: Vec<_> = fs::read_dir(roms)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+
+        for path in paths {
+            let mut state = Chip8State::with_options(fs::read(&path).unwrap(), Default::default());
+            let mut dynarec = Dynarec::new(&state, true).unwrap();
+
+            for frame in 0..300u64 {
+                // Presses a different key each frame one is waited for
+                if state.is_waiting_for_key() {
+                    let key = num::FromPrimitive::from_u64(frame % 16).unwrap();
+                    state.on_key_pressed(key);
+                }
+
+                match dynarec.run(&mut state, 30) {
+                    Ok(_) => state.tick_timers(),
+                    Err(e @ Chip8Error::DynarecMismatch { .. }) => {
+                        panic!("{}: {}", path.display(), e)
+                    }
+                    // Errors of the program itself, which the interpreter raised as well
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use super::{
+    DELAY_TIMER, DIRTY_END, DIRTY_START, EXECUTED, INDEX_REGISTER, MEMORY, PROGRAM_COUNTER, STACK,
+    STACK_SIZE,
+};
+use crate::chip8::bus::MEMORY_SIZE;
+use crate::chip8::opcodes::Opcode;
+
+// Byte registers, as encoded in ModRM
+const AL: u8 = 0;
+const CL: u8 = 1;
+const DL: u8 = 2;
+
+// Condition codes of the short jumps
+const JB: u8 = 0x72;
+const JAE: u8 = 0x73;
+const JE: u8 = 0x74;
+const JNE: u8 = 0x75;
+const JBE: u8 = 0x76;
+
+// Length of the sequence emitted by exit()
+const EXIT_LENGTH: u8 = 15;
+
+// Drawing, keys, random numbers, sound and machine code routines are left to the interpreter
+pub fn is_translated(opcode: &Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Add { .. }
+            | Opcode::AddAddress { .. }
+            | Opcode::Assign { .. }
+            | Opcode::BitOpAnd { .. }
+            | Opcode::BitOpOr { .. }
+            | Opcode::BitOpXor { .. }
+            | Opcode::BitOpShiftL { .. }
+            | Opcode::BitOpShiftR { .. }
+            | Opcode::CallSubroutine { .. }
+            | Opcode::CondEq { .. }
+            | Opcode::CondNe { .. }
+            | Opcode::CondVxVyEq { .. }
+            | Opcode::CondVxVyNe { .. }
+            | Opcode::GetDelayTimer { .. }
+            | Opcode::Goto { .. }
+            | Opcode::Increment { .. }
+            | Opcode::LoadRegisters { .. }
+            | Opcode::Return
+            | Opcode::Set { .. }
+            | Opcode::SetAddress { .. }
+            | Opcode::SetBCD { .. }
+            | Opcode::SetDelayTimer { .. }
+            | Opcode::SetSprite { .. }
+            | Opcode::StoreRegisters { .. }
+            | Opcode::Sub { .. }
+            | Opcode::SubVyVx { .. }
+    )
+}
+
+// Jumps, calls and skips, after which the block returns to the dispatcher
+pub fn ends_block(opcode: &Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::CallSubroutine { .. }
+            | Opcode::CondEq { .. }
+            | Opcode::CondNe { .. }
+            | Opcode::CondVxVyEq { .. }
+            | Opcode::CondVxVyNe { .. }
+            | Opcode::Goto { .. }
+            | Opcode::Return
+    )
+}
+
+// Translates CHIP-8 instructions to System V functions taking the context in rdi. The
+// registers and timers are read and written in the context, rax, rcx, rdx and rsi are
+// scratch registers and r8 holds the base address of the CHIP-8 memory.
+pub struct Assembler {
+    code: Vec<u8>,
+    // Bytes of CHIP-8 memory translated in the block
+    block_start: usize,
+    block_end: usize,
+    stack_depth: usize,
+}
+
+impl Assembler {
+    pub fn new(block_start: usize, block_end: usize, stack_depth: usize) -> Assembler {
+        let mut assembler = Assembler {
+            code: Vec::new(),
+            block_start,
+            block_end,
+            stack_depth,
+        };
+
+        // mov r8, [rdi + MEMORY]
+        assembler.emit(&[0x4C, 0x8B, 0x47, MEMORY]);
+
+        assembler
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.code
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u16(&mut self, value: u16) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn emit_u32(&mut self, value: u32) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // mov reg8, [rdi + offset]
+    fn load_byte(&mut self, reg: u8, offset: u8) {
+        self.emit(&[0x8A, 0x47 | reg << 3, offset]);
+    }
+
+    // mov [rdi + offset], reg8
+    fn store_byte(&mut self, offset: u8, reg: u8) {
+        self.emit(&[0x88, 0x47 | reg << 3, offset]);
+    }
+
+    // Leaves the block, the next instruction being at address
+    pub fn exit(&mut self, address: usize, executed: usize) {
+        // mov dword [rdi + PROGRAM_COUNTER], address
+        self.emit(&[0xC7, 0x47, PROGRAM_COUNTER]);
+        self.emit_u32(address as u32);
+        // mov dword [rdi + EXECUTED], executed
+        self.emit(&[0xC7, 0x47, EXECUTED]);
+        self.emit_u32(executed as u32);
+        // ret
+        self.emit(&[0xC3]);
+    }
+
+    // Leaves the block before the instruction at address unless the last comparison
+    // satisfies the condition, the interpreter running the instruction instead
+    fn side_exit_unless(&mut self, condition: u8, address: usize, executed: usize) {
+        self.emit(&[condition, EXIT_LENGTH]);
+        self.exit(address, executed);
+    }
+
+    // Skip instructions end the block, condition being the one under which the next
+    // instruction is not skipped
+    fn skip_unless(&mut self, condition: u8, address: usize, executed: usize) {
+        self.emit(&[condition, EXIT_LENGTH]);
+        self.exit(address + 4, executed + 1);
+        self.exit(address + 2, executed + 1);
+    }
+
+    // Loads I into edx, leaving the block if I + length goes out of memory
+    fn load_checked_index(&mut self, length: usize, address: usize, executed: usize) {
+        // movzx edx, word [rdi + INDEX_REGISTER]
+        self.emit(&[0x0F, 0xB7, 0x57, INDEX_REGISTER]);
+        // cmp edx, MEMORY_SIZE - length
+        self.emit(&[0x81, 0xFA]);
+        self.emit_u32((MEMORY_SIZE - length) as u32);
+        self.side_exit_unless(JBE, address, executed);
+    }
+
+    // Extends the written range to edx..edx + length, and leaves the block if the write hit
+    // its own code, which has to be translated again
+    fn mark_written(&mut self, length: usize, address: usize, executed: usize) {
+        // cmp [rdi + DIRTY_START], edx; jbe +3; mov [rdi + DIRTY_START], edx
+        self.emit(&[0x39, 0x57, DIRTY_START, JBE, 3, 0x89, 0x57, DIRTY_START]);
+        // lea ecx, [rdx + length]
+        self.emit(&[0x8D, 0x4A, length as u8]);
+        // cmp [rdi + DIRTY_END], ecx; jae +3; mov [rdi + DIRTY_END], ecx
+        self.emit(&[0x39, 0x4F, DIRTY_END, JAE, 3, 0x89, 0x4F, DIRTY_END]);
+
+        // cmp edx, block_end; jae over the rest
+        self.emit(&[0x81, 0xFA]);
+        self.emit_u32(self.block_end as u32);
+        self.emit(&[JAE, 6 + 2 + EXIT_LENGTH]);
+        // cmp ecx, block_start
+        self.emit(&[0x81, 0xF9]);
+        self.emit_u32(self.block_start as u32);
+        self.emit(&[JBE, EXIT_LENGTH]);
+        self.exit(address + 2, executed + 1);
+    }
+
+    // Emits a translated instruction, executed being the number of instructions of the
+    // block before it
+    pub fn instruction(&mut self, opcode: &Opcode, address: usize, executed: usize) {
+        match *opcode {
+            Opcode::Add { r, value } => {
+                // add byte [rdi + r], value
+                self.emit(&[0x80, 0x47, r, value]);
+            }
+            Opcode::AddAddress { r } => {
+                // movzx eax, byte [rdi + r]; add [rdi + INDEX_REGISTER], ax
+                self.emit(&[0x0F, 0xB6, 0x47, r]);
+                self.emit(&[0x66, 0x01, 0x47, INDEX_REGISTER]);
+            }
+            Opcode::Assign { dst, src } => {
+                self.load_byte(AL, src);
+                self.store_byte(dst, AL);
+            }
+            Opcode::BitOpAnd { r1, r2 } => {
+                // mov al, [rdi + r2]; and [rdi + r1], al
+                self.load_byte(AL, r2);
+                self.emit(&[0x20, 0x47, r1]);
+            }
+            Opcode::BitOpOr { r1, r2 } => {
+                self.load_byte(AL, r2);
+                self.emit(&[0x08, 0x47, r1]);
+            }
+            Opcode::BitOpXor { r1, r2 } => {
+                self.load_byte(AL, r2);
+                self.emit(&[0x30, 0x47, r1]);
+            }
+            Opcode::BitOpShiftL { r } => {
+                // VF first, so that shifting VF shifts the flag
+                self.load_byte(AL, r);
+                // shr al, 7
+                self.emit(&[0xC0, 0xE8, 7]);
+                self.store_byte(15, AL);
+                // shl byte [rdi + r], 1
+                self.emit(&[0xD0, 0x67, r]);
+            }
+            Opcode::BitOpShiftR { r } => {
+                self.load_byte(AL, r);
+                // and al, 1
+                self.emit(&[0x24, 1]);
+                self.store_byte(15, AL);
+                // shr byte [rdi + r], 1
+                self.emit(&[0xD0, 0x6F, r]);
+            }
+            Opcode::CallSubroutine { address: target } => {
+                // mov eax, [rdi + STACK_SIZE]; cmp eax, depth
+                self.emit(&[0x8B, 0x47, STACK_SIZE, 0x3D]);
+                self.emit_u32(self.stack_depth as u32);
+                self.side_exit_unless(JB, address, executed);
+                // mov rsi, [rdi + STACK]; mov word [rsi + rax * 2], address + 2
+                self.emit(&[0x48, 0x8B, 0x77, STACK]);
+                self.emit(&[0x66, 0xC7, 0x04, 0x46]);
+                self.emit_u16(address as u16 + 2);
+                // inc dword [rdi + STACK_SIZE]
+                self.emit(&[0xFF, 0x47, STACK_SIZE]);
+                self.exit(target as usize, executed + 1);
+            }
+            Opcode::CondEq { r, value } => {
+                // cmp byte [rdi + r], value
+                self.emit(&[0x80, 0x7F, r, value]);
+                self.skip_unless(JNE, address, executed);
+            }
+            Opcode::CondNe { r, value } => {
+                self.emit(&[0x80, 0x7F, r, value]);
+                self.skip_unless(JE, address, executed);
+            }
+            Opcode::CondVxVyEq { r1, r2 } => {
+                // mov al, [rdi + r1]; cmp al, [rdi + r2]
+                self.load_byte(AL, r1);
+                self.emit(&[0x3A, 0x47, r2]);
+                self.skip_unless(JNE, address, executed);
+            }
+            Opcode::CondVxVyNe { r1, r2 } => {
+                self.load_byte(AL, r1);
+                self.emit(&[0x3A, 0x47, r2]);
+                self.skip_unless(JE, address, executed);
+            }
+            Opcode::GetDelayTimer { r } => {
+                self.load_byte(AL, DELAY_TIMER);
+                self.store_byte(r, AL);
+            }
+            Opcode::Goto { address: target } => self.exit(target as usize, executed + 1),
+            Opcode::Increment { r1, r2 } => {
+                // mov al, [rdi + r1]; add al, [rdi + r2]; setc cl
+                self.load_byte(AL, r1);
+                self.emit(&[0x02, 0x47, r2]);
+                self.emit(&[0x0F, 0x92, 0xC1]);
+                // The flag last, overwriting the result when r1 is VF
+                self.store_byte(r1, AL);
+                self.store_byte(15, CL);
+            }
+            Opcode::LoadRegisters { r } => {
+                let count = r as usize + 1;
+                self.load_checked_index(count, address, executed);
+                for i in 0..count as u8 {
+                    // mov cl, [r8 + rdx + i]
+                    self.emit(&[0x41, 0x8A, 0x4C, 0x10, i]);
+                    self.store_byte(i, CL);
+                }
+            }
+            Opcode::Return => {
+                // mov eax, [rdi + STACK_SIZE]; test eax, eax
+                self.emit(&[0x8B, 0x47, STACK_SIZE, 0x85, 0xC0]);
+                self.side_exit_unless(JNE, address, executed);
+                // dec eax; mov [rdi + STACK_SIZE], eax
+                self.emit(&[0xFF, 0xC8, 0x89, 0x47, STACK_SIZE]);
+                // mov rsi, [rdi + STACK]; movzx ecx, word [rsi + rax * 2]
+                self.emit(&[0x48, 0x8B, 0x77, STACK]);
+                self.emit(&[0x0F, 0xB7, 0x0C, 0x46]);
+                // mov [rdi + PROGRAM_COUNTER], ecx
+                self.emit(&[0x89, 0x4F, PROGRAM_COUNTER]);
+                // mov dword [rdi + EXECUTED], executed + 1; ret
+                self.emit(&[0xC7, 0x47, EXECUTED]);
+                self.emit_u32(executed as u32 + 1);
+                self.emit(&[0xC3]);
+            }
+            Opcode::Set { r, value } => {
+                // mov byte [rdi + r], value
+                self.emit(&[0xC6, 0x47, r, value]);
+            }
+            Opcode::SetAddress { value } => {
+                // mov word [rdi + INDEX_REGISTER], value
+                self.emit(&[0x66, 0xC7, 0x47, INDEX_REGISTER]);
+                self.emit_u16(value);
+            }
+            Opcode::SetBCD { r } => {
+                self.load_checked_index(3, address, executed);
+                // movzx eax, byte [rdi + r]; mov cl, 100; div cl; mov [r8 + rdx], al
+                self.emit(&[0x0F, 0xB6, 0x47, r]);
+                self.emit(&[0xB1, 100, 0xF6, 0xF1]);
+                self.emit(&[0x41, 0x88, 0x04, 0x10]);
+                // movzx eax, ah; mov cl, 10; div cl; mov [r8 + rdx + 1], al
+                self.emit(&[0x0F, 0xB6, 0xC4]);
+                self.emit(&[0xB1, 10, 0xF6, 0xF1]);
+                self.emit(&[0x41, 0x88, 0x44, 0x10, 1]);
+                // ah cannot be encoded with a REX prefix: mov cl, ah; mov [r8 + rdx + 2], cl
+                self.emit(&[0x88, 0xE1]);
+                self.emit(&[0x41, 0x88, 0x4C, 0x10, 2]);
+                self.mark_written(3, address, executed);
+            }
+            Opcode::SetDelayTimer { r } => {
+                self.load_byte(AL, r);
+                self.store_byte(DELAY_TIMER, AL);
+            }
+            Opcode::SetSprite { r } => {
+                // movzx eax, byte [rdi + r]; lea eax, [rax + rax * 4]
+                self.emit(&[0x0F, 0xB6, 0x47, r]);
+                self.emit(&[0x8D, 0x04, 0x80]);
+                // mov [rdi + INDEX_REGISTER], ax
+                self.emit(&[0x66, 0x89, 0x47, INDEX_REGISTER]);
+            }
+            Opcode::StoreRegisters { r } => {
+                let count = r as usize + 1;
+                self.load_checked_index(count, address, executed);
+                for i in 0..count as u8 {
+                    // mov cl, [rdi + i]; mov [r8 + rdx + i], cl
+                    self.load_byte(CL, i);
+                    self.emit(&[0x41, 0x88, 0x4C, 0x10, i]);
+                }
+                self.mark_written(count, address, executed);
+            }
+            Opcode::Sub { r1, r2 } => {
+                self.load_byte(AL, r1);
+                self.load_byte(CL, r2);
+                self.subtract(r1);
+            }
+            Opcode::SubVyVx { r1, r2 } => {
+                self.load_byte(AL, r2);
+                self.load_byte(CL, r1);
+                self.subtract(r1);
+            }
+            _ => unreachable!("{:?} is left to the interpreter", opcode),
+        }
+    }
+
+    // Vr = al - cl, VF being set first to whether al is greater than cl
+    fn subtract(&mut self, r: u8) {
+        // cmp al, cl; seta dl; sub al, cl
+        self.emit(&[0x38, 0xC8, 0x0F, 0x97, 0xC2, 0x28, 0xC8]);
+        self.store_byte(15, DL);
+        self.store_byte(r, AL);
+    }
+}
@@ -0,0 +1,41 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Chip8Error {
+    DynarecMismatch { address: usize, details: String },
+    InvalidOpcode { address: usize, opcode: u16 },
+    MemoryOutOfBounds { address: usize, access: usize },
+    StackOverflow { address: usize, depth: usize },
+    StackUnderflow { address: usize },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::DynarecMismatch { address, details } => write!(
+                f,
+                "{:#05X}: the dynarec block differs from the interpreter: {}",
+                address, details
+            ),
+            Chip8Error::InvalidOpcode { address, opcode } => {
+                write!(f, "{:#05X}: invalid opcode {:04X}", address, opcode)
+            }
+            Chip8Error::MemoryOutOfBounds { address, access } => write!(
+                f,
+                "{:#05X}: memory access out of bounds ({:#X})",
+                address, access
+            ),
+            Chip8Error::StackOverflow { address, depth } => write!(
+                f,
+                "{:#05X}: call with a full stack ({} entries)",
+                address, depth
+            ),
+            Chip8Error::StackUnderflow { address } => {
+                write!(f, "{:#05X}: return with an empty stack", address)
+            }
+        }
+    }
+}
+
+impl Error for Chip8Error {}
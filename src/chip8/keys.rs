@@ -1,4 +1,4 @@
-#[derive(FromPrimitive, Debug)]
+#[derive(FromPrimitive, Clone, Copy, Debug, PartialEq)]
 pub enum Key {
     Key0 = 0,
     Key1 = 1,
@@ -1,4 +1,17 @@
+pub mod bus;
+pub mod coverage;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod dynarec;
+pub mod error;
 pub mod state;
 
 pub mod keys;
-mod opcodes;
+pub mod opcodes;
+pub mod profile;
+// Only used by the programs generated by chip8 recompile, which include these sources, so the
+// emulator builds it without using it
+#[cfg(feature = "recompiled")]
+#[allow(dead_code)]
+pub mod runtime;
+pub mod timing;
+pub mod trace;
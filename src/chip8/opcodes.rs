@@ -1,6 +1,8 @@
-#[derive(Debug)]
+use std::fmt;
+
+#[derive(Clone, Copy, Debug)]
 pub enum Opcode {
-    Invalid,
+    Invalid { opcode: u16 },
 
     Add { r: u8, value: u8 },             // ADD Vx, byte - 7XNN
     AddAddress { r: u8 },                 // ADD I, Vx - FX1E
@@ -38,3 +40,150 @@ pub enum Opcode {
     SubVyVx { r1: u8, r2: u8 },           // SUBN Vx, Vy - 8XY7
     WaitKeyPressed { r: u8 },             // LD Vx, K - FX0A
 }
+
+impl Opcode {
+    // Every name returned by name(), in the same order
+    pub const NAMES: [&'static str; 36] = [
+        "Invalid",
+        "Add",
+        "AddAddress",
+        "Assign",
+        "BitOpAnd",
+        "BitOpOr",
+        "BitOpShiftL",
+        "BitOpShiftR",
+        "BitOpXor",
+        "CallRca",
+        "CallSubroutine",
+        "Clear",
+        "CondEq",
+        "CondKeyPressed",
+        "CondKeyReleased",
+        "CondNe",
+        "CondVxVyEq",
+        "CondVxVyNe",
+        "DrawSprite",
+        "GetDelayTimer",
+        "Goto",
+        "Increment",
+        "Jump",
+        "LoadRegisters",
+        "Return",
+        "Set",
+        "SetAddress",
+        "SetBCD",
+        "SetDelayTimer",
+        "SetRand",
+        "SetSoundTimer",
+        "SetSprite",
+        "StoreRegisters",
+        "Sub",
+        "SubVyVx",
+        "WaitKeyPressed",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Opcode::Invalid { .. } => "Invalid",
+            Opcode::Add { .. } => "Add",
+            Opcode::AddAddress { .. } => "AddAddress",
+            Opcode::Assign { .. } => "Assign",
+            Opcode::BitOpAnd { .. } => "BitOpAnd",
+            Opcode::BitOpOr { .. } => "BitOpOr",
+            Opcode::BitOpShiftL { .. } => "BitOpShiftL",
+            Opcode::BitOpShiftR { .. } => "BitOpShiftR",
+            Opcode::BitOpXor { .. } => "BitOpXor",
+            Opcode::CallRca { .. } => "CallRca",
+            Opcode::CallSubroutine { .. } => "CallSubroutine",
+            Opcode::Clear => "Clear",
+            Opcode::CondEq { .. } => "CondEq",
+            Opcode::CondKeyPressed { .. } => "CondKeyPressed",
+            Opcode::CondKeyReleased { .. } => "CondKeyReleased",
+            Opcode::CondNe { .. } => "CondNe",
+            Opcode::CondVxVyEq { .. } => "CondVxVyEq",
+            Opcode::CondVxVyNe { .. } => "CondVxVyNe",
+            Opcode::DrawSprite { .. } => "DrawSprite",
+            Opcode::GetDelayTimer { .. } => "GetDelayTimer",
+            Opcode::Goto { .. } => "Goto",
+            Opcode::Increment { .. } => "Increment",
+            Opcode::Jump { .. } => "Jump",
+            Opcode::LoadRegisters { .. } => "LoadRegisters",
+            Opcode::Return => "Return",
+            Opcode::Set { .. } => "Set",
+            Opcode::SetAddress { .. } => "SetAddress",
+            Opcode::SetBCD { .. } => "SetBCD",
+            Opcode::SetDelayTimer { .. } => "SetDelayTimer",
+            Opcode::SetRand { .. } => "SetRand",
+            Opcode::SetSoundTimer { .. } => "SetSoundTimer",
+            Opcode::SetSprite { .. } => "SetSprite",
+            Opcode::StoreRegisters { .. } => "StoreRegisters",
+            Opcode::Sub { .. } => "Sub",
+            Opcode::SubVyVx { .. } => "SubVyVx",
+            Opcode::WaitKeyPressed { .. } => "WaitKeyPressed",
+        }
+    }
+}
+
+// Disassembly, using the mnemonics of Cowgod's technical reference
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Opcode::Invalid { opcode } => write!(f, "DW #{:04X}", opcode),
+            Opcode::Add { r, value } => write!(f, "ADD V{:X}, #{:02X}", r, value),
+            Opcode::AddAddress { r } => write!(f, "ADD I, V{:X}", r),
+            Opcode::Assign { dst, src } => write!(f, "LD V{:X}, V{:X}", dst, src),
+            Opcode::BitOpAnd { r1, r2 } => write!(f, "AND V{:X}, V{:X}", r1, r2),
+            Opcode::BitOpOr { r1, r2 } => write!(f, "OR V{:X}, V{:X}", r1, r2),
+            Opcode::BitOpShiftL { r } => write!(f, "SHL V{:X}", r),
+            Opcode::BitOpShiftR { r } => write!(f, "SHR V{:X}", r),
+            Opcode::BitOpXor { r1, r2 } => write!(f, "XOR V{:X}, V{:X}", r1, r2),
+            Opcode::CallRca { address } => write!(f, "SYS #{:03X}", address),
+            Opcode::CallSubroutine { address } => write!(f, "CALL #{:03X}", address),
+            Opcode::Clear => write!(f, "CLS"),
+            Opcode::CondEq { r, value } => write!(f, "SE V{:X}, #{:02X}", r, value),
+            Opcode::CondKeyPressed { r } => write!(f, "SKP V{:X}", r),
+            Opcode::CondKeyReleased { r } => write!(f, "SKNP V{:X}", r),
+            Opcode::CondNe { r, value } => write!(f, "SNE V{:X}, #{:02X}", r, value),
+            Opcode::CondVxVyEq { r1, r2 } => write!(f, "SE V{:X}, V{:X}", r1, r2),
+            Opcode::CondVxVyNe { r1, r2 } => write!(f, "SNE V{:X}, V{:X}", r1, r2),
+            Opcode::DrawSprite { rx, ry, n } => write!(f, "DRW V{:X}, V{:X}, {}", rx, ry, n),
+            Opcode::GetDelayTimer { r } => write!(f, "LD V{:X}, DT", r),
+            Opcode::Goto { address } => write!(f, "JP #{:03X}", address),
+            Opcode::Increment { r1, r2 } => write!(f, "ADD V{:X}, V{:X}", r1, r2),
+            Opcode::Jump { offset } => write!(f, "JP V0, #{:03X}", offset),
+            Opcode::LoadRegisters { r } => write!(f, "LD V{:X}, [I]", r),
+            Opcode::Return => write!(f, "RET"),
+            Opcode::Set { r, value } => write!(f, "LD V{:X}, #{:02X}", r, value),
+            Opcode::SetAddress { value } => write!(f, "LD I, #{:03X}", value),
+            Opcode::SetBCD { r } => write!(f, "LD B, V{:X}", r),
+            Opcode::SetDelayTimer { r } => write!(f, "LD DT, V{:X}", r),
+            Opcode::SetRand { r, mask } => write!(f, "RND V{:X}, #{:02X}", r, mask),
+            Opcode::SetSoundTimer { r } => write!(f, "LD ST, V{:X}", r),
+            Opcode::SetSprite { r } => write!(f, "LD F, V{:X}", r),
+            Opcode::StoreRegisters { r } => write!(f, "LD [I], V{:X}", r),
+            Opcode::Sub { r1, r2 } => write!(f, "SUB V{:X}, V{:X}", r1, r2),
+            Opcode::SubVyVx { r1, r2 } => write!(f, "SUBN V{:X}, V{:X}", r1, r2),
+            Opcode::WaitKeyPressed { r } => write!(f, "LD V{:X}, K", r),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::state::Chip8State;
+
+    #[test]
+    fn names_list_every_decoded_opcode() {
+        let mut decoded: Vec<_> = (0..=0xFFFF)
+            .map(|opcode| Chip8State::decode_instruction(opcode).name())
+            .collect();
+        decoded.sort_unstable();
+        decoded.dedup();
+
+        let mut names = Opcode::NAMES.to_vec();
+        names.sort_unstable();
+
+        assert_eq!(decoded, names);
+    }
+}
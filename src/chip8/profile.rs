@@ -0,0 +1,293 @@
+use super::opcodes::Opcode;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// Number of entries in each table of the report
+const REPORT_LENGTH: usize = 20;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProfileFormat {
+    Report,
+    // One "main;sub_2A4;sub_300 <cycles>" line per call stack, for flame graph tools
+    Folded,
+}
+
+#[derive(Clone, Copy, Default)]
+struct AddressStats {
+    count: u64,
+    cycles: u64,
+    opcode: u16,
+}
+
+#[derive(Clone, Copy, Default)]
+struct SubroutineStats {
+    calls: u64,
+    inclusive: u64,
+    exclusive: u64,
+}
+
+struct CallFrame {
+    target: usize,
+    entered_at: u64,
+}
+
+// Counts executed instructions, time being measured in VIP machine cycles
+pub struct Profiler {
+    output: BufWriter<File>,
+    format: ProfileFormat,
+    instructions: u64,
+    cycles: u64,
+    addresses: BTreeMap<usize, AddressStats>,
+    opcodes: BTreeMap<&'static str, (u64, u64)>,
+    subroutines: BTreeMap<usize, SubroutineStats>,
+    call_stack: Vec<CallFrame>,
+    folded_stacks: BTreeMap<Vec<usize>, u64>,
+}
+
+impl Profiler {
+    pub fn create(path: &str, format: ProfileFormat) -> io::Result<Profiler> {
+        Ok(Profiler {
+            output: BufWriter::new(File::create(path)?),
+            format,
+            instructions: 0,
+            cycles: 0,
+            addresses: BTreeMap::new(),
+            opcodes: BTreeMap::new(),
+            subroutines: BTreeMap::new(),
+            call_stack: Vec::new(),
+            folded_stacks: BTreeMap::new(),
+        })
+    }
+
+    fn current_stack(&self) -> Vec<usize> {
+        self.call_stack.iter().map(|frame| frame.target).collect()
+    }
+
+    // Calls are counted in the caller, returns in the subroutine
+    pub fn record(&mut self, address: usize, opcode: u16, decoded: &Opcode, cycles: u32) {
+        let cycles = u64::from(cycles);
+        self.instructions += 1;
+        self.cycles += cycles;
+
+        let stats = self.addresses.entry(address).or_default();
+        stats.count += 1;
+        stats.cycles += cycles;
+        stats.opcode = opcode;
+
+        let opcode_stats = self.opcodes.entry(decoded.name()).or_default();
+        opcode_stats.0 += 1;
+        opcode_stats.1 += cycles;
+
+        if let Some(frame) = self.call_stack.last() {
+            self.subroutines.entry(frame.target).or_default().exclusive += cycles;
+        }
+        *self.folded_stacks.entry(self.current_stack()).or_default() += cycles;
+
+        match *decoded {
+            Opcode::CallSubroutine { address } => {
+                let target = address as usize;
+                self.subroutines.entry(target).or_default().calls += 1;
+                self.call_stack.push(CallFrame {
+                    target,
+                    entered_at: self.cycles,
+                });
+            }
+            Opcode::Return => {
+                if let Some(frame) = self.call_stack.pop() {
+                    self.subroutines.entry(frame.target).or_default().inclusive +=
+                        self.cycles - frame.entered_at;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn write_report(&mut self) -> io::Result<()> {
+        let total = self.cycles.max(1) as f64;
+        let percent = |cycles: u64| cycles as f64 * 100.0 / total;
+
+        writeln!(
+            self.output,
+            "{} instructions, {} VIP cycles",
+            self.instructions, self.cycles
+        )?;
+
+        let mut addresses: Vec<_> = self.addresses.iter().collect();
+        addresses.sort_by_key(|(_, stats)| Reverse(stats.cycles));
+        writeln!(self.output, "\nHot addresses:")?;
+        writeln!(self.output, "  addr opcode       count       cycles      %")?;
+        for (address, stats) in addresses.iter().take(REPORT_LENGTH) {
+            writeln!(
+                self.output,
+                "  {:03X}  {:04X}   {:10}   {:10} {:6.2}",
+                address,
+                stats.opcode,
+                stats.count,
+                stats.cycles,
+                percent(stats.cycles)
+            )?;
+        }
+
+        let mut opcodes: Vec<_> = self.opcodes.iter().collect();
+        opcodes.sort_by_key(|(_, (_, cycles))| Reverse(*cycles));
+        writeln!(self.output, "\nOpcodes:")?;
+        writeln!(
+            self.output,
+            "  opcode                count       cycles      %"
+        )?;
+        for (name, (count, cycles)) in opcodes {
+            writeln!(
+                self.output,
+                "  {:16} {:10}   {:10} {:6.2}",
+                name,
+                count,
+                cycles,
+                percent(*cycles)
+            )?;
+        }
+
+        let mut subroutines: Vec<_> = self.subroutines.iter().collect();
+        subroutines.sort_by_key(|(_, stats)| Reverse(stats.inclusive));
+        writeln!(self.output, "\nSubroutines:")?;
+        writeln!(
+            self.output,
+            "  addr      calls    inclusive      %    exclusive      %"
+        )?;
+        for (address, stats) in subroutines {
+            writeln!(
+                self.output,
+                "  {:03X}  {:10}   {:10} {:6.2}   {:10} {:6.2}",
+                address,
+                stats.calls,
+                stats.inclusive,
+                percent(stats.inclusive),
+                stats.exclusive,
+                percent(stats.exclusive)
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn write_folded(&mut self) -> io::Result<()> {
+        for (stack, cycles) in &self.folded_stacks {
+            let mut line = String::from("main");
+            for target in stack {
+                line.push_str(&format!(";sub_{:03X}", target));
+            }
+
+            writeln!(self.output, "{} {}", line, cycles)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        // Subroutines still running are counted up to now
+        for frame in self.call_stack.drain(..) {
+            self.subroutines.entry(frame.target).or_default().inclusive +=
+                self.cycles - frame.entered_at;
+        }
+
+        match self.format {
+            ProfileFormat::Report => self.write_report()?,
+            ProfileFormat::Folded => self.write_folded()?,
+        }
+
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::state::{Chip8Options, Chip8State};
+    use crate::chip8::timing;
+    use std::env;
+    use std::fs;
+
+    // Calls the subroutine at 0x206 twice, then loops at 0x204
+    const CALLS: [u16; 5] = [0x2206, 0x2206, 0x1204, 0x6001, 0x00EE];
+
+    fn profile(program: &[u16], instructions: usize, format: ProfileFormat) -> String {
+        let path = env::temp_dir()
+            .join(format!(
+                "chip8-profile-{}-{}",
+                instructions,
+                std::process::id()
+            ))
+            .to_str()
+            .unwrap()
+            .to_owned();
+
+        let rom = program
+            .iter()
+            .flat_map(|opcode| opcode.to_be_bytes())
+            .collect();
+        let mut state = Chip8State::with_options(rom, Chip8Options::default());
+        state.set_profiler(Profiler::create(&path, format).unwrap());
+        for _ in 0..instructions {
+            let _ = state.tick();
+        }
+        state.take_profiler().unwrap().finish().unwrap();
+
+        let output = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        output
+    }
+
+    fn cycles(opcode: u16) -> u64 {
+        let decoded = Chip8State::decode_instruction(opcode);
+        u64::from(timing::instruction_timing(&decoded, &[0; 16]).cycles)
+    }
+
+    #[test]
+    fn report_counts_hot_addresses_and_calls() {
+        let report = profile(&CALLS, 8, ProfileFormat::Report);
+        let (addresses, subroutines) = report.split_once("\nSubroutines:").unwrap();
+        let line = |section: &str, prefix: &str| {
+            section
+                .lines()
+                .find(|line| line.starts_with(prefix))
+                .unwrap_or_else(|| panic!("no {:?} line in {}", prefix, section))
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(report.starts_with("8 instructions, "));
+        // Address, opcode, count
+        assert_eq!(line(addresses, "  200  2206")[2], "1");
+        assert_eq!(line(addresses, "  206  6001")[2], "2");
+        assert_eq!(line(addresses, "  204  1204")[2], "2");
+        // Subroutine address, calls, inclusive cycles
+        let subroutine = line(subroutines, "  206 ");
+        assert_eq!(subroutine[1], "2");
+        assert_eq!(
+            subroutine[2],
+            (2 * (cycles(0x6001) + cycles(0x00EE))).to_string()
+        );
+    }
+
+    #[test]
+    fn folded_stacks_split_cycles_by_caller() {
+        let folded = profile(&CALLS, 8, ProfileFormat::Folded);
+
+        let main = 2 * cycles(0x2206) + 2 * cycles(0x1204);
+        let subroutine = 2 * (cycles(0x6001) + cycles(0x00EE));
+        assert_eq!(
+            folded,
+            format!("main {}\nmain;sub_206 {}\n", main, subroutine)
+        );
+    }
+
+    #[test]
+    fn failing_instructions_are_not_profiled() {
+        // Returns with an empty stack
+        let report = profile(&[0x00EE], 1, ProfileFormat::Report);
+
+        assert!(report.starts_with("0 instructions, 0 VIP cycles"));
+    }
+}
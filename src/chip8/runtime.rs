@@ -0,0 +1,167 @@
+use super::bus::MemoryBus;
+use super::error::Chip8Error;
+use super::state::{Chip8Options, Chip8State, GRID_WIDTH};
+
+const PROGRAM_START: usize = 0x200;
+
+// Runs the block starting at an address, returning the address of the next instruction and
+// the number of instructions run
+pub type BlockFunction = fn(&mut Runtime, usize) -> Result<(usize, usize), Chip8Error>;
+
+// Support for the programs generated by chip8 recompile, whose blocks keep the registers here
+// and call back for memory, the stack and the delay timer. Display, keypad, random numbers and
+// computed jumps are left to the interpreter, as is the whole program once it has written to
+// its recompiled code.
+pub struct Runtime {
+    pub v: [u8; 16],
+    pub i: u16,
+    state: Chip8State,
+    // Ranges of memory which were recompiled
+    code: &'static [(usize, usize)],
+    code_modified: bool,
+}
+
+impl Runtime {
+    pub fn new(rom: Vec<u8>, code: &'static [(usize, usize)]) -> Runtime {
+        Runtime {
+            v: [0; 16],
+            i: 0,
+            state: Chip8State::with_options(rom, Chip8Options::default()),
+            code,
+            code_modified: false,
+        }
+    }
+
+    // Runs the instruction at address in the interpreter, returning the address of the next one
+    pub fn interpret(&mut self, address: usize) -> Result<usize, Chip8Error> {
+        self.state.registers = self.v;
+        self.state.index_register = self.i;
+        self.state.program_counter = address;
+
+        let result = self.state.tick();
+
+        self.v = self.state.registers;
+        self.i = self.state.index_register;
+        result?;
+
+        Ok(self.state.program_counter)
+    }
+
+    pub fn call(&mut self, address: usize, target: usize) -> Result<usize, Chip8Error> {
+        let depth = self.state.options.stack_depth;
+        if self.state.stack.len() >= depth {
+            return Err(Chip8Error::StackOverflow { address, depth });
+        }
+
+        self.state.stack.push(address as u16 + 2);
+
+        Ok(target)
+    }
+
+    pub fn ret(&mut self, address: usize) -> Result<usize, Chip8Error> {
+        self.state
+            .stack
+            .pop()
+            .map(usize::from)
+            .ok_or(Chip8Error::StackUnderflow { address })
+    }
+
+    fn write(&mut self, address: usize, target: usize, value: u8) -> Result<(), Chip8Error> {
+        let target = MemoryBus::checked_address(address, target)?;
+        self.state.bus.memory_range_mut(target..target + 1)[0] = value;
+
+        // Instructions start at any address, so a byte belongs to two of them
+        if self
+            .code
+            .iter()
+            .any(|&(start, end)| (start.saturating_sub(1)..end).contains(&target))
+        {
+            self.code_modified = true;
+        }
+
+        Ok(())
+    }
+
+    pub fn load_registers(&mut self, address: usize, r: usize) -> Result<(), Chip8Error> {
+        for x in 0..=r {
+            let source = MemoryBus::checked_address(address, self.i as usize + x)?;
+            self.v[x] = self.state.bus.memory()[source];
+        }
+
+        Ok(())
+    }
+
+    pub fn store_registers(&mut self, address: usize, r: usize) -> Result<(), Chip8Error> {
+        for x in 0..=r {
+            self.write(address, self.i as usize + x, self.v[x])?;
+        }
+
+        Ok(())
+    }
+
+    pub fn store_bcd(&mut self, address: usize, r: usize) -> Result<(), Chip8Error> {
+        let value = self.v[r];
+        let digits = [value / 100, (value % 100) / 10, value % 10];
+
+        for (x, &digit) in digits.iter().enumerate() {
+            self.write(address, self.i as usize + x, digit)?;
+        }
+
+        Ok(())
+    }
+
+    // The recompiled blocks must not run anymore
+    pub fn code_modified(&self) -> bool {
+        self.code_modified
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.state.delay_timer
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.state.delay_timer = value;
+    }
+
+    // Runs frames of about instructions_per_frame instructions, as blocks are run whole, the
+    // instructions run past the end of a frame counting for the next one
+    pub fn run(
+        &mut self,
+        frames: u64,
+        instructions_per_frame: usize,
+        run_block: BlockFunction,
+    ) -> Result<(), Chip8Error> {
+        let mut address = PROGRAM_START;
+        let mut instructions = 0;
+
+        for _ in 0..frames {
+            while instructions < instructions_per_frame && !self.state.is_waiting_for_key() {
+                let (next, count) = if self.code_modified {
+                    (self.interpret(address)?, 1)
+                } else {
+                    run_block(self, address)?
+                };
+
+                address = next;
+                instructions += count;
+            }
+
+            instructions = instructions.saturating_sub(instructions_per_frame);
+            self.state.tick_timers();
+        }
+
+        Ok(())
+    }
+
+    pub fn screen(&self) -> String {
+        let mut output = String::new();
+        for row in self.state.grid.chunks(GRID_WIDTH) {
+            for &cell in row {
+                output.push(if cell { '#' } else { '.' });
+            }
+            output.push('\n');
+        }
+
+        output
+    }
+}
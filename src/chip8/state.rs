@@ -3,30 +3,52 @@ use rand::Rng;
 pub const GRID_WIDTH: usize = 64;
 pub const GRID_HEIGHT: usize = 32;
 
-const CHIP8_MEMORY: usize = 4096;
 const CHIP8_PROGRAM_START: usize = 512;
 
+pub const VIP_STACK_DEPTH: usize = 12;
+pub const SCHIP_STACK_DEPTH: usize = 16;
+
+// The VIP interpreter keeps its stack below its variables, growing down from 0xECF
+const VIP_STACK_TOP: usize = 0xECF;
+pub const VIP_STACK_MAX_DEPTH: usize = 24;
+
+// Followed by the display, one bit per cell, which fills the last page of memory
+const VIP_REGISTERS_START: usize = 0xEF0;
+const VIP_DISPLAY_START: usize = 0xF00;
+
+use super::bus::{AccessKind, MemoryBus, MemoryObserver, MEMORY_SIZE};
+use super::coverage::Coverage;
+use super::error::Chip8Error;
 use super::keys::Key;
 use super::opcodes::Opcode;
+use super::profile::Profiler;
+use super::timing::{self, InstructionTiming};
+use super::trace::{TraceEntry, Tracer};
 
+#[cfg(windows)]
 use libc::{c_int, c_uint};
+#[cfg(windows)]
 use std::thread;
-use std::time::SystemTime;
 
 extern crate num;
 
+#[cfg(windows)]
 #[link(name = "Kernel32")]
 extern "C" {
     fn Beep(frequency: c_uint, duration: c_uint) -> c_int;
 }
 
+#[cfg(windows)]
 fn beep(duration: u16) {
     thread::spawn(move || unsafe {
         Beep(800, duration as c_uint);
     });
 }
 
-static CHIP8_FONTSET: [u8; 80] = [
+#[cfg(not(windows))]
+fn beep(_duration: u16) {}
+
+pub static CHIP8_FONTSET: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
     0x20, 0x60, 0x20, 0x20, 0x70, // 1
     0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
@@ -45,65 +67,102 @@ static CHIP8_FONTSET: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+#[derive(Clone, Copy)]
+pub struct Chip8Options {
+    pub stack_depth: usize,
+    // Also writes the stack entries where the VIP interpreter keeps them
+    pub stack_in_memory: bool,
+    // Registers, stack and display live in memory like on the VIP, so programs can access them
+    pub vip_layout: bool,
+    // Keeps decoded instructions until their memory is written
+    pub decode_cache: bool,
+}
+
+impl Default for Chip8Options {
+    fn default() -> Chip8Options {
+        Chip8Options {
+            stack_depth: SCHIP_STACK_DEPTH,
+            stack_in_memory: false,
+            vip_layout: false,
+            decode_cache: true,
+        }
+    }
+}
+
+// Machine state taken from another implementation by resync
+pub struct ResyncState<'a> {
+    // From 0x200
+    pub program_memory: &'a [u8],
+    pub registers: [u8; 16],
+    pub index_register: u16,
+    pub program_counter: usize,
+    pub grid: Vec<bool>,
+    // Return addresses, the last one being the top of the stack
+    pub stack: Vec<u16>,
+    pub delay_timer: u8,
+}
+
+// The machine state is shared with the dynamic recompiler
 pub struct Chip8State {
-    delay_clock: SystemTime,
-    delay_timer: u8,
-    draw_flag: bool,
-    index_register: u16,
+    pub(super) bus: MemoryBus,
+    coverage: Option<Coverage>,
+    pub(super) delay_timer: u8,
+    pub(super) draw_flag: bool,
+    pub(super) index_register: u16,
     pub grid: Vec<bool>, // Temp public for tests
-    key_pressed: Option<Box<Fn(Key) -> bool>>,
-    memory: [u8; CHIP8_MEMORY],
-    program_counter: usize,
-    registers: [u8; 16],
-    stack: Vec<u16>,
+    key_pressed: Option<Box<dyn Fn(Key) -> bool>>,
+    pub(super) options: Chip8Options,
+    profiler: Option<Profiler>,
+    pub(super) program_counter: usize,
+    pub(super) registers: [u8; 16],
+    pub(super) stack: Vec<u16>,
+    tracer: Option<Tracer>,
     waiting_for_key: Option<u8>,
 }
 
 impl Chip8State {
-    pub fn new(source: Vec<u8>) -> Chip8State {
-        let mut memory: [u8; CHIP8_MEMORY] = [0; CHIP8_MEMORY];
+    pub fn with_options(source: Vec<u8>, mut options: Chip8Options) -> Chip8State {
+        options.stack_in_memory |= options.vip_layout;
+
+        let mut memory: [u8; MEMORY_SIZE] = [0; MEMORY_SIZE];
 
         // Copy font set into "interpreter memory"
-        for i in 0..CHIP8_FONTSET.len() {
-            memory[i] = CHIP8_FONTSET[i];
-        }
+        memory[..CHIP8_FONTSET.len()].copy_from_slice(&CHIP8_FONTSET);
 
-        for i in 0..source.len() {
-            memory[i + CHIP8_PROGRAM_START] = source[i];
-        }
+        memory[CHIP8_PROGRAM_START..CHIP8_PROGRAM_START + source.len()].copy_from_slice(&source);
 
         Chip8State {
-            delay_clock: SystemTime::now(),
+            bus: MemoryBus::new(memory, options.decode_cache),
+            coverage: None,
             delay_timer: 0,
             draw_flag: false,
             index_register: 0,
             grid: vec![false; GRID_WIDTH * GRID_HEIGHT],
             key_pressed: None,
-            memory: memory,
+            options,
+            profiler: None,
             program_counter: CHIP8_PROGRAM_START,
             registers: [0; 16],
-            stack: vec![0u16, 0],
+            stack: Vec::with_capacity(options.stack_depth),
+            tracer: None,
             waiting_for_key: None,
         }
     }
 
-    fn decode_next_instruction(&self) -> Opcode {
-        let opcode = (self.memory[self.program_counter] as u16) << 8
-            | (self.memory[self.program_counter + 1] as u16);
-
-        let opcode = match opcode & 0xF000 {
+    pub fn decode_instruction(opcode: u16) -> Opcode {
+        match opcode & 0xF000 {
             0x0000 => match opcode {
                 0x00E0 => Opcode::Clear,
                 0x00EE => Opcode::Return,
                 _ => Opcode::CallRca {
-                    address: (opcode & 0x0FFF) as u16,
+                    address: opcode & 0x0FFF,
                 },
             },
             0x1000 => Opcode::Goto {
-                address: (opcode & 0x0FFF) as u16,
+                address: opcode & 0x0FFF,
             },
             0x2000 => Opcode::CallSubroutine {
-                address: (opcode & 0x0FFF) as u16,
+                address: opcode & 0x0FFF,
             },
             0x3000 => Opcode::CondEq {
                 r: ((opcode & 0x0F00) >> 8) as u8,
@@ -160,17 +219,17 @@ impl Chip8State {
                 0xE => Opcode::BitOpShiftL {
                     r: ((opcode & 0x0F00) >> 8) as u8,
                 },
-                _ => Opcode::Invalid,
+                _ => Opcode::Invalid { opcode },
             },
             0x9000 => Opcode::CondVxVyNe {
                 r1: ((opcode & 0x0F00) >> 8) as u8,
                 r2: ((opcode & 0x00F0) >> 4) as u8,
             },
             0xA000 => Opcode::SetAddress {
-                value: (opcode & 0x0FFF) as u16,
+                value: opcode & 0x0FFF,
             },
             0xB000 => Opcode::Jump {
-                offset: (opcode & 0x0FFF) as u16,
+                offset: opcode & 0x0FFF,
             },
             0xC000 => Opcode::SetRand {
                 r: ((opcode & 0x0F00) >> 8) as u8,
@@ -188,7 +247,7 @@ impl Chip8State {
                 0xE0A1 => Opcode::CondKeyReleased {
                     r: ((opcode & 0x0F00) >> 8) as u8,
                 },
-                _ => Opcode::Invalid,
+                _ => Opcode::Invalid { opcode },
             },
             0xF000 => match opcode & 0xF0FF {
                 0xF007 => Opcode::GetDelayTimer {
@@ -218,27 +277,97 @@ impl Chip8State {
                 0xF065 => Opcode::LoadRegisters {
                     r: ((opcode & 0x0F00) >> 8) as u8,
                 },
-                _ => Opcode::Invalid,
+                _ => Opcode::Invalid { opcode },
             },
-            _ => Opcode::Invalid,
-        };
+            _ => Opcode::Invalid { opcode },
+        }
+    }
+
+    fn push_stack(&mut self, value: u16) -> Result<(), Chip8Error> {
+        if self.stack.len() >= self.options.stack_depth {
+            return Err(Chip8Error::StackOverflow {
+                address: self.program_counter,
+                depth: self.options.stack_depth,
+            });
+        }
+
+        // Two bytes per entry, the low byte being pushed first. They go through the bus so
+        // observers and watches see them.
+        if self.options.stack_in_memory {
+            let address = VIP_STACK_TOP - 2 * self.stack.len();
+            self.bus.write(self.program_counter, address, value as u8)?;
+            self.bus
+                .write(self.program_counter, address - 1, (value >> 8) as u8)?;
+        }
+
+        self.stack.push(value);
 
-        opcode
+        Ok(())
     }
 
-    fn execute(&mut self, opcode: Opcode) {
-        if let Some(_) = self.waiting_for_key {
+    // Memory holds the reference copy of the state stored in it, which the fields mirror while
+    // an instruction executes
+    fn load_memory_views(&mut self) {
+        let memory = self.bus.memory();
+
+        if self.options.stack_in_memory {
+            for (i, entry) in self.stack.iter_mut().enumerate() {
+                let address = VIP_STACK_TOP - 2 * i;
+                *entry = u16::from(memory[address - 1]) << 8 | u16::from(memory[address]);
+            }
+        }
+
+        if !self.options.vip_layout {
             return;
         }
 
-        self.draw_flag = false;
+        self.registers
+            .copy_from_slice(&memory[VIP_REGISTERS_START..VIP_REGISTERS_START + 16]);
+
+        for (i, cell) in self.grid.iter_mut().enumerate() {
+            *cell = memory[VIP_DISPLAY_START + i / 8] & (0x80 >> (i & 7)) != 0;
+        }
+    }
+
+    // Only the views which changed are written back, writes dropping the decoded instructions
+    fn store_memory_views(&mut self, registers_changed: bool, display_changed: bool) {
+        if !self.options.vip_layout {
+            return;
+        }
+
+        if registers_changed {
+            self.bus
+                .memory_range_mut(VIP_REGISTERS_START..VIP_REGISTERS_START + 16)
+                .copy_from_slice(&self.registers);
+        }
 
-        //println!("{}: {:?}", self.program_counter, opcode);
+        if !display_changed {
+            return;
+        }
+
+        for (byte, cells) in self
+            .bus
+            .memory_range_mut(VIP_DISPLAY_START..MEMORY_SIZE)
+            .iter_mut()
+            .zip(self.grid.chunks(8))
+        {
+            *byte = cells.iter().fold(0, |byte, &cell| byte << 1 | cell as u8);
+        }
+    }
+
+    fn execute(&mut self, opcode: Opcode) -> Result<(), Chip8Error> {
+        if self.waiting_for_key.is_some() {
+            return Ok(());
+        }
+
+        self.draw_flag = false;
 
         self.program_counter += match opcode {
-            Opcode::Invalid => {
-                println!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
-                0
+            Opcode::Invalid { opcode } => {
+                return Err(Chip8Error::InvalidOpcode {
+                    address: self.program_counter,
+                    opcode,
+                });
             }
             Opcode::Add { r, value } => {
                 self.registers[r as usize] = self.registers[r as usize].overflowing_add(value).0;
@@ -253,18 +382,15 @@ impl Chip8State {
                 2
             }
             Opcode::BitOpAnd { r1, r2 } => {
-                self.registers[r1 as usize] =
-                    self.registers[r1 as usize] & self.registers[r2 as usize];
+                self.registers[r1 as usize] &= self.registers[r2 as usize];
                 2
             }
             Opcode::BitOpOr { r1, r2 } => {
-                self.registers[r1 as usize] =
-                    self.registers[r1 as usize] | self.registers[r2 as usize];
+                self.registers[r1 as usize] |= self.registers[r2 as usize];
                 2
             }
             Opcode::BitOpXor { r1, r2 } => {
-                self.registers[r1 as usize] =
-                    self.registers[r1 as usize] ^ self.registers[r2 as usize];
+                self.registers[r1 as usize] ^= self.registers[r2 as usize];
                 2
             }
             Opcode::BitOpShiftL { r } => {
@@ -282,7 +408,7 @@ impl Chip8State {
                 2
             }
             Opcode::CallSubroutine { address } => {
-                self.stack.push(self.program_counter as u16);
+                self.push_stack(self.program_counter as u16 + 2)?;
                 self.program_counter = address as usize;
                 0
             }
@@ -351,7 +477,10 @@ impl Chip8State {
                 self.registers[15] = 0;
 
                 for y in 0..n {
-                    let pixel = self.memory[(self.index_register + y as u16) as usize];
+                    let pixel = self.bus.read(
+                        self.program_counter,
+                        self.index_register as usize + y as usize,
+                    )?;
                     for x in 0..8 {
                         if pixel & (0x80 >> x) != 0 {
                             let cell_x = (origin_x.wrapping_add(x) as usize) % GRID_WIDTH;
@@ -381,6 +510,10 @@ impl Chip8State {
                 self.program_counter = address as usize;
                 0
             }
+            Opcode::Jump { offset } => {
+                self.program_counter = self.registers[0] as usize + offset as usize;
+                0
+            }
             Opcode::Increment { r1, r2 } => {
                 let result =
                     self.registers[r1 as usize].overflowing_add(self.registers[r2 as usize]);
@@ -390,15 +523,19 @@ impl Chip8State {
                 2
             }
             Opcode::LoadRegisters { r } => {
-                for i in 0..((r + 1) as u16) {
-                    self.registers[i as usize] = self.memory[(self.index_register + i) as usize];
+                for i in 0..((r + 1) as usize) {
+                    self.registers[i] = self
+                        .bus
+                        .read(self.program_counter, self.index_register as usize + i)?;
                 }
 
                 2
             }
             Opcode::Return => {
-                self.program_counter = self.stack.pop().unwrap() as usize;
-                2
+                self.program_counter = self.stack.pop().ok_or(Chip8Error::StackUnderflow {
+                    address: self.program_counter,
+                })? as usize;
+                0
             }
             Opcode::Set { r, value } => {
                 self.registers[r as usize] = value;
@@ -412,10 +549,16 @@ impl Chip8State {
                 let register_value = self.registers[r as usize];
 
                 let memory_index = self.index_register as usize;
+                let digits = [
+                    register_value / 100,
+                    (register_value % 100) / 10,
+                    register_value % 10,
+                ];
 
-                self.memory[memory_index + 0] = register_value / 100;
-                self.memory[memory_index + 1] = (register_value % 100) / 10;
-                self.memory[memory_index + 2] = register_value % 10;
+                for (i, &digit) in digits.iter().enumerate() {
+                    self.bus
+                        .write(self.program_counter, memory_index + i, digit)?;
+                }
 
                 2
             }
@@ -425,7 +568,6 @@ impl Chip8State {
             }
             Opcode::SetRand { r, mask } => {
                 self.registers[r as usize] = rand::thread_rng().gen::<u8>() & mask;
-                println!("Rand value: {}", self.registers[r as usize]);
                 2
             }
             Opcode::SetSprite { r } => {
@@ -434,9 +576,7 @@ impl Chip8State {
                 2
             }
             Opcode::SetSoundTimer { r } => {
-                let sound_time = self.registers[r as usize] as u16 * 1000 / 60;
-                println!("Sound time: {}", sound_time);
-                beep(sound_time);
+                beep(self.registers[r as usize] as u16 * 1000 / 60);
                 2
             }
             Opcode::Sub { r1, r2 } => {
@@ -460,8 +600,12 @@ impl Chip8State {
                 2
             }
             Opcode::StoreRegisters { r } => {
-                for i in 0..((r + 1) as u16) {
-                    self.memory[(self.index_register + i) as usize] = self.registers[i as usize];
+                for i in 0..((r + 1) as usize) {
+                    self.bus.write(
+                        self.program_counter,
+                        self.index_register as usize + i,
+                        self.registers[i],
+                    )?;
                 }
 
                 2
@@ -470,43 +614,452 @@ impl Chip8State {
                 self.waiting_for_key = Some(r);
                 2
             }
-            _ => {
-                println!("{}: unhandled opcode {:?}", self.program_counter, opcode);
-                2
-            }
+            // Machine code routines of the original interpreter cannot run here, like most
+            // interpreters after it the call is ignored
+            Opcode::CallRca { .. } => 2,
         } as usize;
+
+        Ok(())
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    // Width and height of the display, in cells
+    pub fn display_size(&self) -> (usize, usize) {
+        (GRID_WIDTH, GRID_HEIGHT)
     }
 
     pub fn has_drawn(&self) -> bool {
         self.draw_flag
     }
 
+    pub fn index_register(&self) -> u16 {
+        self.index_register
+    }
+
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.waiting_for_key.is_some()
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        self.bus.memory()
+    }
+
     pub fn on_key_pressed(&mut self, key: Key) {
         if let Some(register) = self.waiting_for_key {
             self.registers[register as usize] = key as u8;
             self.waiting_for_key = None;
+            self.store_memory_views(true, false);
         }
     }
 
-    pub fn set_key_callback(&mut self, callback: Box<Fn(Key) -> bool>) {
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.registers
+    }
+
+    // Overwrites the machine state, to resynchronise with another implementation after
+    // instructions that cannot be reproduced (random numbers, machine code routines)
+    pub fn resync(&mut self, machine: ResyncState<'_>) {
+        let end = (CHIP8_PROGRAM_START + machine.program_memory.len()).min(MEMORY_SIZE);
+        self.bus
+            .memory_range_mut(CHIP8_PROGRAM_START..end)
+            .copy_from_slice(&machine.program_memory[..end - CHIP8_PROGRAM_START]);
+        self.registers = machine.registers;
+        self.index_register = machine.index_register;
+        self.program_counter = machine.program_counter;
+        self.grid = machine.grid;
+        self.delay_timer = machine.delay_timer;
+
+        self.stack = machine.stack;
+        if self.options.stack_in_memory {
+            for (i, entry) in self.stack.iter().enumerate() {
+                let address = VIP_STACK_TOP - 2 * i;
+                self.bus
+                    .memory_range_mut(address - 1..address + 1)
+                    .copy_from_slice(&entry.to_be_bytes());
+            }
+        }
+
+        self.store_memory_views(true, true);
+    }
+
+    pub fn set_key_callback(&mut self, callback: Box<dyn Fn(Key) -> bool>) {
         self.key_pressed = Some(callback);
     }
 
-    pub fn tick(&mut self) {
+    // Return addresses, the last one being the top of the stack
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    // Notified of every memory access made by the program, including instruction fetches
+    pub fn add_memory_observer(&mut self, observer: MemoryObserver) {
+        self.bus.add_observer(observer);
+    }
+
+    pub fn set_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    pub fn stack_depth(&self) -> usize {
+        self.options.stack_depth
+    }
+
+    // Timing of the next instruction on the COSMAC VIP
+    pub fn next_instruction_timing(&self) -> Result<InstructionTiming, Chip8Error> {
+        let opcode = Chip8State::decode_instruction(self.bus.peek_opcode(self.program_counter)?);
+        Ok(timing::instruction_timing(&opcode, &self.registers))
+    }
+
+    pub fn tick(&mut self) -> Result<(), Chip8Error> {
+        // Nothing is executed while waiting for a key
+        if self.waiting_for_key.is_some() {
+            return Ok(());
+        }
+
+        let address = self.program_counter;
+        let (raw_opcode, opcode) = self.bus.fetch(address)?;
+
+        self.load_memory_views();
+
+        let previous_registers = self.registers;
+        // Depends on the registers before the instruction
+        let cycles = self
+            .profiler
+            .as_ref()
+            .map(|_| timing::instruction_timing(&opcode, &self.registers).cycles);
+        let result = self.execute(opcode);
+
+        let accesses = self.bus.accesses();
+
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(address, &opcode, accesses, self.program_counter);
+        }
+
+        // Instructions failing are not counted, the machine stops on them
+        if let (Some(profiler), Some(cycles), Ok(())) = (&mut self.profiler, cycles, &result) {
+            profiler.record(address, raw_opcode, &opcode, cycles);
+        }
+
+        if let Some(tracer) = &mut self.tracer {
+            let entry = TraceEntry {
+                frame: tracer.frame(),
+                address,
+                opcode: raw_opcode,
+                index_register: self.index_register,
+                register_changes: TraceEntry::register_changes(
+                    &previous_registers,
+                    &self.registers,
+                ),
+                memory_writes: accesses
+                    .iter()
+                    .filter(|access| access.kind == AccessKind::Write)
+                    .map(|access| (access.address, access.value))
+                    .collect(),
+            };
+            tracer.record(&entry, &opcode);
+        }
+
+        // Instructions writing to memory leave the mirrored state untouched, but may have
+        // written over it
+        if accesses
+            .iter()
+            .any(|access| access.kind == AccessKind::Write)
+        {
+            self.load_memory_views();
+        } else {
+            self.store_memory_views(
+                self.registers != previous_registers,
+                matches!(opcode, Opcode::Clear | Opcode::DrawSprite { .. }),
+            );
+        }
+
+        result
+    }
+
+    // Must be called at 60Hz
+    pub fn tick_timers(&mut self) {
         if self.delay_timer > 0 {
-            match self.delay_clock.elapsed() {
-                Ok(d) => {
-                    if d.as_millis() >= 1000 / 60 {
-                        self.delay_timer -= 1;
-                        self.delay_clock = SystemTime::now();
-                    }
-                }
-                Err(err) => {
-                    println!("An error occurred: {}", err);
-                }
-            }
+            self.delay_timer -= 1;
+        }
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.next_frame();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(opcodes: &[u16]) -> Vec<u8> {
+        opcodes
+            .iter()
+            .flat_map(|opcode| opcode.to_be_bytes())
+            .collect()
+    }
+
+    fn run(state: &mut Chip8State, instructions: usize) -> Result<(), Chip8Error> {
+        for _ in 0..instructions {
+            state.tick()?;
         }
-        let opcode = self.decode_next_instruction();
-        self.execute(opcode);
+
+        Ok(())
+    }
+
+    // Patches the immediate of an ADD it then runs, counting 1 to 16 into V1, then draws
+    const SELF_MODIFYING: &[u16] = &[
+        0x6000, // LD V0, 00
+        0xA209, // LD I, 209
+        0x7001, // ADD V0, 01
+        0xF055, // LD [I], V0
+        0x7100, // ADD V1, <V0>
+        0x3010, // SE V0, 10
+        0x1204, // JP 204
+        0xA200, // LD I, 200
+        0xD015, // DRW V0, V1, 5
+        0x1212, // JP 212
+    ];
+
+    #[test]
+    fn decode_cache_follows_self_modifying_code() {
+        for &vip_layout in &[false, true] {
+            let run_with_cache = |decode_cache| {
+                let options = Chip8Options {
+                    vip_layout,
+                    decode_cache,
+                    ..Chip8Options::default()
+                };
+                let mut state = Chip8State::with_options(rom(SELF_MODIFYING), options);
+                run(&mut state, 100).unwrap();
+
+                state
+            };
+
+            let cached = run_with_cache(true);
+            let uncached = run_with_cache(false);
+
+            assert_eq!(cached.registers[1], 0x88);
+            assert_eq!(cached.registers, uncached.registers);
+            assert_eq!(cached.index_register, uncached.index_register);
+            assert_eq!(cached.program_counter, uncached.program_counter);
+            assert_eq!(cached.stack, uncached.stack);
+            assert_eq!(cached.grid, uncached.grid);
+            assert_eq!(cached.bus.memory()[..], uncached.bus.memory()[..]);
+            assert!(cached.grid.contains(&true));
+        }
+    }
+
+    fn with_stack_depth(program: &[u16], stack_depth: usize) -> Chip8State {
+        let options = Chip8Options {
+            stack_depth,
+            ..Chip8Options::default()
+        };
+
+        Chip8State::with_options(rom(program), options)
+    }
+
+    #[test]
+    fn calls_overflow_at_the_configured_depth() {
+        for &depth in &[SCHIP_STACK_DEPTH, VIP_STACK_DEPTH] {
+            // CALL 200, calling itself
+            let mut state = with_stack_depth(&[0x2200], depth);
+
+            run(&mut state, depth).unwrap();
+            assert_eq!(state.stack(), vec![0x202; depth].as_slice());
+
+            assert_eq!(
+                state.tick(),
+                Err(Chip8Error::StackOverflow {
+                    address: 0x200,
+                    depth
+                })
+            );
+            assert_eq!(state.stack().len(), depth);
+        }
+    }
+
+    #[test]
+    fn returns_underflow_with_an_empty_stack() {
+        // CALL 204, RET, RET: the one at 0x204 returns to the one at 0x202, with the stack empty
+        let mut state = with_stack_depth(&[0x2204, 0x00EE, 0x00EE], SCHIP_STACK_DEPTH);
+
+        run(&mut state, 2).unwrap();
+        assert_eq!(state.program_counter(), 0x202);
+        assert!(state.stack().is_empty());
+
+        assert_eq!(
+            state.tick(),
+            Err(Chip8Error::StackUnderflow { address: 0x202 })
+        );
+    }
+
+    #[test]
+    fn resync_restores_the_stack_and_delay_timer() {
+        for &stack_in_memory in &[false, true] {
+            let options = Chip8Options {
+                stack_in_memory,
+                ..Chip8Options::default()
+            };
+            // RET at 0x200, the return addresses come from the resync
+            let mut state = Chip8State::with_options(rom(&[0x00EE]), options);
+
+            state.resync(ResyncState {
+                program_memory: &rom(&[0x00EE, 0x00EE]),
+                registers: [1; 16],
+                index_register: 0x300,
+                program_counter: 0x200,
+                grid: vec![true; GRID_WIDTH * GRID_HEIGHT],
+                stack: vec![0x300, 0x202],
+                delay_timer: 30,
+            });
+            assert_eq!(state.delay_timer(), 30);
+            assert_eq!(state.stack(), [0x300, 0x202]);
+
+            run(&mut state, 2).unwrap();
+            assert_eq!(state.program_counter(), 0x300);
+            assert!(state.stack().is_empty());
+            assert_eq!(state.registers(), &[1; 16]);
+        }
+    }
+
+    fn vip_layout(program: &[u16], stack_depth: usize) -> Chip8State {
+        let options = Chip8Options {
+            stack_depth,
+            vip_layout: true,
+            ..Chip8Options::default()
+        };
+
+        Chip8State::with_options(rom(program), options)
+    }
+
+    #[test]
+    fn vip_layout_keeps_the_registers_at_0xef0() {
+        let mut state = vip_layout(
+            &[
+                0x6012, // LD V0, 12
+                0x6134, // LD V1, 34
+                0x6F56, // LD VF, 56
+                0xAEF4, // LD I, EF4
+                0xF155, // LD [I], V1, over V4 and V5
+                0x7401, // ADD V4, 01
+            ],
+            VIP_STACK_DEPTH,
+        );
+
+        run(&mut state, 3).unwrap();
+        assert_eq!(
+            state.memory()[0xEF0..0xF00],
+            [0x12, 0x34, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x56]
+        );
+
+        run(&mut state, 3).unwrap();
+        assert_eq!(state.registers()[4..6], [0x13, 0x34]);
+        assert_eq!(state.memory()[0xEF4..0xEF6], [0x13, 0x34]);
+    }
+
+    #[test]
+    fn vip_layout_keeps_the_stack_below_0xed0() {
+        // CALL 200, calling itself
+        let mut state = vip_layout(&[0x2200], VIP_STACK_MAX_DEPTH);
+
+        run(&mut state, VIP_STACK_MAX_DEPTH).unwrap();
+        for i in 0..VIP_STACK_MAX_DEPTH {
+            assert_eq!(state.memory()[0xECE - 2 * i..0xED0 - 2 * i], [0x02, 0x02]);
+        }
+        assert_eq!(state.memory()[0xE9F], 0);
+        assert_eq!(state.memory()[0xED0..0xEF0], [0; 0x20]);
+    }
+
+    #[test]
+    fn vip_layout_returns_to_addresses_written_to_the_stack() {
+        let mut state = vip_layout(
+            &[
+                0x2206, // CALL 206
+                0x1202, // JP 202
+                0x1204, // JP 204
+                0x6003, // LD V0, 03
+                0x6104, // LD V1, 04
+                0xAECE, // LD I, ECE
+                0xF155, // LD [I], V1, the return address becomes 0x304
+                0x00EE, // RET
+            ],
+            VIP_STACK_DEPTH,
+        );
+
+        run(&mut state, 5).unwrap();
+        assert_eq!(state.stack(), [0x304]);
+
+        run(&mut state, 1).unwrap();
+        assert_eq!(state.program_counter(), 0x304);
+    }
+
+    #[test]
+    fn vip_layout_keeps_the_display_at_0xf00() {
+        let mut state = vip_layout(
+            &[
+                0xA204, // LD I, 204
+                0xD011, // DRW V0, V1, 1
+                0x8100, // sprite
+            ],
+            VIP_STACK_DEPTH,
+        );
+
+        run(&mut state, 2).unwrap();
+        assert_eq!(state.memory()[0xF00..0xF09], [0x81, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(state.grid.iter().filter(|&&cell| cell).count(), 2);
+        assert!(state.grid[0] && state.grid[7]);
+
+        let mut state = vip_layout(
+            &[
+                0x60F0, // LD V0, F0
+                0xAF08, // LD I, F08, the second row
+                0xF055, // LD [I], V0
+            ],
+            VIP_STACK_DEPTH,
+        );
+        run(&mut state, 3).unwrap();
+        assert_eq!(
+            state.grid[GRID_WIDTH..GRID_WIDTH + 8],
+            [true, true, true, true, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn machine_code_calls_are_skipped() {
+        let mut state = Chip8State::with_options(rom(&[0x0123, 0x6001]), Chip8Options::default());
+
+        run(&mut state, 2).unwrap();
+
+        assert_eq!(state.program_counter(), 0x204);
+        assert_eq!(state.registers()[0], 1);
+        assert!(state.stack().is_empty());
     }
 }
@@ -0,0 +1,159 @@
+use super::opcodes::Opcode;
+
+// Timings of the original COSMAC VIP interpreter, in CDP1802 machine cycles (8 clock cycles
+// at 1.76 MHz). They count the instructions of its routines in the listing of the RCA COSMAC
+// VIP Instruction Manual (VIP-311), as walked through in Laurence Scotford's "Chip-8 on the
+// COSMAC VIP" articles, each 1802 instruction taking 2 machine cycles and long branches 3.
+pub const CYCLES_PER_FRAME: u32 = 3668;
+
+// The 1861 display interrupt and its DMA keep the CPU busy during the 128 displayed lines
+pub const DISPLAY_CYCLES: u32 = 1832;
+
+pub const AVAILABLE_CYCLES_PER_FRAME: u32 = CYCLES_PER_FRAME - DISPLAY_CYCLES;
+
+// Fetching and dispatching an instruction, common to all of them
+const FETCH_CYCLES: u32 = 40;
+
+pub struct InstructionTiming {
+    pub cycles: u32,
+    // The interpreter waits for the next display interrupt before drawing
+    pub waits_for_vblank: bool,
+}
+
+pub fn instruction_timing(opcode: &Opcode, registers: &[u8; 16]) -> InstructionTiming {
+    let cycles = match *opcode {
+        Opcode::Invalid { .. } => 0,
+        Opcode::Add { .. } => 10,
+        Opcode::AddAddress { .. } => 19,
+        Opcode::Assign { .. }
+        | Opcode::BitOpAnd { .. }
+        | Opcode::BitOpOr { .. }
+        | Opcode::BitOpXor { .. }
+        | Opcode::BitOpShiftL { .. }
+        | Opcode::BitOpShiftR { .. }
+        | Opcode::Increment { .. }
+        | Opcode::Sub { .. }
+        | Opcode::SubVyVx { .. } => 44,
+        // Machine code routines called by 0NNN are not timed, only the call is counted
+        Opcode::CallRca { .. }
+        | Opcode::CallSubroutine { .. }
+        | Opcode::Goto { .. }
+        | Opcode::Jump { .. }
+        | Opcode::Return => 23,
+        // The routine at 00E0 clears the 256 bytes of the display page one by one, in a loop of
+        // 5 instructions
+        Opcode::Clear => 24 + 256 * 10,
+        Opcode::CondEq { .. } | Opcode::CondNe { .. } => 12,
+        Opcode::CondKeyPressed { .. } | Opcode::CondKeyReleased { .. } => 16,
+        Opcode::CondVxVyEq { .. } | Opcode::CondVxVyNe { .. } => 16,
+        // Sprites not aligned on a byte are shifted and spread over two display bytes
+        Opcode::DrawSprite { rx, n, .. } => {
+            let row_cycles = if registers[rx as usize] & 7 == 0 {
+                20
+            } else {
+                36
+            };
+
+            68 + u32::from(n) * row_cycles
+        }
+        Opcode::GetDelayTimer { .. } => 10,
+        Opcode::LoadRegisters { r } | Opcode::StoreRegisters { r } => 14 + 14 * (u32::from(r) + 1),
+        Opcode::Set { .. } => 6,
+        Opcode::SetAddress { .. } => 12,
+        // Digits are computed by repeated subtraction
+        Opcode::SetBCD { r } => {
+            let value = registers[r as usize];
+            let digit_sum = value / 100 + (value / 10) % 10 + value % 10;
+
+            84 + 16 * u32::from(digit_sum)
+        }
+        Opcode::SetDelayTimer { .. } | Opcode::SetSoundTimer { .. } => 10,
+        Opcode::SetRand { .. } => 36,
+        Opcode::SetSprite { .. } => 20,
+        Opcode::WaitKeyPressed { .. } => 16,
+    };
+
+    InstructionTiming {
+        cycles: FETCH_CYCLES + cycles,
+        waits_for_vblank: matches!(opcode, Opcode::DrawSprite { .. }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cycles(opcode: Opcode, registers: &[u8; 16]) -> u32 {
+        instruction_timing(&opcode, registers).cycles - FETCH_CYCLES
+    }
+
+    #[test]
+    fn clearing_the_screen_loops_over_the_display() {
+        assert_eq!(cycles(Opcode::Clear, &[0; 16]), 2584);
+    }
+
+    #[test]
+    fn sprites_cost_more_per_row_when_not_byte_aligned() {
+        let mut registers = [0; 16];
+        let sprite = |n| Opcode::DrawSprite { rx: 1, ry: 2, n };
+
+        for &x in &[0, 8, 56] {
+            registers[1] = x;
+            assert_eq!(cycles(sprite(1), &registers), 68 + 20);
+            assert_eq!(cycles(sprite(15), &registers), 68 + 15 * 20);
+        }
+
+        for &x in &[1, 7, 63] {
+            registers[1] = x;
+            assert_eq!(cycles(sprite(1), &registers), 68 + 36);
+            assert_eq!(cycles(sprite(15), &registers), 68 + 15 * 36);
+        }
+
+        // Only X decides the alignment
+        registers[1] = 0;
+        registers[2] = 5;
+        assert_eq!(cycles(sprite(4), &registers), 68 + 4 * 20);
+        assert_eq!(cycles(sprite(0), &registers), 68);
+    }
+
+    #[test]
+    fn sprites_wait_for_the_display_interrupt() {
+        let registers = [0; 16];
+        let draw = Opcode::DrawSprite { rx: 0, ry: 0, n: 1 };
+
+        assert!(instruction_timing(&draw, &registers).waits_for_vblank);
+        assert!(!instruction_timing(&Opcode::Clear, &registers).waits_for_vblank);
+    }
+
+    #[test]
+    fn bcd_costs_one_subtraction_per_unit_of_each_digit() {
+        let mut registers = [0; 16];
+        let bcd = Opcode::SetBCD { r: 3 };
+
+        for &(value, digit_sum) in &[(0, 0), (9, 9), (100, 1), (255, 12), (199, 19)] {
+            registers[3] = value;
+            assert_eq!(cycles(bcd, &registers), 84 + 16 * digit_sum, "{}", value);
+        }
+    }
+
+    #[test]
+    fn register_transfers_cost_one_step_per_register() {
+        let registers = [0; 16];
+
+        for r in 0..16 {
+            let expected = 14 + 14 * (u32::from(r) + 1);
+            assert_eq!(cycles(Opcode::StoreRegisters { r }, &registers), expected);
+            assert_eq!(cycles(Opcode::LoadRegisters { r }, &registers), expected);
+        }
+    }
+
+    #[test]
+    fn every_instruction_pays_for_its_fetch() {
+        let registers = [0; 16];
+
+        assert_eq!(
+            instruction_timing(&Opcode::Set { r: 0, value: 0 }, &registers).cycles,
+            FETCH_CYCLES + 6
+        );
+    }
+}
@@ -0,0 +1,512 @@
+use super::opcodes::Opcode;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+
+// Binary traces start with this magic and a version byte, followed by one record per
+// instruction: frame (u32), address (u16), opcode (u16), I (u16), changed register count
+// (u8), then register, previous value and new value for each changed register (u8 each),
+// memory write count (u8), then address (u16) and value (u8) for each write.
+// Values are little endian, decoded opcodes are not stored.
+pub const BINARY_TRACE_MAGIC: &[u8] = b"C8TR";
+// Bumped whenever the record layout changes, version 1 had no memory writes
+pub const BINARY_TRACE_VERSION: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+// Instructions are traced when they match all the filters, bounds are inclusive
+#[derive(Clone, Default)]
+pub struct TraceFilter {
+    pub addresses: Option<(usize, usize)>,
+    pub frames: Option<(u64, u64)>,
+    // Opcode names, empty for all of them
+    pub opcodes: Vec<&'static str>,
+}
+
+impl TraceFilter {
+    fn matches(&self, entry: &TraceEntry, opcode: &Opcode) -> bool {
+        self.addresses
+            .is_none_or(|(start, end)| (start..=end).contains(&entry.address))
+            && self
+                .frames
+                .is_none_or(|(start, end)| (start..=end).contains(&entry.frame))
+            && (self.opcodes.is_empty() || self.opcodes.contains(&opcode.name()))
+    }
+}
+
+// Comma separated opcode names, as in Opcode (CallSubroutine,DrawSprite), ignoring case
+pub fn parse_opcode_names(list: &str) -> Result<Vec<&'static str>, String> {
+    list.split(',')
+        .map(|name| {
+            Opcode::NAMES
+                .iter()
+                .find(|known| known.eq_ignore_ascii_case(name.trim()))
+                .copied()
+                .ok_or_else(|| format!("Unknown opcode: {}", name))
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegisterChange {
+    pub register: u8,
+    pub previous: u8,
+    pub value: u8,
+}
+
+// One executed instruction and its effects
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    pub frame: u64,
+    pub address: usize,
+    pub opcode: u16,
+    // After the instruction
+    pub index_register: u16,
+    pub register_changes: Vec<RegisterChange>,
+    pub memory_writes: Vec<(usize, u8)>,
+}
+
+impl TraceEntry {
+    pub fn register_changes(previous: &[u8; 16], registers: &[u8; 16]) -> Vec<RegisterChange> {
+        (0..16)
+            .filter(|&r| previous[r] != registers[r])
+            .map(|r| RegisterChange {
+                register: r as u8,
+                previous: previous[r],
+                value: registers[r],
+            })
+            .collect()
+    }
+
+    // "<frame> <address> <opcode> I=<I> [V<r>:<previous>><value>]... [[<address>]=<value>]...",
+    // in hexadecimal except for the frame, anything after ';' being a comment
+    pub fn parse(line: &str) -> Option<TraceEntry> {
+        let line = line.split(';').next()?;
+        let mut fields = line.split_whitespace();
+
+        let frame = fields.next()?.parse().ok()?;
+        let address = usize::from_str_radix(fields.next()?, 16).ok()?;
+        let opcode = u16::from_str_radix(fields.next()?, 16).ok()?;
+        let index_register = u16::from_str_radix(fields.next()?.strip_prefix("I=")?, 16).ok()?;
+
+        let mut register_changes = Vec::new();
+        let mut memory_writes = Vec::new();
+        for field in fields {
+            if let Some(change) = field.strip_prefix('V') {
+                let (register, values) = change.split_once(':')?;
+                let (previous, value) = values.split_once('>')?;
+                register_changes.push(RegisterChange {
+                    register: u8::from_str_radix(register, 16).ok().filter(|&r| r < 16)?,
+                    previous: u8::from_str_radix(previous, 16).ok()?,
+                    value: u8::from_str_radix(value, 16).ok()?,
+                });
+            } else {
+                let (address, value) = field.strip_prefix('[')?.split_once("]=")?;
+                memory_writes.push((
+                    usize::from_str_radix(address, 16).ok()?,
+                    u8::from_str_radix(value, 16).ok()?,
+                ));
+            }
+        }
+
+        Some(TraceEntry {
+            frame,
+            address,
+            opcode,
+            index_register,
+            register_changes,
+            memory_writes,
+        })
+    }
+
+    fn encode(&self, record: &mut Vec<u8>) {
+        record.extend_from_slice(&(self.frame as u32).to_le_bytes());
+        record.extend_from_slice(&(self.address as u16).to_le_bytes());
+        record.extend_from_slice(&self.opcode.to_le_bytes());
+        record.extend_from_slice(&self.index_register.to_le_bytes());
+        record.push(self.register_changes.len() as u8);
+        for change in &self.register_changes {
+            record.extend_from_slice(&[change.register, change.previous, change.value]);
+        }
+        record.push(self.memory_writes.len() as u8);
+        for &(address, value) in &self.memory_writes {
+            record.extend_from_slice(&(address as u16).to_le_bytes());
+            record.push(value);
+        }
+    }
+
+    // Returns the entry and the size of its record
+    fn decode(record: &[u8]) -> Option<(TraceEntry, usize)> {
+        let word = |offset: usize| -> Option<u16> {
+            Some(u16::from_le_bytes([
+                *record.get(offset)?,
+                *record.get(offset + 1)?,
+            ]))
+        };
+
+        let frame = u32::from_le_bytes(record.get(0..4)?.try_into().ok()?);
+        let mut entry = TraceEntry {
+            frame: u64::from(frame),
+            address: usize::from(word(4)?),
+            opcode: word(6)?,
+            index_register: word(8)?,
+            register_changes: Vec::new(),
+            memory_writes: Vec::new(),
+        };
+
+        let mut offset = 11;
+        for _ in 0..*record.get(10)? {
+            let change = record.get(offset..offset + 3)?;
+            entry.register_changes.push(RegisterChange {
+                register: change[0],
+                previous: change[1],
+                value: change[2],
+            });
+            offset += 3;
+        }
+
+        let writes = *record.get(offset)?;
+        offset += 1;
+        for _ in 0..writes {
+            entry
+                .memory_writes
+                .push((usize::from(word(offset)?), *record.get(offset + 2)?));
+            offset += 3;
+        }
+
+        Some((entry, offset))
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:6} {:03X} {:04X} I={:03X}",
+            self.frame, self.address, self.opcode, self.index_register
+        )?;
+        for change in &self.register_changes {
+            write!(
+                f,
+                " V{:X}:{:02X}>{:02X}",
+                change.register, change.previous, change.value
+            )?;
+        }
+        for (address, value) in &self.memory_writes {
+            write!(f, " [{:03X}]={:02X}", address, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Reads a text or binary trace
+pub fn load_trace(path: &str) -> Result<Vec<TraceEntry>, Box<dyn Error>> {
+    let content = fs::read(path)?;
+
+    let mut entries = Vec::new();
+    if let Some(records) = content.strip_prefix(BINARY_TRACE_MAGIC) {
+        let mut records = match records.split_first() {
+            Some((&BINARY_TRACE_VERSION, records)) => records,
+            Some((version, _)) => {
+                return Err(format!(
+                    "{}: unsupported binary trace version {}, expected {}",
+                    path, version, BINARY_TRACE_VERSION
+                )
+                .into())
+            }
+            None => return Err(format!("{}: truncated header", path).into()),
+        };
+        while !records.is_empty() {
+            let (entry, size) = TraceEntry::decode(records)
+                .ok_or_else(|| format!("{}: truncated record {}", path, entries.len() + 1))?;
+            entries.push(entry);
+            records = &records[size..];
+        }
+    } else {
+        let content = String::from_utf8(content).map_err(|_| format!("{}: not a trace", path))?;
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            entries.push(TraceEntry::parse(line).ok_or_else(|| {
+                format!(
+                    "{}:{}: invalid trace entry \"{}\"",
+                    path,
+                    line_number + 1,
+                    line
+                )
+            })?);
+        }
+    }
+
+    Ok(entries)
+}
+
+pub struct Tracer {
+    output: BufWriter<File>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    frame: u64,
+    // Writing stops at the first error, which is reported when finishing
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn create(path: &str, format: TraceFormat, filter: TraceFilter) -> io::Result<Tracer> {
+        let mut output = BufWriter::new(File::create(path)?);
+        if format == TraceFormat::Binary {
+            output.write_all(BINARY_TRACE_MAGIC)?;
+            output.write_all(&[BINARY_TRACE_VERSION])?;
+        }
+
+        Ok(Tracer {
+            output,
+            format,
+            filter,
+            frame: 0,
+            error: None,
+        })
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn next_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    pub fn record(&mut self, entry: &TraceEntry, opcode: &Opcode) {
+        if self.error.is_some() || !self.filter.matches(entry, opcode) {
+            return;
+        }
+
+        let result = match self.format {
+            TraceFormat::Text => writeln!(self.output, "{} ; {:?}", entry, opcode),
+            TraceFormat::Binary => {
+                let mut record = Vec::new();
+                entry.encode(&mut record);
+                self.output.write_all(&record)
+            }
+        };
+
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.output.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::state::Chip8State;
+    use std::env;
+
+    fn entry() -> TraceEntry {
+        TraceEntry {
+            frame: 12,
+            address: 0x2A4,
+            opcode: 0xF355,
+            index_register: 0x3F0,
+            register_changes: vec![
+                RegisterChange {
+                    register: 0x3,
+                    previous: 0x00,
+                    value: 0xFF,
+                },
+                RegisterChange {
+                    register: 0xF,
+                    previous: 0x01,
+                    value: 0x00,
+                },
+            ],
+            memory_writes: vec![(0x3F0, 0x12), (0xFFF, 0x00)],
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("chip8-trace-{}-{}", name, std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn text_entries_round_trip() {
+        let entry = entry();
+        let line = entry.to_string();
+        assert_eq!(
+            line,
+            "    12 2A4 F355 I=3F0 V3:00>FF VF:01>00 [3F0]=12 [FFF]=00"
+        );
+
+        assert_eq!(TraceEntry::parse(&line), Some(entry.clone()));
+        assert_eq!(
+            TraceEntry::parse(&format!("{} ; StoreRegisters {{ r: 3 }}", line)),
+            Some(entry)
+        );
+    }
+
+    #[test]
+    fn invalid_text_entries_are_rejected() {
+        for line in &[
+            "",
+            "12 2A4 F355",
+            "12 2A4 F355 3F0",
+            "12 2A4 F355 I=3F0 V10:00>01",
+            "12 2A4 F355 I=3F0 V3:00",
+            "12 2A4 F355 I=3F0 [3F0]",
+            "x 2A4 F355 I=3F0",
+        ] {
+            assert_eq!(TraceEntry::parse(line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn binary_entries_round_trip() {
+        let entry = entry();
+        let mut record = Vec::new();
+        entry.encode(&mut record);
+        assert_eq!(record.len(), 11 + 2 * 3 + 1 + 2 * 3);
+
+        assert_eq!(TraceEntry::decode(&record), Some((entry, record.len())));
+
+        for size in 0..record.len() {
+            assert_eq!(TraceEntry::decode(&record[..size]), None, "{}", size);
+        }
+    }
+
+    #[test]
+    fn written_traces_are_loaded_back() {
+        for &(name, format) in &[("text", TraceFormat::Text), ("binary", TraceFormat::Binary)] {
+            let path = temp_path(name);
+            let mut tracer = Tracer::create(&path, format, TraceFilter::default()).unwrap();
+            let mut entries = vec![entry(), entry()];
+            entries[1].register_changes.clear();
+            entries[1].memory_writes.clear();
+            for entry in &entries {
+                tracer.record(entry, &Chip8State::decode_instruction(entry.opcode));
+            }
+            tracer.finish().unwrap();
+
+            let loaded = load_trace(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+
+            assert_eq!(loaded, entries, "{}", name);
+        }
+    }
+
+    #[test]
+    fn binary_traces_need_the_current_version() {
+        let mut record = Vec::new();
+        entry().encode(&mut record);
+
+        for &(name, version, error) in &[
+            ("v1", 1, "unsupported binary trace version 1, expected 2"),
+            ("v3", 3, "unsupported binary trace version 3, expected 2"),
+        ] {
+            let path = temp_path(name);
+            let mut content = BINARY_TRACE_MAGIC.to_vec();
+            content.push(version);
+            content.extend_from_slice(&record);
+            fs::write(&path, content).unwrap();
+
+            let result = load_trace(&path);
+            fs::remove_file(&path).unwrap();
+
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                format!("{}: {}", path, error)
+            );
+        }
+
+        let path = temp_path("header");
+        fs::write(&path, BINARY_TRACE_MAGIC).unwrap();
+        let result = load_trace(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            format!("{}: truncated header", path)
+        );
+    }
+
+    #[test]
+    fn traces_without_the_magic_are_not_binary() {
+        let mut record = Vec::new();
+        entry().encode(&mut record);
+
+        let path = temp_path("magic");
+        let mut content = b"C8TX\x02".to_vec();
+        content.extend_from_slice(&record);
+        fs::write(&path, content).unwrap();
+
+        let result = load_trace(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn opcode_names_ignore_case() {
+        assert_eq!(
+            parse_opcode_names("drawsprite, CALLSUBROUTINE,Return"),
+            Ok(vec!["DrawSprite", "CallSubroutine", "Return"])
+        );
+    }
+
+    #[test]
+    fn unknown_opcode_names_are_rejected() {
+        assert_eq!(
+            parse_opcode_names("DrawSprite,Draw"),
+            Err(String::from("Unknown opcode: Draw"))
+        );
+        assert!(parse_opcode_names("").is_err());
+    }
+
+    #[test]
+    fn filters_match_all_their_conditions() {
+        let entry = entry();
+        let opcode = Chip8State::decode_instruction(entry.opcode);
+
+        let filter = TraceFilter {
+            addresses: Some((0x200, 0x2A4)),
+            frames: Some((12, 12)),
+            opcodes: parse_opcode_names("storeregisters").unwrap(),
+        };
+        assert!(filter.matches(&entry, &opcode));
+
+        for filter in &[
+            TraceFilter {
+                addresses: Some((0x2A6, 0x2FF)),
+                ..filter.clone()
+            },
+            TraceFilter {
+                frames: Some((13, 20)),
+                ..filter.clone()
+            },
+            TraceFilter {
+                opcodes: parse_opcode_names("LoadRegisters").unwrap(),
+                ..filter.clone()
+            },
+        ] {
+            assert!(!filter.matches(&entry, &opcode));
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use crate::frontend::runner::MAX_INSTRUCTIONS_PER_FRAME;
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Default)]
+struct RomSettings {
+    // Only informative, ROMs are identified by their hash
+    name: Option<String>,
+    instructions_per_frame: Option<usize>,
+}
+
+// Identifies a ROM by its contents (64-bit FNV-1a), so renamed copies share their settings
+pub fn rom_hash(rom: &[u8]) -> String {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for &byte in rom {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01B3);
+    }
+
+    format!("{:016x}", hash)
+}
+
+// User settings saved between sessions, made of one section per ROM:
+//
+// [<rom hash>]
+// name = PONG
+// instructions_per_frame = 15
+//
+// The default configuration is empty and has no path.
+#[derive(Default)]
+pub struct UserConfig {
+    path: Option<PathBuf>,
+    roms: BTreeMap<String, RomSettings>,
+}
+
+impl UserConfig {
+    // $XDG_CONFIG_HOME/chip8/config, ~/.config/chip8/config or %APPDATA%\chip8\config
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = if cfg!(windows) {
+            env::var_os("APPDATA").map(PathBuf::from)
+        } else {
+            env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        };
+
+        config_dir.map(|dir| dir.join("chip8").join("config"))
+    }
+
+    // A missing file is an empty configuration, without a path nothing is ever saved
+    pub fn load(path: Option<PathBuf>) -> Result<UserConfig, Box<dyn Error>> {
+        let mut roms: BTreeMap<String, RomSettings> = BTreeMap::new();
+
+        let path = match path {
+            Some(path) if path.exists() => path,
+            path => return Ok(UserConfig { path, roms }),
+        };
+
+        let content = fs::read_to_string(&path)?;
+        let mut current = None;
+
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid_line = || {
+                format!(
+                    "{}:{}: invalid setting \"{}\"",
+                    path.display(),
+                    line_number + 1,
+                    line
+                )
+            };
+
+            if line.starts_with('[') && line.ends_with(']') {
+                let hash = line[1..line.len() - 1].trim().to_lowercase();
+                roms.entry(hash.clone()).or_default();
+                current = Some(hash);
+                continue;
+            }
+
+            let settings = current
+                .as_ref()
+                .and_then(|hash| roms.get_mut(hash))
+                .ok_or_else(invalid_line)?;
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().ok_or_else(invalid_line)?.trim();
+
+            match key {
+                "name" => settings.name = Some(value.to_owned()),
+                "instructions_per_frame" => {
+                    let count = value
+                        .parse()
+                        .ok()
+                        .filter(|count| (1..=MAX_INSTRUCTIONS_PER_FRAME).contains(count))
+                        .ok_or_else(invalid_line)?;
+                    settings.instructions_per_frame = Some(count);
+                }
+                _ => return Err(invalid_line().into()),
+            }
+        }
+
+        Ok(UserConfig {
+            path: Some(path),
+            roms,
+        })
+    }
+
+    pub fn instructions_per_frame(&self, rom_hash: &str) -> Option<usize> {
+        self.roms
+            .get(rom_hash)
+            .and_then(|settings| settings.instructions_per_frame)
+    }
+
+    pub fn set_instructions_per_frame(&mut self, rom_hash: &str, rom_name: &str, count: usize) {
+        let settings = self.roms.entry(rom_hash.to_owned()).or_default();
+        settings.name = Some(rom_name.to_owned());
+        settings.instructions_per_frame = Some(count);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut content = String::new();
+        for (hash, settings) in &self.roms {
+            writeln!(content, "[{}]", hash).unwrap();
+            if let Some(name) = &settings.name {
+                writeln!(content, "name = {}", name).unwrap();
+            }
+            if let Some(count) = settings.instructions_per_frame {
+                writeln!(content, "instructions_per_frame = {}", count).unwrap();
+            }
+            content.push('\n');
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(path, content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, content: &str) -> Result<UserConfig, Box<dyn Error>> {
+        let path = env::temp_dir().join(format!("chip8-config-{}-{}", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        let config = UserConfig::load(Some(path.clone()));
+        fs::remove_file(path).unwrap();
+
+        config
+    }
+
+    #[test]
+    fn instructions_per_frame_are_loaded_per_rom() {
+        let config = load(
+            "valid",
+            "[00ff]\nname = PONG\ninstructions_per_frame = 15\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.instructions_per_frame("00ff"), Some(15));
+        assert_eq!(config.instructions_per_frame("0000"), None);
+    }
+
+    #[test]
+    fn instructions_per_frame_out_of_range_are_rejected() {
+        for count in &["0", "1001", "-1", "many"] {
+            let content = format!("[00ff]\ninstructions_per_frame = {}\n", count);
+            assert!(load("range", &content).is_err(), "{}", count);
+        }
+
+        let content = format!(
+            "[00ff]\ninstructions_per_frame = {}\n",
+            MAX_INSTRUCTIONS_PER_FRAME
+        );
+        assert!(load("max", &content).is_ok());
+    }
+
+    #[test]
+    fn saved_settings_are_loaded_back() {
+        let dir = env::temp_dir().join(format!("chip8-config-saved-{}", std::process::id()));
+        let path = dir.join("chip8").join("config");
+        let pong = rom_hash(b"PONG");
+        let brix = rom_hash(b"BRIX");
+
+        let mut config = UserConfig::load(Some(path.clone())).unwrap();
+        config.set_instructions_per_frame(&pong, "PONG", 15);
+        config.set_instructions_per_frame(&brix, "BRIX", 8);
+        config.set_instructions_per_frame(&pong, "PONG2", 20);
+        config.save().unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let config = UserConfig::load(Some(path)).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(content.contains("name = PONG2\ninstructions_per_frame = 20\n"));
+        assert_eq!(config.instructions_per_frame(&pong), Some(20));
+        assert_eq!(config.instructions_per_frame(&brix), Some(8));
+        assert_eq!(config.instructions_per_frame(&rom_hash(b"")), None);
+    }
+
+    #[test]
+    fn configurations_without_a_path_are_not_saved() {
+        let mut config = UserConfig::load(None).unwrap();
+        config.set_instructions_per_frame("00ff", "PONG", 15);
+
+        config.save().unwrap();
+        assert_eq!(config.instructions_per_frame("00ff"), Some(15));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::palette::Palette;
+
+// Below this intensity a cell is considered fully dark
+const MIN_INTENSITY: f32 = 1.0 / 255.0;
+
+const SCANLINE_BRIGHTNESS: f32 = 0.65;
+
+#[derive(Clone, Copy, Default)]
+pub struct CrtOptions {
+    // Fraction of its brightness a cell keeps every frame after being turned off
+    pub phosphor_decay: Option<f32>,
+    // Averages the last two frames, which hides sprites erased and redrawn every other frame
+    pub frame_blending: bool,
+    pub scanlines: bool,
+}
+
+impl CrtOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.phosphor_decay.is_some() || self.frame_blending || self.scanlines
+    }
+}
+
+// Software post-process simulating a CRT, run on the CPU once per 60Hz frame
+pub struct CrtFilter {
+    options: CrtOptions,
+    intensities: Vec<f32>,
+    previous_grid: Vec<bool>,
+    animating: bool,
+}
+
+impl CrtFilter {
+    pub fn new(options: CrtOptions, cell_count: usize) -> CrtFilter {
+        CrtFilter {
+            options,
+            intensities: vec![0.0; cell_count],
+            previous_grid: vec![false; cell_count],
+            animating: false,
+        }
+    }
+
+    // Starts over from a dark screen when the display resolution changes
+    pub fn resize(&mut self, cell_count: usize) {
+        if self.intensities.len() != cell_count {
+            *self = CrtFilter::new(self.options, cell_count);
+        }
+    }
+
+    pub fn update(&mut self, grid: &[bool]) {
+        let mut animating = false;
+
+        for ((intensity, &cell), previous) in self
+            .intensities
+            .iter_mut()
+            .zip(grid)
+            .zip(self.previous_grid.iter_mut())
+        {
+            let lit = if cell { 1.0 } else { 0.0 };
+
+            let target = if self.options.frame_blending {
+                let blended = (lit + if *previous { 1.0 } else { 0.0 }) / 2.0;
+                animating |= *previous != cell;
+                blended
+            } else {
+                lit
+            };
+
+            let new_intensity = match self.options.phosphor_decay {
+                Some(decay) => {
+                    let decayed = *intensity * decay;
+                    if decayed > target && decayed >= MIN_INTENSITY {
+                        animating = true;
+                        decayed
+                    } else {
+                        target
+                    }
+                }
+                None => target,
+            };
+
+            *intensity = new_intensity;
+            *previous = cell;
+        }
+
+        self.animating = animating;
+    }
+
+    // True while the picture keeps changing without the grid being modified
+    pub fn is_animating(&self) -> bool {
+        self.animating
+    }
+
+    pub fn cell_color(&self, index: usize, palette: &Palette) -> u32 {
+        blend(
+            palette.background(),
+            palette.foreground(),
+            self.intensities[index],
+        )
+    }
+
+    // Darkens every other line of the output
+    pub fn apply_scanline(&self, color: u32, y: usize) -> u32 {
+        if self.options.scanlines && y % 2 == 1 {
+            blend(0, color, SCANLINE_BRIGHTNESS)
+        } else {
+            color
+        }
+    }
+}
+
+fn blend(from: u32, to: u32, factor: f32) -> u32 {
+    let channel = |shift: u32| {
+        let from = ((from >> shift) & 0xFF) as f32;
+        let to = ((to >> shift) & 0xFF) as f32;
+
+        ((from + (to - from) * factor).round() as u32) << shift
+    };
+
+    channel(16) | channel(8) | channel(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intensities(filter: &mut CrtFilter, grids: &[bool]) -> Vec<(f32, bool)> {
+        grids
+            .iter()
+            .map(|&cell| {
+                filter.update(&[cell]);
+                (filter.intensities[0], filter.is_animating())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn phosphors_decay_until_dark() {
+        let options = CrtOptions {
+            phosphor_decay: Some(0.5),
+            ..CrtOptions::default()
+        };
+        let mut filter = CrtFilter::new(options, 1);
+
+        assert_eq!(
+            intensities(&mut filter, &[true, false, false, false]),
+            [(1.0, false), (0.5, true), (0.25, true), (0.125, true)]
+        );
+        assert_eq!(filter.cell_color(0, &Palette::default()), 0x202020);
+
+        // Turned back on at full brightness
+        assert_eq!(intensities(&mut filter, &[true]), [(1.0, false)]);
+
+        // 1/128 is still visible, 1/256 is below a step of 8 bit colors
+        let dark = intensities(&mut filter, &[false; 8]);
+        assert_eq!(dark[6], (1.0 / 128.0, true));
+        assert_eq!(dark[7], (0.0, false));
+    }
+
+    #[test]
+    fn frame_blending_averages_two_frames() {
+        let options = CrtOptions {
+            frame_blending: true,
+            ..CrtOptions::default()
+        };
+        let mut filter = CrtFilter::new(options, 1);
+
+        assert_eq!(
+            intensities(&mut filter, &[true, true, false, false, true, false]),
+            [
+                (0.5, true),
+                (1.0, false),
+                (0.5, true),
+                (0.0, false),
+                (0.5, true),
+                (0.5, true),
+            ]
+        );
+        assert_eq!(filter.cell_color(0, &Palette::default()), 0x808080);
+    }
+
+    #[test]
+    fn scanlines_darken_odd_lines() {
+        let filter = CrtFilter::new(
+            CrtOptions {
+                scanlines: true,
+                ..CrtOptions::default()
+            },
+            1,
+        );
+        assert_eq!(filter.apply_scanline(0xFFFFFF, 0), 0xFFFFFF);
+        assert_eq!(filter.apply_scanline(0xFFFFFF, 1), 0xA6A6A6);
+        assert_eq!(filter.apply_scanline(0x33FF66, 3), 0x21A642);
+
+        let filter = CrtFilter::new(CrtOptions::default(), 1);
+        assert_eq!(filter.apply_scanline(0xFFFFFF, 1), 0xFFFFFF);
+    }
+
+    #[test]
+    fn resizing_starts_from_a_dark_screen() {
+        let options = CrtOptions {
+            phosphor_decay: Some(0.5),
+            ..CrtOptions::default()
+        };
+        let mut filter = CrtFilter::new(options, 1);
+        filter.update(&[true]);
+
+        filter.resize(1);
+        assert_eq!(filter.intensities, [1.0]);
+
+        filter.resize(2);
+        assert_eq!(filter.intensities, [0.0, 0.0]);
+        assert!(!filter.is_animating());
+    }
+}
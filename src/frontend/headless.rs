@@ -0,0 +1,345 @@
+use super::runner::Runner;
+use super::{CELL_SIZE, INSTRUCTIONS_PER_FRAME};
+use crate::capture::{self, GifRecorder};
+use crate::chip8::error::Chip8Error;
+use crate::chip8::keys::Key;
+use crate::chip8::state::Chip8State;
+use crate::options::RunOptions;
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+pub struct InputEvent {
+    pub frame: u64,
+    pub key: Key,
+    pub down: bool,
+}
+
+// Input scripts are made of "<frame> <down|up> <key>" lines, key being a hexadecimal digit
+pub fn load_input_script(path: &str) -> Result<Vec<InputEvent>, Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+
+    let mut events = Vec::new();
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid_line = || format!("{}:{}: invalid input \"{}\"", path, line_number + 1, line);
+
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() != 3 {
+            return Err(invalid_line().into());
+        }
+
+        let frame = parts[0].parse::<u64>().map_err(|_| invalid_line())?;
+        let down = match parts[1] {
+            "down" => true,
+            "up" => false,
+            _ => return Err(invalid_line().into()),
+        };
+        let key = u8::from_str_radix(parts[2], 16)
+            .ok()
+            .and_then(num::FromPrimitive::from_u8)
+            .ok_or_else(invalid_line)?;
+
+        events.push(InputEvent { frame, key, down });
+    }
+
+    events.sort_by_key(|event| event.frame);
+
+    Ok(events)
+}
+
+fn write_output(path: &str, content: &str) -> io::Result<()> {
+    if path == "-" {
+        io::stdout().write_all(content.as_bytes())
+    } else {
+        fs::write(path, content)
+    }
+}
+
+fn json_array<T: ToString>(values: &[T]) -> String {
+    let values: Vec<String> = values.iter().map(T::to_string).collect();
+    format!("[{}]", values.join(", "))
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+
+    json
+}
+
+fn state_to_json(state: &Chip8State, frames: u64, error: Option<&Chip8Error>) -> String {
+    let mut json = String::from("{\n");
+    writeln!(json, "  \"frames\": {},", frames).unwrap();
+    writeln!(json, "  \"pc\": {},", state.program_counter()).unwrap();
+    writeln!(json, "  \"i\": {},", state.index_register()).unwrap();
+    writeln!(json, "  \"registers\": {},", json_array(state.registers())).unwrap();
+    writeln!(json, "  \"stack\": {},", json_array(state.stack())).unwrap();
+    writeln!(json, "  \"stack_depth\": {},", state.stack_depth()).unwrap();
+    writeln!(json, "  \"delay_timer\": {},", state.delay_timer()).unwrap();
+    writeln!(
+        json,
+        "  \"waiting_for_key\": {},",
+        state.is_waiting_for_key()
+    )
+    .unwrap();
+    writeln!(
+        json,
+        "  \"memory\": \"{}\",",
+        state
+            .memory()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    )
+    .unwrap();
+    match error {
+        Some(e) => writeln!(json, "  \"error\": {}", json_string(&e.to_string())).unwrap(),
+        None => writeln!(json, "  \"error\": null").unwrap(),
+    }
+    json.push_str("}\n");
+
+    json
+}
+
+pub fn run(options: &RunOptions, rom: Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let input_events = match &options.input_script {
+        Some(path) => load_input_script(path)?,
+        None => Vec::new(),
+    };
+
+    // Saved settings are ignored so that runs are reproducible
+    let mut runner = Runner::new(
+        super::create_state(options, rom)?,
+        options
+            .instructions_per_frame
+            .unwrap_or(INSTRUCTIONS_PER_FRAME),
+    );
+    runner.set_vip_timing(options.vip_timing);
+    super::enable_dynarec(&mut runner, options)?;
+    let palette = &options.palette;
+
+    let keys = Rc::new(RefCell::new([false; 16]));
+    let callback_keys = Rc::clone(&keys);
+    runner
+        .state_mut()
+        .set_key_callback(Box::new(move |key| callback_keys.borrow()[key as usize]));
+
+    let mut recorder = match &options.record_path {
+        Some(path) => Some(GifRecorder::new(Path::new(path), CELL_SIZE, palette)?),
+        None => None,
+    };
+
+    let mut next_event = input_events.iter().peekable();
+    let mut error = None;
+
+    // Runs as fast as possible, frames are not paced
+    while runner.frame_count() < options.frames {
+        let frame = runner.frame_count();
+        while let Some(event) = next_event.peek() {
+            if event.frame > frame {
+                break;
+            }
+
+            keys.borrow_mut()[event.key as usize] = event.down;
+            if event.down {
+                runner.state_mut().on_key_pressed(event.key);
+            }
+
+            next_event.next();
+        }
+
+        if let Err(e) = runner.run_frame() {
+            error = Some(e);
+            break;
+        }
+
+        if let Some(recorder) = &mut recorder {
+            recorder.add_frame(&runner.state().grid, palette)?;
+        }
+    }
+
+    super::finish_outputs(runner.state_mut())?;
+    let state = runner.state();
+
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
+
+    // The screen is printed to stdout unless told otherwise
+    let screen_path = options.screen_dump.as_ref().map_or("-", String::as_str);
+    if screen_path.ends_with(".png") {
+        capture::save_png(&state.grid, CELL_SIZE, palette, Path::new(screen_path))?;
+    } else {
+        write_output(screen_path, &capture::grid_to_ascii(&state.grid))?;
+    }
+
+    if let Some(path) = &options.state_dump {
+        write_output(
+            path,
+            &state_to_json(state, runner.frame_count(), error.as_ref()),
+        )?;
+    }
+
+    match error {
+        Some(e) => Err(Box::new(e)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::keys::Key;
+    use crate::chip8::state::Chip8Options;
+    use crate::options::{self, Command};
+    use std::env;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("chip8-headless-{}-{}", name, std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    fn input_script(name: &str, content: &str) -> Result<Vec<InputEvent>, Box<dyn Error>> {
+        let path = temp_path(name);
+        fs::write(&path, content).unwrap();
+        let events = load_input_script(&path);
+        fs::remove_file(&path).unwrap();
+        events
+    }
+
+    #[test]
+    fn input_scripts_are_sorted_by_frame() {
+        let events =
+            input_script("sorted", "# Comment\n\n10 up a\n  2 down A  \n10 down 0\n").unwrap();
+
+        let events: Vec<_> = events
+            .iter()
+            .map(|event| (event.frame, event.key, event.down))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (2, Key::KeyA, true),
+                (10, Key::KeyA, false),
+                (10, Key::Key0, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_input_lines_are_reported() {
+        for line in [
+            "1 down",
+            "1 down 2 3",
+            "x down 2",
+            "-1 down 2",
+            "1 pressed 2",
+            "1 down 10",
+            "1 down g",
+        ] {
+            let error = input_script("invalid", &format!("0 up 1\n{}\n", line))
+                .err()
+                .unwrap_or_else(|| panic!("{:?} was accepted", line))
+                .to_string();
+
+            assert!(
+                error.ends_with(&format!(":2: invalid input \"{}\"", line)),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn errors_are_escaped_in_state_dumps() {
+        let state = Chip8State::with_options(vec![0x12, 0x00], Chip8Options::default());
+        let error = Chip8Error::DynarecMismatch {
+            address: 0x200,
+            details: String::from("V0 \"01\" \\ 02\n\t"),
+        };
+
+        let json = state_to_json(&state, 1, Some(&error));
+        assert!(json.contains(
+            "  \"error\": \"0x200: the dynarec block differs from the interpreter: \
+             V0 \\\"01\\\" \\\\ 02\\n\\u0009\"\n"
+        ));
+    }
+
+    #[test]
+    fn headless_runs_dump_the_screen_and_state() {
+        let rom_path = temp_path("dump.ch8");
+        let script_path = temp_path("dump.txt");
+        let screen_path = temp_path("dump-screen.txt");
+        let state_path = temp_path("dump-state.json");
+
+        #[rustfmt::skip]
+        fs::write(&rom_path, [
+            0xF0, 0x0A, // LD V0, K
+            0xF0, 0x29, // LD F, V0
+            0xD1, 0x25, // DRW V1, V2, 5
+            0x12, 0x06, // JP 206
+        ])
+        .unwrap();
+        fs::write(&script_path, "1 down 1\n").unwrap();
+
+        let args = [
+            "--headless",
+            &rom_path,
+            "--frames",
+            "3",
+            "--input",
+            &script_path,
+            "--dump-screen",
+            &screen_path,
+            "--dump-state",
+            &state_path,
+        ];
+        let options = match options::parse(args.iter().map(|&arg| arg.to_owned())) {
+            Ok(Command::Run(options)) => options,
+            _ => panic!("invalid options"),
+        };
+        run(&options, fs::read(&rom_path).unwrap()).unwrap();
+
+        let screen = fs::read_to_string(&screen_path).unwrap();
+        let state = fs::read_to_string(&state_path).unwrap();
+        for path in [&rom_path, &script_path, &screen_path, &state_path] {
+            fs::remove_file(path).unwrap();
+        }
+
+        // The font sprite of 1
+        let rows: Vec<_> = screen.lines().map(|row| &row[..8]).collect();
+        assert_eq!(
+            rows[..6],
+            ["..#.....", ".##.....", "..#.....", "..#.....", ".###....", "........"]
+        );
+        assert_eq!(screen.lines().count(), 32);
+
+        assert!(state.starts_with("{\n  \"frames\": 3,\n  \"pc\": 518,\n"));
+        assert!(state
+            .contains("\n  \"registers\": [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],\n"));
+        assert!(state.contains("\n  \"waiting_for_key\": false,\n"));
+        assert!(state.ends_with("\n  \"error\": null\n}\n"));
+    }
+}
@@ -0,0 +1,270 @@
+use crate::chip8::bus::{AccessKind, MemoryAccess};
+use crate::chip8::coverage::Coverage;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use crate::chip8::dynarec::Dynarec;
+use crate::chip8::profile::Profiler;
+use crate::chip8::state::Chip8State;
+use crate::chip8::trace::Tracer;
+use crate::config::UserConfig;
+use crate::options::RunOptions;
+use runner::Runner;
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+
+pub mod headless;
+pub mod runner;
+#[cfg(unix)]
+pub mod tty;
+pub mod window;
+
+pub const CELL_SIZE: usize = 10;
+
+// 720 instructions per second
+pub const INSTRUCTIONS_PER_FRAME: usize = 12;
+
+// The command line takes precedence over the value saved for the ROM
+pub fn instructions_per_frame(options: &RunOptions, config: &UserConfig, rom_hash: &str) -> usize {
+    options
+        .instructions_per_frame
+        .or_else(|| config.instructions_per_frame(rom_hash))
+        .unwrap_or(INSTRUCTIONS_PER_FRAME)
+}
+
+// A malformed file is left untouched, the settings are not saved during the session
+pub fn load_user_config() -> UserConfig {
+    UserConfig::load(UserConfig::default_path()).unwrap_or_else(|e| {
+        println!("Ignoring the saved settings: {}", e);
+        UserConfig::default()
+    })
+}
+
+// Line printed by --watch for an access to the watched range
+fn watched_access(access: &MemoryAccess, (start, end): (usize, usize)) -> Option<String> {
+    if !(start..=end).contains(&access.address) {
+        return None;
+    }
+
+    let kind = match access.kind {
+        AccessKind::Read => "read",
+        AccessKind::Write => "write",
+        AccessKind::Execute => "execute",
+    };
+
+    Some(format!(
+        "{:#05X}: {} {:#05X} = {:#04X}",
+        access.pc, kind, access.address, access.value
+    ))
+}
+
+// Line printed by --warn-code-writes, once per address, some programs modify their code on
+// purpose
+fn code_write_warning(access: &MemoryAccess, reported: &mut HashSet<usize>) -> Option<String> {
+    if !access.modifies_code || !reported.insert(access.address) {
+        return None;
+    }
+
+    Some(format!(
+        "{:#05X}: write to code at {:#05X} = {:#04X}",
+        access.pc, access.address, access.value
+    ))
+}
+
+pub fn create_state(options: &RunOptions, rom: Vec<u8>) -> Result<Chip8State, Box<dyn Error>> {
+    let coverage = match &options.coverage_path {
+        Some(path) => Some(
+            Coverage::create(path, options.coverage_format, &options.rom_path, &rom)
+                .map_err(|e| format!("Failed to create coverage file: {}", e))?,
+        ),
+        None => None,
+    };
+
+    let mut state = Chip8State::with_options(rom, options.machine);
+    if let Some(coverage) = coverage {
+        state.set_coverage(coverage);
+    }
+
+    if options.warn_code_writes {
+        let mut reported = HashSet::new();
+        state.add_memory_observer(Box::new(move |access| {
+            if let Some(line) = code_write_warning(access, &mut reported) {
+                println!("{}", line);
+            }
+        }));
+    }
+
+    if let Some(range) = options.watch {
+        state.add_memory_observer(Box::new(move |access| {
+            if let Some(line) = watched_access(access, range) {
+                println!("{}", line);
+            }
+        }));
+    }
+
+    if let Some(path) = &options.trace_path {
+        let tracer = Tracer::create(path, options.trace_format, options.trace_filter.clone())
+            .map_err(|e| format!("Failed to create trace file: {}", e))?;
+        state.set_tracer(tracer);
+    }
+
+    if let Some(path) = &options.profile_path {
+        let profiler = Profiler::create(path, options.profile_format)
+            .map_err(|e| format!("Failed to create profile file: {}", e))?;
+        state.set_profiler(profiler);
+    }
+
+    Ok(state)
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub fn enable_dynarec(runner: &mut Runner, options: &RunOptions) -> Result<(), Box<dyn Error>> {
+    if options.dynarec {
+        let dynarec = Dynarec::new(runner.state(), options.dynarec_lockstep)
+            .map_err(|e| format!("Failed to start the dynarec: {}", e))?;
+        runner.set_dynarec(dynarec);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+pub fn enable_dynarec(_runner: &mut Runner, options: &RunOptions) -> Result<(), Box<dyn Error>> {
+    if options.dynarec {
+        return Err("The dynarec is only supported on x86-64 Linux".into());
+    }
+
+    Ok(())
+}
+
+// Flushes the trace, reporting any error which happened while writing it, and writes the
+// profile and coverage
+pub fn finish_outputs(state: &mut Chip8State) -> Result<(), Box<dyn Error>> {
+    if let Some(tracer) = state.take_tracer() {
+        tracer
+            .finish()
+            .map_err(|e| format!("Failed to write trace: {}", e))?;
+    }
+
+    if let Some(profiler) = state.take_profiler() {
+        profiler
+            .finish()
+            .map_err(|e| format!("Failed to write profile: {}", e))?;
+    }
+
+    if let Some(coverage) = state.take_coverage() {
+        coverage
+            .finish()
+            .map_err(|e| format!("Failed to write coverage: {}", e))?;
+    }
+
+    Ok(())
+}
+
+pub fn rom_name(options: &RunOptions) -> &str {
+    Path::new(&options.rom_path)
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .unwrap_or("chip8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::state::Chip8Options;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn rom(opcodes: &[u16]) -> Vec<u8> {
+        opcodes
+            .iter()
+            .flat_map(|opcode| opcode.to_be_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn watches_report_the_accesses_to_their_range() {
+        let program = rom(&[
+            0xA300, // LD I, 300
+            0x6005, // LD V0, 05
+            0xF055, // LD [I], V0
+            0xF065, // LD V0, [I]
+            0xA310, // LD I, 310
+            0xF055, // LD [I], V0
+            0x12FF, // JP 2FF
+        ]);
+        let mut state = Chip8State::with_options(program, Chip8Options::default());
+
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let watched = Rc::clone(&lines);
+        state.add_memory_observer(Box::new(move |access| {
+            watched
+                .borrow_mut()
+                .extend(watched_access(access, (0x300, 0x30F)));
+        }));
+
+        for _ in 0..7 {
+            state.tick().unwrap();
+        }
+
+        assert_eq!(
+            *lines.borrow(),
+            ["0x204: write 0x300 = 0x05", "0x206: read 0x300 = 0x05"]
+        );
+    }
+
+    #[test]
+    fn writes_to_executed_code_are_reported_once() {
+        let program = rom(&[
+            0xA20A, // LD I, 20A
+            0x6070, // LD V0, 70
+            0xF055, // LD [I], V0, turning the unexecuted 6001 below into 7001
+            0xA200, // LD I, 200
+            0xF055, // LD [I], V0, over the first instruction
+            0x6001, // ADD V0, 01 once written
+            0xF055, // LD [I], V0, over it again
+            0x120E, // JP 20E
+        ]);
+        let mut state = Chip8State::with_options(program, Chip8Options::default());
+
+        let flagged = Rc::new(RefCell::new(Vec::new()));
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let (observed, warned) = (Rc::clone(&flagged), Rc::clone(&warnings));
+        let mut reported = HashSet::new();
+        state.add_memory_observer(Box::new(move |access| {
+            if access.modifies_code {
+                observed.borrow_mut().push((access.pc, access.address));
+            }
+            warned
+                .borrow_mut()
+                .extend(code_write_warning(access, &mut reported));
+        }));
+
+        for _ in 0..8 {
+            state.tick().unwrap();
+        }
+
+        assert_eq!(state.registers()[0], 0x71);
+        assert_eq!(*flagged.borrow(), [(0x208, 0x200), (0x20C, 0x200)]);
+        assert_eq!(*warnings.borrow(), ["0x208: write to code at 0x200 = 0x70"]);
+    }
+
+    #[test]
+    fn watches_include_both_bounds() {
+        let mut lines = Vec::new();
+        for &address in &[0x2FF, 0x300, 0x30F, 0x310] {
+            let access = MemoryAccess {
+                pc: 0x200,
+                address,
+                kind: AccessKind::Execute,
+                value: 0,
+                modifies_code: false,
+            };
+            lines.extend(watched_access(&access, (0x300, 0x30F)));
+        }
+
+        assert_eq!(
+            lines,
+            ["0x200: execute 0x300 = 0x00", "0x200: execute 0x30F = 0x00"]
+        );
+    }
+}
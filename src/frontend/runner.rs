@@ -0,0 +1,430 @@
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use crate::chip8::dynarec::Dynarec;
+use crate::chip8::error::Chip8Error;
+use crate::chip8::state::Chip8State;
+use crate::chip8::timing::AVAILABLE_CYCLES_PER_FRAME;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const FRAME_DURATION: Duration = Duration::from_micros(1_000_000 / 60);
+
+pub const MAX_INSTRUCTIONS_PER_FRAME: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    // Emulated frames per presented frame
+    Rate(f32),
+    // As many frames as the host can run
+    Unlimited,
+}
+
+// Speeds selectable with the faster/slower hotkeys
+const SPEEDS: [Speed; 6] = [
+    Speed::Rate(0.25),
+    Speed::Rate(0.5),
+    Speed::Rate(1.0),
+    Speed::Rate(2.0),
+    Speed::Rate(4.0),
+    Speed::Unlimited,
+];
+
+impl Speed {
+    pub fn faster(self) -> Speed {
+        let index = SPEEDS.iter().position(|&speed| speed == self).unwrap_or(2);
+        SPEEDS[(index + 1).min(SPEEDS.len() - 1)]
+    }
+
+    pub fn slower(self) -> Speed {
+        let index = SPEEDS.iter().position(|&speed| speed == self).unwrap_or(2);
+        SPEEDS[index.saturating_sub(1)]
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Speed::Rate(rate) => write!(f, "{}x", rate),
+            Speed::Unlimited => write!(f, "unlimited"),
+        }
+    }
+}
+
+// Drives the emulator one 60Hz frame at a time: a fixed number of instructions, then the
+// timers, then the frame can be presented
+pub struct Runner {
+    state: Chip8State,
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    dynarec: Option<Dynarec>,
+    instructions_per_frame: usize,
+    vip_timing: bool,
+    // Cycles left to the interpreter in the current VIP frame, an instruction overrunning
+    // the frame makes it negative
+    cycle_budget: i64,
+    // The first instruction of the frame follows a display interrupt, where pending sprites
+    // are drawn
+    in_vblank: bool,
+    frame_count: u64,
+    dropped_frames: u64,
+    next_frame: Option<Instant>,
+    speed: Speed,
+    paused: bool,
+    frame_advance: bool,
+    // Fraction of a frame carried over when running at a fractional speed
+    frame_budget: f32,
+    instruction_count: u64,
+    instructions_per_second: u64,
+    frames_per_second: u64,
+    // Time, instruction count and frame count at the start of the current measure
+    rate_sample: (Instant, u64, u64),
+}
+
+impl Runner {
+    pub fn new(state: Chip8State, instructions_per_frame: usize) -> Runner {
+        Runner {
+            state,
+            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+            dynarec: None,
+            instructions_per_frame: instructions_per_frame.clamp(1, MAX_INSTRUCTIONS_PER_FRAME),
+            vip_timing: false,
+            cycle_budget: 0,
+            in_vblank: false,
+            frame_count: 0,
+            dropped_frames: 0,
+            next_frame: None,
+            speed: Speed::Rate(1.0),
+            paused: false,
+            frame_advance: false,
+            frame_budget: 0.0,
+            instruction_count: 0,
+            instructions_per_second: 0,
+            frames_per_second: 0,
+            rate_sample: (Instant::now(), 0, 0),
+        }
+    }
+
+    pub fn state(&self) -> &Chip8State {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut Chip8State {
+        &mut self.state
+    }
+
+    pub fn instructions_per_frame(&self) -> usize {
+        self.instructions_per_frame
+    }
+
+    pub fn set_instructions_per_frame(&mut self, count: usize) {
+        self.instructions_per_frame = count.clamp(1, MAX_INSTRUCTIONS_PER_FRAME);
+    }
+
+    // Runs instructions for as many cycles as they took on the COSMAC VIP instead of
+    // a fixed number of instructions per frame
+    pub fn set_vip_timing(&mut self, enabled: bool) {
+        self.vip_timing = enabled;
+        self.cycle_budget = 0;
+    }
+
+    // Runs the fixed number of instructions per frame through the dynamic recompiler
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    pub fn set_dynarec(&mut self, dynarec: Dynarec) {
+        self.dynarec = Some(dynarec);
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Frames skipped because the host could not keep up with 60Hz
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.frame_budget = 0.0;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.frame_advance = false;
+    }
+
+    // Runs a single frame on the next update, while paused
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.frame_advance = true;
+        }
+    }
+
+    // Measured over the last second, real time
+    pub fn instructions_per_second(&self) -> u64 {
+        self.instructions_per_second
+    }
+
+    // Emulated frames, measured over the last second
+    pub fn frames_per_second(&self) -> u64 {
+        self.frames_per_second
+    }
+
+    // Runs the frames due for one presented frame, depending on the speed and pause state.
+    // Returns true if the screen was drawn to.
+    pub fn update(&mut self) -> Result<bool, Chip8Error> {
+        self.update_with(|_| Ok(()))
+    }
+
+    // Same as update, calling on_frame with the state after each emulated frame
+    pub fn update_with<E: From<Chip8Error>>(
+        &mut self,
+        mut on_frame: impl FnMut(&Chip8State) -> Result<(), E>,
+    ) -> Result<bool, E> {
+        let mut drawn = false;
+
+        if self.paused {
+            if self.frame_advance {
+                self.frame_advance = false;
+                drawn = self.run_frame()?;
+                on_frame(&self.state)?;
+            }
+        } else {
+            match self.speed {
+                Speed::Rate(rate) => {
+                    self.frame_budget += rate;
+                    while self.frame_budget >= 1.0 {
+                        self.frame_budget -= 1.0;
+                        drawn |= self.run_frame()?;
+                        on_frame(&self.state)?;
+                    }
+                }
+                Speed::Unlimited => {
+                    // Leaves the host time to present a frame at 60Hz
+                    let start = Instant::now();
+                    while start.elapsed() < FRAME_DURATION {
+                        drawn |= self.run_frame()?;
+                        on_frame(&self.state)?;
+                    }
+                }
+            }
+        }
+
+        let (sample_time, sample_instructions, sample_frames) = self.rate_sample;
+        let elapsed = sample_time.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let rate = |count: u64| (count as f64 / elapsed.as_secs_f64()).round() as u64;
+
+            self.instructions_per_second = rate(self.instruction_count - sample_instructions);
+            self.frames_per_second = rate(self.frame_count - sample_frames);
+            self.rate_sample = (Instant::now(), self.instruction_count, self.frame_count);
+        }
+
+        Ok(drawn)
+    }
+
+    // Runs one frame regardless of the speed and pause state.
+    // Returns true if the screen was drawn to during the frame.
+    pub fn run_frame(&mut self) -> Result<bool, Chip8Error> {
+        let mut drawn = false;
+
+        if self.vip_timing {
+            self.cycle_budget += i64::from(AVAILABLE_CYCLES_PER_FRAME);
+            self.in_vblank = true;
+
+            while self.cycle_budget > 0 {
+                // Nothing runs until a key is pressed
+                if self.state.is_waiting_for_key() {
+                    self.cycle_budget = 0;
+                    break;
+                }
+
+                let timing = self.state.next_instruction_timing()?;
+
+                // Sprites are drawn right after the display interrupt, the rest of the frame
+                // is spent waiting for it
+                if timing.waits_for_vblank && !self.in_vblank {
+                    self.cycle_budget = 0;
+                    break;
+                }
+
+                self.state.tick()?;
+                self.instruction_count += 1;
+                self.cycle_budget -= i64::from(timing.cycles);
+                self.in_vblank = false;
+                drawn |= self.state.has_drawn();
+            }
+        } else {
+            let (frame_drawn, executed) = self.run_instructions()?;
+            drawn = frame_drawn;
+            self.instruction_count += executed as u64;
+        }
+
+        self.state.tick_timers();
+        self.frame_count += 1;
+
+        Ok(drawn)
+    }
+
+    // Returns whether the screen was drawn to and the number of instructions run, fewer than
+    // the frame's once waiting for a key
+    fn interpret_instructions(&mut self) -> Result<(bool, usize), Chip8Error> {
+        let mut drawn = false;
+        let mut executed = 0;
+        while executed < self.instructions_per_frame && !self.state.is_waiting_for_key() {
+            self.state.tick()?;
+            drawn |= self.state.has_drawn();
+            executed += 1;
+        }
+
+        Ok((drawn, executed))
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn run_instructions(&mut self) -> Result<(bool, usize), Chip8Error> {
+        match &mut self.dynarec {
+            Some(dynarec) => {
+                let drawn = dynarec.run(&mut self.state, self.instructions_per_frame)?;
+                Ok((drawn, dynarec.executed()))
+            }
+            None => self.interpret_instructions(),
+        }
+    }
+
+    #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+    fn run_instructions(&mut self) -> Result<(bool, usize), Chip8Error> {
+        self.interpret_instructions()
+    }
+
+    // Sleeps until the next frame is due. When running late by more than a frame, the
+    // missed frames are dropped instead of being run in a burst.
+    pub fn wait_for_next_frame(&mut self) {
+        // Unlimited speed already spent a frame worth of time running instructions
+        if self.speed == Speed::Unlimited && !self.paused {
+            self.next_frame = None;
+            return;
+        }
+
+        let now = Instant::now();
+        let next_frame = self.next_frame.unwrap_or(now) + FRAME_DURATION;
+
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+            self.next_frame = Some(next_frame);
+        } else {
+            let late = (now - next_frame).as_micros() / FRAME_DURATION.as_micros();
+            self.dropped_frames += late as u64;
+            self.next_frame = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::state::Chip8Options;
+
+    fn runner(program: &[u16], instructions_per_frame: usize) -> Runner {
+        let rom = program
+            .iter()
+            .flat_map(|opcode| opcode.to_be_bytes())
+            .collect();
+        Runner::new(
+            Chip8State::with_options(rom, Chip8Options::default()),
+            instructions_per_frame,
+        )
+    }
+
+    #[test]
+    fn speeds_stop_at_the_fastest_and_slowest() {
+        assert_eq!(Speed::Rate(1.0).faster(), Speed::Rate(2.0));
+        assert_eq!(Speed::Rate(4.0).faster(), Speed::Unlimited);
+        assert_eq!(Speed::Unlimited.faster(), Speed::Unlimited);
+
+        assert_eq!(Speed::Rate(1.0).slower(), Speed::Rate(0.5));
+        assert_eq!(Speed::Unlimited.slower(), Speed::Rate(4.0));
+        assert_eq!(Speed::Rate(0.25).slower(), Speed::Rate(0.25));
+
+        // Speeds which cannot be selected move from the normal one
+        assert_eq!(Speed::Rate(3.0).faster(), Speed::Rate(2.0));
+        assert_eq!(Speed::Rate(3.0).slower(), Speed::Rate(0.5));
+    }
+
+    #[test]
+    fn fractional_speeds_carry_frames_over() {
+        let mut runner = runner(&[0x1200], 10);
+
+        runner.set_speed(Speed::Rate(0.25));
+        for expected in [0, 0, 0, 1, 1, 1, 1, 2] {
+            runner.update().unwrap();
+            assert_eq!(runner.frame_count(), expected);
+        }
+
+        runner.set_speed(Speed::Rate(0.5));
+        runner.update().unwrap();
+        assert_eq!(runner.frame_count(), 2);
+        runner.update().unwrap();
+        assert_eq!(runner.frame_count(), 3);
+
+        runner.set_speed(Speed::Rate(2.0));
+        runner.update().unwrap();
+        assert_eq!(runner.frame_count(), 5);
+        assert_eq!(runner.instruction_count, 50);
+    }
+
+    #[test]
+    fn paused_runner_only_runs_advanced_frames() {
+        let mut runner = runner(&[0x1200], 10);
+        runner.set_paused(true);
+
+        runner.update().unwrap();
+        assert_eq!(runner.frame_count(), 0);
+
+        runner.advance_frame();
+        runner.update().unwrap();
+        assert_eq!(runner.frame_count(), 1);
+        runner.update().unwrap();
+        assert_eq!(runner.frame_count(), 1);
+
+        // Advancing only applies while paused
+        runner.set_paused(false);
+        runner.advance_frame();
+        runner.set_paused(true);
+        runner.update().unwrap();
+        assert_eq!(runner.frame_count(), 1);
+    }
+
+    #[test]
+    fn instructions_are_not_counted_while_waiting_for_a_key() {
+        // LD V0, K
+        let mut runner = runner(&[0xF00A], 10);
+        runner.run_frame().unwrap();
+        runner.run_frame().unwrap();
+        assert_eq!(runner.instruction_count, 1);
+
+        let mut runner = self::runner(&[0xF00A], 10);
+        runner.set_vip_timing(true);
+        runner.run_frame().unwrap();
+        runner.run_frame().unwrap();
+        assert_eq!(runner.instruction_count, 1);
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn recompiled_instructions_are_not_counted_while_waiting_for_a_key() {
+        // LD V0, 01, LD V0, K
+        let mut runner = runner(&[0x6001, 0xF00A], 10);
+        let dynarec = Dynarec::new(runner.state(), false).unwrap();
+        runner.set_dynarec(dynarec);
+
+        runner.run_frame().unwrap();
+        runner.run_frame().unwrap();
+        assert_eq!(runner.instruction_count, 2);
+    }
+}
@@ -0,0 +1,281 @@
+use super::runner::{Runner, Speed};
+use crate::chip8::keys::Key;
+use crate::chip8::state::{GRID_HEIGHT, GRID_WIDTH};
+use crate::config;
+use crate::options::{RunOptions, TtyMode};
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Terminals only report key presses (and autorepeats), consider a key held for
+// this long after it was last seen
+const KEY_HOLD_TIME: Duration = Duration::from_millis(200);
+
+// Puts the terminal in raw mode and switches to the alternate screen until dropped
+struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    fn new() -> io::Result<RawTerminal> {
+        let mut original: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut raw = original;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Alternate screen, hidden cursor
+        let mut stdout = io::stdout();
+        stdout.write_all(b"\x1b[?1049h\x1b[?25l\x1b[2J")?;
+        stdout.flush()?;
+
+        Ok(RawTerminal { original })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(b"\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+// Returns the bytes available on stdin without blocking
+fn read_stdin() -> io::Result<Vec<u8>> {
+    let mut input = Vec::new();
+    let mut buffer = [0u8; 64];
+
+    loop {
+        let mut poll_fd = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        if unsafe { libc::poll(&mut poll_fd, 1, 0) } <= 0 || poll_fd.revents & libc::POLLIN == 0 {
+            break;
+        }
+
+        let read = io::stdin().read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        input.extend_from_slice(&buffer[..read]);
+    }
+
+    Ok(input)
+}
+
+fn key_from_char(c: u8) -> Option<Key> {
+    let digit = match c {
+        b'0'..=b'9' => c - b'0',
+        b'a'..=b'f' => c - b'a' + 10,
+        b'A'..=b'F' => c - b'A' + 10,
+        _ => return None,
+    };
+
+    num::FromPrimitive::from_u8(digit)
+}
+
+fn set_colors(output: &mut String, foreground: u32, background: u32) {
+    write!(
+        output,
+        "\x1b[38;2;{};{};{};48;2;{};{};{}m",
+        (foreground >> 16) & 0xFF,
+        (foreground >> 8) & 0xFF,
+        foreground & 0xFF,
+        (background >> 16) & 0xFF,
+        (background >> 8) & 0xFF,
+        background & 0xFF
+    )
+    .unwrap();
+}
+
+// Two vertical pixels per character
+fn render_half_blocks(grid: &[bool], output: &mut String) {
+    for y in (0..GRID_HEIGHT).step_by(2) {
+        for x in 0..GRID_WIDTH {
+            let top = grid[y * GRID_WIDTH + x];
+            let bottom = grid[(y + 1) * GRID_WIDTH + x];
+
+            output.push(match (top, bottom) {
+                (false, false) => ' ',
+                (true, false) => '\u{2580}',
+                (false, true) => '\u{2584}',
+                (true, true) => '\u{2588}',
+            });
+        }
+        output.push_str("\r\n");
+    }
+}
+
+// 2x4 pixels per character
+fn render_braille(grid: &[bool], output: &mut String) {
+    // Braille dot numbering, indexed by [y][x] inside the character cell
+    const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+    for y in (0..GRID_HEIGHT).step_by(4) {
+        for x in (0..GRID_WIDTH).step_by(2) {
+            let mut pattern = 0;
+            for (dy, row) in DOTS.iter().enumerate() {
+                for (dx, dot) in row.iter().enumerate() {
+                    if grid[(y + dy) * GRID_WIDTH + x + dx] {
+                        pattern |= dot;
+                    }
+                }
+            }
+
+            output.push(std::char::from_u32(0x2800 + pattern).unwrap());
+        }
+        output.push_str("\r\n");
+    }
+}
+
+pub fn run(options: &RunOptions, rom: Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let mut config = super::load_user_config();
+    let rom_hash = config::rom_hash(&rom);
+
+    let mut runner = Runner::new(
+        super::create_state(options, rom)?,
+        super::instructions_per_frame(options, &config, &rom_hash),
+    );
+    runner.set_vip_timing(options.vip_timing);
+    super::enable_dynarec(&mut runner, options)?;
+
+    let key_times: Rc<RefCell<[Option<Instant>; 16]>> = Rc::new(RefCell::new([None; 16]));
+    let callback_key_times = Rc::clone(&key_times);
+    runner.state_mut().set_key_callback(Box::new(move |key| {
+        callback_key_times.borrow()[key as usize].is_some_and(|time| time.elapsed() < KEY_HOLD_TIME)
+    }));
+
+    let _terminal = RawTerminal::new()?;
+    let mut palette = options.palette.clone();
+
+    let mut last_grid = Vec::new();
+    let mut last_status = String::new();
+    let mut save_error = None;
+
+    'running: loop {
+        let input = read_stdin()?;
+        let mut bytes = input.iter().cloned().peekable();
+
+        while let Some(c) = bytes.next() {
+            match c {
+                // A lone escape exits, escape sequences (arrows, function keys) are skipped
+                0x1b => match bytes.peek() {
+                    Some(b'[') | Some(b'O') => {
+                        bytes.next();
+                        for c in bytes.by_ref() {
+                            if (0x40..=0x7E).contains(&c) {
+                                break;
+                            }
+                        }
+                    }
+                    _ => break 'running,
+                },
+                // Ctrl-C
+                0x03 => break 'running,
+                b' ' => runner.set_paused(!runner.is_paused()),
+                b'n' | b'N' => runner.advance_frame(),
+                b'[' => runner.set_speed(runner.speed().slower()),
+                b']' => runner.set_speed(runner.speed().faster()),
+                b'\t' => runner.set_speed(if runner.speed() == Speed::Unlimited {
+                    Speed::Rate(1.0)
+                } else {
+                    Speed::Unlimited
+                }),
+                b'+' | b'=' | b'-' => {
+                    let count = runner.instructions_per_frame();
+                    runner.set_instructions_per_frame(if c == b'-' {
+                        count - 1
+                    } else {
+                        count + 1
+                    });
+
+                    config.set_instructions_per_frame(
+                        &rom_hash,
+                        super::rom_name(options),
+                        runner.instructions_per_frame(),
+                    );
+                    save_error = config
+                        .save()
+                        .err()
+                        .map(|e| format!(", failed to save settings: {}", e));
+                }
+                b'p' | b'P' => {
+                    palette = palette.next();
+                    // Force a redraw with the new colors
+                    last_grid.clear();
+                }
+                _ => {
+                    if let Some(key) = key_from_char(c) {
+                        key_times.borrow_mut()[key as usize] = Some(Instant::now());
+                        runner.state_mut().on_key_pressed(key);
+                    }
+                }
+            }
+        }
+
+        runner.update()?;
+        let state = runner.state();
+
+        let mut status = if runner.is_paused() {
+            String::from("paused")
+        } else {
+            format!(
+                "{}, {} IPS",
+                runner.speed(),
+                runner.instructions_per_second()
+            )
+        };
+        write!(status, ", {}/frame", runner.instructions_per_frame())?;
+        if let Some(error) = &save_error {
+            status.push_str(error);
+        }
+
+        if state.grid != last_grid || status != last_status {
+            let mut output = String::from("\x1b[H");
+            set_colors(&mut output, palette.foreground(), palette.background());
+
+            match options.tty_mode {
+                TtyMode::HalfBlocks => render_half_blocks(&state.grid, &mut output),
+                TtyMode::Braille => render_braille(&state.grid, &mut output),
+            }
+
+            write!(
+                output,
+                "\x1b[0m\x1b[KESC to exit, P to change palette ({}), space to pause, [ ] to change speed ({})",
+                palette.name(),
+                status
+            )?;
+
+            let mut stdout = io::stdout();
+            stdout.write_all(output.as_bytes())?;
+            stdout.flush()?;
+
+            last_grid = state.grid.clone();
+            last_status = status;
+        }
+
+        runner.wait_for_next_frame();
+    }
+
+    super::finish_outputs(runner.state_mut())?;
+
+    Ok(())
+}
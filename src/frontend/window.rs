@@ -0,0 +1,470 @@
+use super::runner::{Runner, Speed};
+use super::CELL_SIZE;
+use crate::capture::{self, GifRecorder};
+use crate::chip8::keys::Key as Chip8Key;
+use crate::config;
+use crate::crt::CrtFilter;
+#[cfg(target_os = "linux")]
+use crate::gamepad;
+use crate::options::RunOptions;
+use crate::overlay::Overlay;
+use crate::scaler::Scaler;
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+use std::cell::RefCell;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const TITLE: &str = "Chip-8 Emulator - ESC to exit";
+
+const MAX_SCALE: usize = 32;
+
+// minifb cannot switch a window to fullscreen, so fullscreen is emulated with a borderless
+// window which minifb scales to fit the screen
+fn create_window(
+    scale: usize,
+    fullscreen: bool,
+    display_size: (usize, usize),
+) -> minifb::Result<Window> {
+    let (width, height) = display_size;
+
+    if fullscreen {
+        let mut window = Window::new(
+            TITLE,
+            width,
+            height,
+            WindowOptions {
+                borderless: true,
+                title: false,
+                resize: false,
+                scale: Scale::FitScreen,
+            },
+        )?;
+        window.set_position(0, 0);
+
+        Ok(window)
+    } else {
+        Window::new(
+            TITLE,
+            width * scale,
+            height * scale,
+            WindowOptions {
+                resize: true,
+                ..WindowOptions::default()
+            },
+        )
+    }
+}
+
+pub fn run(options: &RunOptions, rom: Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let mut config = super::load_user_config();
+    let rom_hash = config::rom_hash(&rom);
+
+    let mut runner = Runner::new(
+        super::create_state(options, rom)?,
+        super::instructions_per_frame(options, &config, &rom_hash),
+    );
+    runner.set_vip_timing(options.vip_timing);
+    super::enable_dynarec(&mut runner, options)?;
+    let mut palette = options.palette.clone();
+
+    let mut scale = options.scale;
+    let mut fullscreen = false;
+    let mut scaler = Scaler::default();
+    let mut rendered_grid = Vec::new();
+    let mut title = String::from(TITLE);
+    let mut overlay = Overlay::default();
+    let mut overlay_drawn = false;
+
+    let window = Rc::new(RefCell::from(create_window(
+        scale,
+        fullscreen,
+        runner.state().display_size(),
+    )?));
+
+    #[cfg(target_os = "linux")]
+    let gamepad = match &options.gamepad_device {
+        Some(device) => {
+            let gamepad =
+                open_gamepad(device, options.gamepad_profiles.as_ref(), &options.rom_path)
+                    .map_err(|e| format!("Failed to open gamepad: {}", e))?;
+
+            println!("Using gamepad: {}", gamepad.name());
+            Some(Rc::new(RefCell::new(gamepad)))
+        }
+        None => None,
+    };
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        if options.gamepad_device.is_some() || options.gamepad_profiles.is_some() {
+            return Err("Gamepad input is only supported on Linux".into());
+        }
+    }
+
+    let mut recorder = match &options.record_path {
+        Some(path) => Some(GifRecorder::new(Path::new(path), CELL_SIZE, &palette)?),
+        None => None,
+    };
+
+    let mut crt = if options.crt.is_enabled() {
+        Some(CrtFilter::new(options.crt, runner.state().grid.len()))
+    } else {
+        None
+    };
+
+    let new_window_ref = Rc::clone(&window);
+    #[cfg(target_os = "linux")]
+    let new_gamepad_ref = gamepad.as_ref().map(Rc::clone);
+
+    runner
+        .state_mut()
+        .set_key_callback(Box::new(move |key| -> bool {
+            #[cfg(target_os = "linux")]
+            {
+                if let Some(gamepad) = &new_gamepad_ref {
+                    if gamepad.borrow().is_key_down(key) {
+                        return true;
+                    }
+                }
+            }
+
+            match key {
+                Chip8Key::Key0 => new_window_ref.borrow().is_key_down(Key::NumPad0),
+                Chip8Key::Key1 => new_window_ref.borrow().is_key_down(Key::NumPad1),
+                Chip8Key::Key2 => new_window_ref.borrow().is_key_down(Key::NumPad2),
+                Chip8Key::Key3 => new_window_ref.borrow().is_key_down(Key::NumPad3),
+                Chip8Key::Key4 => new_window_ref.borrow().is_key_down(Key::NumPad4),
+                Chip8Key::Key5 => new_window_ref.borrow().is_key_down(Key::NumPad5),
+                Chip8Key::Key6 => new_window_ref.borrow().is_key_down(Key::NumPad6),
+                Chip8Key::Key7 => new_window_ref.borrow().is_key_down(Key::NumPad7),
+                Chip8Key::Key8 => new_window_ref.borrow().is_key_down(Key::NumPad8),
+                Chip8Key::Key9 => new_window_ref.borrow().is_key_down(Key::NumPad9),
+                Chip8Key::KeyA => new_window_ref.borrow().is_key_down(Key::A),
+                Chip8Key::KeyB => new_window_ref.borrow().is_key_down(Key::B),
+                Chip8Key::KeyC => new_window_ref.borrow().is_key_down(Key::C),
+                Chip8Key::KeyD => new_window_ref.borrow().is_key_down(Key::D),
+                Chip8Key::KeyE => new_window_ref.borrow().is_key_down(Key::E),
+                Chip8Key::KeyF => new_window_ref.borrow().is_key_down(Key::F),
+            }
+        }));
+
+    while window.borrow().is_open() && !window.borrow().is_key_down(Key::Escape) {
+        let mut take_screenshot = false;
+        let mut toggle_recording = false;
+        let mut palette_changed = false;
+        let mut new_scale = scale;
+        let mut toggle_fullscreen = false;
+        let mut toggle_overlay = false;
+        let mut toggle_pause = false;
+        let mut advance_frame = false;
+        let mut speed = runner.speed();
+        let mut instructions_per_frame = runner.instructions_per_frame();
+
+        let state = runner.state_mut();
+        if let Some(keys) = window.borrow().get_keys_pressed(KeyRepeat::No) {
+            for k in keys {
+                match k {
+                    Key::NumPad0 => state.on_key_pressed(Chip8Key::Key0),
+                    Key::NumPad1 => state.on_key_pressed(Chip8Key::Key1),
+                    Key::NumPad2 => state.on_key_pressed(Chip8Key::Key2),
+                    Key::NumPad3 => state.on_key_pressed(Chip8Key::Key3),
+                    Key::NumPad4 => state.on_key_pressed(Chip8Key::Key4),
+                    Key::NumPad5 => state.on_key_pressed(Chip8Key::Key5),
+                    Key::NumPad6 => state.on_key_pressed(Chip8Key::Key6),
+                    Key::NumPad7 => state.on_key_pressed(Chip8Key::Key7),
+                    Key::NumPad8 => state.on_key_pressed(Chip8Key::Key8),
+                    Key::NumPad9 => state.on_key_pressed(Chip8Key::Key9),
+                    Key::A => state.on_key_pressed(Chip8Key::KeyA),
+                    Key::B => state.on_key_pressed(Chip8Key::KeyB),
+                    Key::C => state.on_key_pressed(Chip8Key::KeyC),
+                    Key::D => state.on_key_pressed(Chip8Key::KeyD),
+                    Key::E => state.on_key_pressed(Chip8Key::KeyE),
+                    Key::F => state.on_key_pressed(Chip8Key::KeyF),
+                    Key::P => palette_changed = true,
+                    Key::Equal | Key::NumPadPlus => new_scale = (scale + 1).min(MAX_SCALE),
+                    Key::Minus | Key::NumPadMinus => new_scale = (scale - 1).max(1),
+                    Key::F1 => toggle_overlay = true,
+                    Key::F10 => toggle_fullscreen = true,
+                    Key::Space => toggle_pause = true,
+                    Key::N => advance_frame = true,
+                    Key::LeftBracket => speed = speed.slower(),
+                    Key::RightBracket => speed = speed.faster(),
+                    Key::Backspace => speed = Speed::Rate(1.0),
+                    Key::PageUp => instructions_per_frame += 1,
+                    Key::PageDown => instructions_per_frame -= 1,
+                    Key::Tab => {
+                        speed = if speed == Speed::Unlimited {
+                            Speed::Rate(1.0)
+                        } else {
+                            Speed::Unlimited
+                        }
+                    }
+                    Key::F11 => toggle_recording = true,
+                    Key::F12 => take_screenshot = true,
+                    _ => (),
+                }
+            }
+        }
+
+        if toggle_overlay {
+            overlay.toggle();
+        }
+
+        if palette_changed {
+            palette = palette.next();
+            notify(&mut overlay, format!("Palette: {}", palette.name()));
+        }
+
+        // Windows are recreated at their new size, the fullscreen one ignores the scale
+        if toggle_fullscreen || (new_scale != scale && !fullscreen) {
+            fullscreen ^= toggle_fullscreen;
+            scale = new_scale;
+            *window.borrow_mut() = create_window(scale, fullscreen, state.display_size())?;
+            title = String::from(TITLE);
+        }
+
+        if take_screenshot {
+            let path = capture_path(options, "png");
+            match capture::save_png(&state.grid, CELL_SIZE, &palette, &path) {
+                Ok(()) => notify(
+                    &mut overlay,
+                    format!("Saved screenshot to {}", path.display()),
+                ),
+                Err(e) => notify(&mut overlay, format!("Failed to save screenshot: {}", e)),
+            }
+        }
+
+        if toggle_recording {
+            match recorder.take() {
+                Some(recorder) => {
+                    recorder.finish()?;
+                    notify(&mut overlay, String::from("Stopped recording"));
+                }
+                None => {
+                    let path = capture_path(options, "gif");
+                    match GifRecorder::new(&path, CELL_SIZE, &palette) {
+                        Ok(new_recorder) => {
+                            notify(&mut overlay, format!("Recording to {}", path.display()));
+                            recorder = Some(new_recorder);
+                        }
+                        Err(e) => notify(&mut overlay, format!("Failed to start recording: {}", e)),
+                    }
+                }
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(gamepad) = &gamepad {
+                match gamepad.borrow_mut().poll() {
+                    Ok(keys) => {
+                        for key in keys {
+                            state.on_key_pressed(key);
+                        }
+                    }
+                    Err(e) => println!("Gamepad error: {}", e),
+                }
+            }
+        }
+
+        if toggle_pause {
+            runner.set_paused(!runner.is_paused());
+        }
+
+        if advance_frame {
+            runner.advance_frame();
+        }
+
+        if speed != runner.speed() {
+            runner.set_speed(speed);
+            notify(&mut overlay, format!("Speed: {}", speed));
+        }
+
+        if instructions_per_frame != runner.instructions_per_frame() {
+            runner.set_instructions_per_frame(instructions_per_frame);
+
+            let count = runner.instructions_per_frame();
+            notify(
+                &mut overlay,
+                format!("{} instructions per frame ({}/s)", count, count * 60),
+            );
+
+            config.set_instructions_per_frame(&rom_hash, super::rom_name(options), count);
+            if let Err(e) = config.save() {
+                notify(&mut overlay, format!("Failed to save settings: {}", e));
+            }
+        }
+
+        // One frame is presented per 60Hz tick, running as many frames as the speed requires,
+        // each of them being recorded
+        let drawn = runner.update_with(|state| match &mut recorder {
+            Some(recorder) => recorder.add_frame(&state.grid, &palette),
+            None => Ok(()),
+        })?;
+
+        let new_title = if runner.is_paused() {
+            String::from("Chip-8 Emulator - Paused - ESC to exit")
+        } else {
+            format!(
+                "Chip-8 Emulator - {} - {} IPS - ESC to exit",
+                runner.speed(),
+                runner.instructions_per_second()
+            )
+        };
+        if new_title != title {
+            window.borrow_mut().set_title(&new_title);
+            title = new_title;
+        }
+
+        let state = runner.state();
+
+        if let Some(crt) = &mut crt {
+            crt.resize(state.grid.len());
+            crt.update(&state.grid);
+        }
+
+        // The fullscreen window is scaled by minifb, its buffer is the size of the display
+        let (display_width, display_height) = state.display_size();
+        let (output_width, output_height) = if fullscreen {
+            (display_width, display_height)
+        } else {
+            window.borrow().get_size()
+        };
+
+        let layout_changed =
+            scaler.set_layout(output_width, output_height, display_width, display_height);
+        // The overlay is drawn over the frame, which must be expanded again to erase it
+        let overlay_active = overlay.update();
+        let force_render = palette_changed || layout_changed || overlay_active || overlay_drawn;
+
+        // The grid is only expanded again if the frame changed
+        let redraw = match &crt {
+            Some(crt) => force_render || drawn || crt.is_animating(),
+            None => force_render || (drawn && state.grid != rendered_grid),
+        };
+
+        // Nothing can be presented while the window is minimized
+        if !scaler.buffer().is_empty() {
+            if redraw {
+                match &crt {
+                    Some(crt) => scaler.render(
+                        |cell| crt.cell_color(cell, &palette),
+                        |color, y| crt.apply_scanline(color, y),
+                    ),
+                    None => scaler.render(
+                        |cell| palette.color(state.grid[cell] as u8),
+                        |color, _| color,
+                    ),
+                }
+
+                rendered_grid.clone_from(&state.grid);
+
+                if overlay_active {
+                    let (width, height) = scaler.output_size();
+                    overlay.draw(
+                        scaler.buffer_mut(),
+                        width,
+                        height,
+                        &overlay_status(&runner, options),
+                    );
+                }
+                overlay_drawn = overlay_active;
+            }
+
+            window.borrow_mut().update_with_buffer(scaler.buffer())?;
+        } else {
+            window.borrow_mut().update();
+        }
+
+        runner.wait_for_next_frame();
+    }
+
+    if runner.dropped_frames() > 0 {
+        println!(
+            "Dropped {} of {} frames",
+            runner.dropped_frames(),
+            runner.frame_count() + runner.dropped_frames()
+        );
+    }
+
+    super::finish_outputs(runner.state_mut())?;
+    let state = runner.state();
+
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
+
+    if let Some(path) = &options.screen_dump {
+        capture::save_png(&state.grid, CELL_SIZE, &palette, Path::new(path))?;
+    }
+
+    Ok(())
+}
+
+// Status messages are both printed and shown on the overlay
+fn notify(overlay: &mut Overlay, message: String) {
+    println!("{}", message);
+    overlay.show_message(message);
+}
+
+fn overlay_status(runner: &Runner, options: &RunOptions) -> Vec<String> {
+    let mut status = vec![
+        String::from(super::rom_name(options)),
+        format!(
+            "FPS {} IPS {}",
+            runner.frames_per_second(),
+            runner.instructions_per_second()
+        ),
+    ];
+
+    if runner.is_paused() {
+        status.push(String::from("PAUSED"));
+    } else if runner.speed() != Speed::Rate(1.0) {
+        status.push(format!("SPEED {}", runner.speed()));
+    }
+
+    status
+}
+
+// Screenshots and recordings are named after the ROM and the time they were taken
+fn capture_path(options: &RunOptions, extension: &str) -> PathBuf {
+    let rom_name = super::rom_name(options);
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+
+    Path::new(&options.capture_dir).join(format!("{}-{}.{}", rom_name, timestamp, extension))
+}
+
+#[cfg(target_os = "linux")]
+fn open_gamepad(
+    device: &str,
+    profiles_path: Option<&String>,
+    rom_path: &str,
+) -> std::io::Result<gamepad::Gamepad> {
+    // Profiles are looked up by ROM file name (PONG, TANK, ...)
+    let rom_name = Path::new(rom_path)
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .unwrap_or("")
+        .to_uppercase();
+
+    let user_profile = match profiles_path {
+        Some(path) => gamepad::Profile::load_from_file(Path::new(path), &rom_name)?,
+        None => None,
+    };
+
+    let profile = user_profile
+        .or_else(|| gamepad::Profile::builtin(&rom_name))
+        .unwrap_or_else(gamepad::Profile::default_profile);
+
+    if device.is_empty() {
+        gamepad::Gamepad::open_first(profile)
+    } else {
+        gamepad::Gamepad::open(Path::new(device), profile)
+    }
+}
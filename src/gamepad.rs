@@ -0,0 +1,749 @@
+use crate::chip8::keys::Key;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+// Linux input event codes (see linux/input-event-codes.h)
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const ABS_HAT0X: u16 = 0x10;
+const ABS_HAT0Y: u16 = 0x11;
+
+const BTN_JOYSTICK: u16 = 0x120;
+const BTN_TRIGGER: u16 = 0x120;
+const BTN_THUMB: u16 = 0x121;
+const BTN_THUMB2: u16 = 0x122;
+const BTN_TOP: u16 = 0x123;
+const BTN_SOUTH: u16 = 0x130;
+const BTN_EAST: u16 = 0x131;
+const BTN_NORTH: u16 = 0x133;
+const BTN_WEST: u16 = 0x134;
+const BTN_TL: u16 = 0x136;
+const BTN_TR: u16 = 0x137;
+const BTN_SELECT: u16 = 0x13a;
+const BTN_START: u16 = 0x13b;
+const BTN_DIGI: u16 = 0x140;
+const BTN_DPAD_UP: u16 = 0x220;
+const BTN_DPAD_DOWN: u16 = 0x221;
+const BTN_DPAD_LEFT: u16 = 0x222;
+const BTN_DPAD_RIGHT: u16 = 0x223;
+
+const KEY_MAX: usize = 0x2ff;
+
+// ioctl request numbers, built the same way as the _IOC macro does
+const IOC_READ: libc::c_ulong = 2;
+
+fn ioc(direction: libc::c_ulong, kind: u8, nr: libc::c_ulong, size: usize) -> libc::c_ulong {
+    (direction << 30) | ((size as libc::c_ulong) << 16) | ((kind as libc::c_ulong) << 8) | nr
+}
+
+fn ioc_read(nr: libc::c_ulong, size: usize) -> libc::c_ulong {
+    ioc(IOC_READ, b'E', nr, size)
+}
+
+fn eviocgname(len: usize) -> libc::c_ulong {
+    ioc_read(0x06, len)
+}
+
+fn eviocgbit(ev: u16, len: usize) -> libc::c_ulong {
+    ioc_read(0x20 + ev as libc::c_ulong, len)
+}
+
+fn eviocgabs(abs: u16) -> libc::c_ulong {
+    ioc_read(
+        0x40 + abs as libc::c_ulong,
+        mem::size_of::<libc::input_absinfo>(),
+    )
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Control {
+    Up,
+    Down,
+    Left,
+    Right,
+    South,
+    East,
+    West,
+    North,
+    ShoulderL,
+    ShoulderR,
+    Select,
+    Start,
+}
+
+impl Control {
+    fn from_name(name: &str) -> Option<Control> {
+        match name.to_lowercase().as_str() {
+            "up" => Some(Control::Up),
+            "down" => Some(Control::Down),
+            "left" => Some(Control::Left),
+            "right" => Some(Control::Right),
+            "south" | "a" => Some(Control::South),
+            "east" | "b" => Some(Control::East),
+            "west" | "x" => Some(Control::West),
+            "north" | "y" => Some(Control::North),
+            "l" | "tl" => Some(Control::ShoulderL),
+            "r" | "tr" => Some(Control::ShoulderR),
+            "select" => Some(Control::Select),
+            "start" => Some(Control::Start),
+            _ => None,
+        }
+    }
+
+    fn from_button(code: u16) -> Option<Control> {
+        match code {
+            BTN_DPAD_UP => Some(Control::Up),
+            BTN_DPAD_DOWN => Some(Control::Down),
+            BTN_DPAD_LEFT => Some(Control::Left),
+            BTN_DPAD_RIGHT => Some(Control::Right),
+            BTN_SOUTH | BTN_TRIGGER => Some(Control::South),
+            BTN_EAST | BTN_THUMB => Some(Control::East),
+            BTN_WEST | BTN_THUMB2 => Some(Control::West),
+            BTN_NORTH | BTN_TOP => Some(Control::North),
+            BTN_TL => Some(Control::ShoulderL),
+            BTN_TR => Some(Control::ShoulderR),
+            BTN_SELECT => Some(Control::Select),
+            BTN_START => Some(Control::Start),
+            _ => None,
+        }
+    }
+}
+
+// Maps gamepad controls to Chip-8 keys
+#[derive(Clone, Debug)]
+pub struct Profile {
+    bindings: HashMap<Control, u8>,
+}
+
+impl Profile {
+    fn from_bindings(bindings: &[(Control, u8)]) -> Profile {
+        Profile {
+            bindings: bindings.iter().cloned().collect(),
+        }
+    }
+
+    // Most games use 2/4/6/8 as directions and 5 as the action key
+    pub fn default_profile() -> Profile {
+        Profile::from_bindings(&[
+            (Control::Up, 0x2),
+            (Control::Down, 0x8),
+            (Control::Left, 0x4),
+            (Control::Right, 0x6),
+            (Control::South, 0x5),
+            (Control::East, 0x0),
+            (Control::West, 0x1),
+            (Control::North, 0x3),
+            (Control::ShoulderL, 0x7),
+            (Control::ShoulderR, 0x9),
+            (Control::Select, 0xE),
+            (Control::Start, 0xF),
+        ])
+    }
+
+    // Built-in profiles for the bundled ROMs, keyed by file name
+    pub fn builtin(rom_name: &str) -> Option<Profile> {
+        let bindings: &[(Control, u8)] = match rom_name {
+            "PONG" | "PONG2" => &[
+                (Control::Up, 0x1),
+                (Control::Down, 0x4),
+                (Control::North, 0xC),
+                (Control::South, 0xD),
+            ],
+            "TANK" => &[
+                (Control::Up, 0x8),
+                (Control::Down, 0x2),
+                (Control::Left, 0x4),
+                (Control::Right, 0x6),
+                (Control::South, 0x5),
+            ],
+            "UFO" => &[
+                (Control::Left, 0x4),
+                (Control::Up, 0x5),
+                (Control::Right, 0x6),
+                (Control::West, 0x4),
+                (Control::South, 0x5),
+                (Control::East, 0x6),
+            ],
+            "BLITZ" => &[(Control::South, 0x5)],
+            "BRIX" => &[(Control::Left, 0x4), (Control::Right, 0x6)],
+            // The paddle moves vertically, 7 starts the game
+            "VBRIX" => &[
+                (Control::Up, 0x1),
+                (Control::Down, 0x4),
+                (Control::South, 0x7),
+            ],
+            "INVADERS" => &[
+                (Control::Left, 0x4),
+                (Control::Right, 0x6),
+                (Control::South, 0x5),
+            ],
+            "TETRIS" => &[
+                (Control::Left, 0x5),
+                (Control::Right, 0x6),
+                (Control::Down, 0x7),
+                (Control::South, 0x4),
+            ],
+            "MISSILE" => &[(Control::South, 0x8)],
+            "WIPEOFF" => &[(Control::Left, 0x4), (Control::Right, 0x6)],
+            _ => return None,
+        };
+
+        Some(Profile::from_bindings(bindings))
+    }
+
+    // Profile files are made of "[ROMNAME]" sections followed by "control = key" lines,
+    // where key is a hexadecimal digit, e.g. "up = 1"
+    pub fn load_from_file(path: &Path, rom_name: &str) -> io::Result<Option<Profile>> {
+        let content = fs::read_to_string(path)?;
+
+        let mut bindings = Vec::new();
+        let mut in_section = false;
+        let mut found = false;
+
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                in_section = line[1..line.len() - 1]
+                    .trim()
+                    .eq_ignore_ascii_case(rom_name);
+                found |= in_section;
+                continue;
+            }
+
+            if !in_section {
+                continue;
+            }
+
+            let invalid_line = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{}:{}: invalid binding \"{}\"",
+                        path.display(),
+                        line_number + 1,
+                        line
+                    ),
+                )
+            };
+
+            let mut parts = line.splitn(2, '=');
+            let control = parts
+                .next()
+                .and_then(|c| Control::from_name(c.trim()))
+                .ok_or_else(invalid_line)?;
+
+            let key = parts
+                .next()
+                .and_then(|k| u8::from_str_radix(k.trim(), 16).ok())
+                .filter(|&k| k < 16)
+                .ok_or_else(invalid_line)?;
+
+            bindings.push((control, key));
+        }
+
+        if found {
+            Ok(Some(Profile::from_bindings(&bindings)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn key_for(&self, control: Control) -> Option<u8> {
+        self.bindings.get(&control).cloned()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct AxisRange {
+    low: i32,
+    high: i32,
+}
+
+pub struct Gamepad {
+    file: File,
+    name: String,
+    profile: Profile,
+    controls: HashMap<Control, bool>,
+    axes: HashMap<u16, AxisRange>,
+    pressed_keys: Vec<Key>,
+}
+
+impl Gamepad {
+    pub fn open(path: &Path, profile: Profile) -> io::Result<Gamepad> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+
+        if !is_joystick(&file) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a joystick device", path.display()),
+            ));
+        }
+
+        let mut axes = HashMap::new();
+        for &axis in &[ABS_X, ABS_Y] {
+            if let Some(range) = query_axis_range(&file, axis) {
+                axes.insert(axis, range);
+            }
+        }
+
+        Ok(Gamepad {
+            name: device_name(&file).unwrap_or_else(|| path.display().to_string()),
+            file,
+            profile,
+            controls: HashMap::new(),
+            axes,
+            pressed_keys: Vec::new(),
+        })
+    }
+
+    // Opens the first joystick-like device found in /dev/input
+    pub fn open_first(profile: Profile) -> io::Result<Gamepad> {
+        let mut paths: Vec<PathBuf> = fs::read_dir("/dev/input")?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("event"))
+            })
+            .collect();
+
+        paths.sort_by_key(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name["event".len()..].parse::<u32>().ok())
+                .unwrap_or(u32::MAX)
+        });
+
+        for path in paths {
+            if let Ok(gamepad) = Gamepad::open(&path, profile.clone()) {
+                return Ok(gamepad);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no joystick device found in /dev/input",
+        ))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_key_down(&self, key: Key) -> bool {
+        let key = key as u8;
+
+        self.controls
+            .iter()
+            .any(|(&control, &down)| down && self.profile.key_for(control) == Some(key))
+    }
+
+    // Reads all pending events, returns the Chip-8 keys which went down since the last poll
+    pub fn poll(&mut self) -> io::Result<Vec<Key>> {
+        let event_size = mem::size_of::<libc::input_event>();
+        let mut buffer = vec![0u8; event_size * 64];
+
+        loop {
+            let read = match self.file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+
+            for chunk in buffer[..read].chunks_exact(event_size) {
+                let event: libc::input_event =
+                    unsafe { ptr::read_unaligned(chunk.as_ptr() as *const libc::input_event) };
+
+                self.handle_event(event.type_, event.code, event.value);
+            }
+        }
+
+        Ok(mem::take(&mut self.pressed_keys))
+    }
+
+    fn handle_event(&mut self, event_type: u16, code: u16, value: i32) {
+        match event_type {
+            EV_KEY => {
+                if let Some(control) = Control::from_button(code) {
+                    // value 2 is an autorepeat, which doesn't change the button state
+                    if value != 2 {
+                        self.set_control(control, value != 0);
+                    }
+                }
+            }
+            EV_ABS => match code {
+                ABS_HAT0X => {
+                    self.set_control(Control::Left, value < 0);
+                    self.set_control(Control::Right, value > 0);
+                }
+                ABS_HAT0Y => {
+                    self.set_control(Control::Up, value < 0);
+                    self.set_control(Control::Down, value > 0);
+                }
+                ABS_X | ABS_Y => {
+                    if let Some(range) = self.axes.get(&code).cloned() {
+                        let (negative, positive) = if code == ABS_X {
+                            (Control::Left, Control::Right)
+                        } else {
+                            (Control::Up, Control::Down)
+                        };
+
+                        self.set_control(negative, value <= range.low);
+                        self.set_control(positive, value >= range.high);
+                    }
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn set_control(&mut self, control: Control, down: bool) {
+        let was_down = self.controls.insert(control, down).unwrap_or(false);
+
+        if down && !was_down {
+            if let Some(key) = self.profile.key_for(control) {
+                self.pressed_keys
+                    .push(num::FromPrimitive::from_u8(key).unwrap());
+            }
+        }
+    }
+}
+
+fn test_bit(bits: &[u8], bit: usize) -> bool {
+    bits.get(bit / 8)
+        .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+}
+
+fn is_joystick(file: &File) -> bool {
+    let mut key_bits = [0u8; KEY_MAX / 8 + 1];
+
+    let result = unsafe {
+        libc::ioctl(
+            file.as_raw_fd(),
+            eviocgbit(EV_KEY, key_bits.len()),
+            key_bits.as_mut_ptr(),
+        )
+    };
+
+    if result < 0 {
+        return false;
+    }
+
+    // Joysticks and gamepads report buttons in the BTN_JOYSTICK..BTN_DIGI range,
+    // some pads only expose their D-pad as buttons
+    (BTN_JOYSTICK..BTN_DIGI)
+        .chain(BTN_DPAD_UP..BTN_DPAD_RIGHT + 1)
+        .any(|bit| test_bit(&key_bits, bit as usize))
+}
+
+fn device_name(file: &File) -> Option<String> {
+    let mut name = [0u8; 256];
+
+    let result =
+        unsafe { libc::ioctl(file.as_raw_fd(), eviocgname(name.len()), name.as_mut_ptr()) };
+
+    if result <= 0 {
+        return None;
+    }
+
+    let length = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Some(String::from_utf8_lossy(&name[..length]).into_owned())
+}
+
+fn query_axis_range(file: &File, axis: u16) -> Option<AxisRange> {
+    let mut info: libc::input_absinfo = unsafe { mem::zeroed() };
+
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), eviocgabs(axis), &mut info) };
+    if result < 0 || info.maximum <= info.minimum {
+        return None;
+    }
+
+    // Consider the stick pushed when it travels past half of its course
+    let center = info.minimum + (info.maximum - info.minimum) / 2;
+    let quarter = (info.maximum - info.minimum) / 4;
+
+    Some(AxisRange {
+        low: center - quarter,
+        high: center + quarter,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+    use std::thread;
+    use std::time::Duration;
+
+    // uinput ioctl requests (see linux/uinput.h)
+    const IOC_WRITE: libc::c_ulong = 1;
+    const UI_DEV_CREATE: libc::c_ulong = 0x5501;
+    const UI_DEV_DESTROY: libc::c_ulong = 0x5502;
+
+    const EV_SYN: u16 = 0x00;
+
+    fn ui_set_bit(nr: libc::c_ulong) -> libc::c_ulong {
+        ioc(IOC_WRITE, b'U', nr, mem::size_of::<libc::c_int>())
+    }
+
+    fn ui_get_sysname(len: usize) -> libc::c_ulong {
+        ioc(IOC_READ, b'U', 44, len)
+    }
+
+    fn check(result: libc::c_int) -> io::Result<()> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Gamepad created through /dev/uinput, whose events the kernel reports on a
+    // /dev/input/event* device like a real one
+    struct VirtualGamepad {
+        uinput: File,
+        event_path: PathBuf,
+    }
+
+    impl VirtualGamepad {
+        fn create() -> io::Result<VirtualGamepad> {
+            let mut uinput = OpenOptions::new().write(true).open("/dev/uinput")?;
+            let fd = uinput.as_raw_fd();
+
+            unsafe {
+                check(libc::ioctl(fd, ui_set_bit(100), libc::c_int::from(EV_KEY)))?;
+                for &button in &[
+                    BTN_SOUTH,
+                    BTN_EAST,
+                    BTN_START,
+                    BTN_DPAD_UP,
+                    BTN_DPAD_DOWN,
+                    BTN_DPAD_LEFT,
+                    BTN_DPAD_RIGHT,
+                ] {
+                    check(libc::ioctl(fd, ui_set_bit(101), libc::c_int::from(button)))?;
+                }
+
+                check(libc::ioctl(fd, ui_set_bit(100), libc::c_int::from(EV_ABS)))?;
+                for &axis in &[ABS_X, ABS_Y, ABS_HAT0X, ABS_HAT0Y] {
+                    check(libc::ioctl(fd, ui_set_bit(103), libc::c_int::from(axis)))?;
+                }
+            }
+
+            let mut device: libc::uinput_user_dev = unsafe { mem::zeroed() };
+            for (c, &byte) in device.name.iter_mut().zip(b"chip8 test gamepad") {
+                *c = byte as libc::c_char;
+            }
+            device.id.bustype = 0x06; // BUS_VIRTUAL
+            device.absmax[ABS_X as usize] = 255;
+            device.absmax[ABS_Y as usize] = 255;
+            device.absmin[ABS_HAT0X as usize] = -1;
+            device.absmax[ABS_HAT0X as usize] = 1;
+            device.absmin[ABS_HAT0Y as usize] = -1;
+            device.absmax[ABS_HAT0Y as usize] = 1;
+
+            let bytes = unsafe {
+                std::slice::from_raw_parts(
+                    &device as *const libc::uinput_user_dev as *const u8,
+                    mem::size_of::<libc::uinput_user_dev>(),
+                )
+            };
+            uinput.write_all(bytes)?;
+            check(unsafe { libc::ioctl(fd, UI_DEV_CREATE) })?;
+
+            let mut sysname = [0u8; 64];
+            check(unsafe { libc::ioctl(fd, ui_get_sysname(sysname.len()), sysname.as_mut_ptr()) })?;
+            let length = sysname.iter().position(|&c| c == 0).unwrap_or(0);
+            let sysfs = Path::new("/sys/devices/virtual/input")
+                .join(String::from_utf8_lossy(&sysname[..length]).as_ref());
+
+            // The event device appears once udev has processed the new input device
+            for _ in 0..100 {
+                let event_name = fs::read_dir(&sysfs)?
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .find(|name| name.starts_with("event"));
+
+                if let Some(name) = event_name {
+                    let event_path = Path::new("/dev/input").join(name);
+                    if event_path.exists() {
+                        return Ok(VirtualGamepad { uinput, event_path });
+                    }
+                }
+
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no event device for the virtual gamepad",
+            ))
+        }
+
+        fn emit(&mut self, event_type: u16, code: u16, value: i32) {
+            let mut events: [libc::input_event; 2] = unsafe { mem::zeroed() };
+            events[0].type_ = event_type;
+            events[0].code = code;
+            events[0].value = value;
+            events[1].type_ = EV_SYN;
+
+            let bytes = unsafe {
+                std::slice::from_raw_parts(events.as_ptr() as *const u8, mem::size_of_val(&events))
+            };
+            self.uinput.write_all(bytes).unwrap();
+
+            // Leaves the kernel time to deliver the events to the reader
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    impl Drop for VirtualGamepad {
+        fn drop(&mut self) {
+            unsafe {
+                libc::ioctl(self.uinput.as_raw_fd(), UI_DEV_DESTROY);
+            }
+        }
+    }
+
+    // Set where uinput must work, such as CI, so the tests cannot pass without running
+    const REQUIRE_UINPUT: &str = "CHIP8_REQUIRE_UINPUT";
+
+    fn unavailable<T>(reason: String) -> Option<T> {
+        if env::var_os(REQUIRE_UINPUT).is_some() {
+            panic!("{} (required by {})", reason, REQUIRE_UINPUT);
+        }
+
+        println!("skipped, {}", reason);
+        None
+    }
+
+    // Skips the test when the system does not allow creating and reading input devices
+    fn open_virtual_gamepad(profile: Profile) -> Option<(VirtualGamepad, Gamepad)> {
+        let device = match VirtualGamepad::create() {
+            Ok(device) => device,
+            Err(e) => return unavailable(format!("cannot create a uinput device: {}", e)),
+        };
+
+        // udev may still be setting the permissions of the new device
+        let mut result = Gamepad::open(&device.event_path, profile.clone());
+        for _ in 0..100 {
+            match &result {
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    thread::sleep(Duration::from_millis(10));
+                    result = Gamepad::open(&device.event_path, profile.clone());
+                }
+                _ => break,
+            }
+        }
+
+        match result {
+            Ok(gamepad) => Some((device, gamepad)),
+            Err(e) => unavailable(format!(
+                "cannot open {}: {}",
+                device.event_path.display(),
+                e
+            )),
+        }
+    }
+
+    #[test]
+    fn buttons_map_to_profile_keys() {
+        let (mut device, mut gamepad) = match open_virtual_gamepad(Profile::default_profile()) {
+            Some(devices) => devices,
+            None => return,
+        };
+        assert_eq!(gamepad.name(), "chip8 test gamepad");
+
+        device.emit(EV_KEY, BTN_SOUTH, 1);
+        assert_eq!(gamepad.poll().unwrap(), [Key::Key5]);
+        assert!(gamepad.is_key_down(Key::Key5));
+
+        // Holding the button does not press the key again
+        device.emit(EV_KEY, BTN_SOUTH, 2);
+        assert!(gamepad.poll().unwrap().is_empty());
+        assert!(gamepad.is_key_down(Key::Key5));
+
+        device.emit(EV_KEY, BTN_START, 1);
+        device.emit(EV_KEY, BTN_SOUTH, 0);
+        assert_eq!(gamepad.poll().unwrap(), [Key::KeyF]);
+        assert!(!gamepad.is_key_down(Key::Key5));
+        assert!(gamepad.is_key_down(Key::KeyF));
+
+        device.emit(EV_KEY, BTN_DPAD_UP, 1);
+        assert_eq!(gamepad.poll().unwrap(), [Key::Key2]);
+    }
+
+    #[test]
+    fn hat_and_stick_map_to_directions() {
+        let (mut device, mut gamepad) = match open_virtual_gamepad(Profile::default_profile()) {
+            Some(devices) => devices,
+            None => return,
+        };
+
+        device.emit(EV_ABS, ABS_HAT0X, -1);
+        assert_eq!(gamepad.poll().unwrap(), [Key::Key4]);
+        assert!(gamepad.is_key_down(Key::Key4));
+
+        device.emit(EV_ABS, ABS_HAT0X, 1);
+        assert_eq!(gamepad.poll().unwrap(), [Key::Key6]);
+        assert!(!gamepad.is_key_down(Key::Key4));
+
+        device.emit(EV_ABS, ABS_HAT0X, 0);
+        device.emit(EV_ABS, ABS_Y, 255);
+        assert_eq!(gamepad.poll().unwrap(), [Key::Key8]);
+        assert!(!gamepad.is_key_down(Key::Key6));
+
+        // Inside the dead zone, the stick is released
+        device.emit(EV_ABS, ABS_Y, 140);
+        assert!(gamepad.poll().unwrap().is_empty());
+        assert!(!gamepad.is_key_down(Key::Key8));
+    }
+
+    #[test]
+    fn builtin_profiles_bind_directions_to_different_keys() {
+        let names = [
+            "PONG", "PONG2", "TANK", "UFO", "BLITZ", "BRIX", "VBRIX", "INVADERS", "TETRIS",
+            "MISSILE", "WIPEOFF",
+        ];
+        let directions = [Control::Up, Control::Down, Control::Left, Control::Right];
+
+        for name in &names {
+            let profile = Profile::builtin(name).unwrap();
+            let mut keys: Vec<u8> = directions
+                .iter()
+                .filter_map(|&control| profile.key_for(control))
+                .collect();
+            let count = keys.len();
+            keys.sort_unstable();
+            keys.dedup();
+
+            assert_eq!(
+                keys.len(),
+                count,
+                "{} binds a key to several directions",
+                name
+            );
+        }
+    }
+}
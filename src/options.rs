@@ -0,0 +1,920 @@
+use crate::chip8::coverage::CoverageFormat;
+use crate::chip8::profile::ProfileFormat;
+use crate::chip8::state::{Chip8Options, SCHIP_STACK_DEPTH, VIP_STACK_DEPTH, VIP_STACK_MAX_DEPTH};
+use crate::chip8::trace::{self, TraceFilter, TraceFormat};
+use crate::crt::CrtOptions;
+use crate::frontend::runner::MAX_INSTRUCTIONS_PER_FRAME;
+use crate::frontend::CELL_SIZE;
+use crate::palette::Palette;
+use crate::vip::DEFAULT_FETCH_ADDRESS;
+
+pub const USAGE: &str = "Usage:
+    chip8 [run] [options] <rom-path>
+    chip8 vip --monitor <file> --interpreter <file> [options] <rom-path>
+    chip8 trace-diff [--context <count>] <trace> <reference-trace>
+    chip8 bench [--frames <count>] [--ipf <count>] [<rom-path>...]
+    chip8 recompile [--sources <path>] <rom-path> -o <output.rs>
+    chip8 cfg [-o <output.dot>] <rom-path>
+
+Run options:
+    --headless                  Run without opening a window
+    --tty                       Render in the terminal instead of a window (Unix only)
+    --tty-mode <mode>           Terminal rendering, halfblocks or braille (default: halfblocks)
+    --frames <count>            Number of 60Hz frames to run in headless mode (default: 600)
+    --input <script>            Scripted input, lines of \"<frame> <down|up> <key>\"
+    --dump-screen <path>        Write the final screen as PNG (.png) or ASCII (default: -, stdout)
+                                in headless mode, as PNG when the window is closed otherwise
+    --dump-state <path>         Write the final interpreter state as JSON, - for stdout
+    --ipf <count>               Instructions per 60Hz frame, overriding the value saved for
+                                the ROM, which is not used in headless mode (default: 12)
+    --stack-depth <depth>       Maximum number of nested calls, vip (12), schip (16) or
+                                a number (default: schip)
+    --stack-in-memory           Also store the stack in memory like the VIP (0xEA0-0xECF)
+    --vip-layout                Keep the stack, registers (0xEF0) and display (0xF00) in memory
+                                like the VIP, for programs accessing them directly
+    --vip-timing                Run instructions at the speed of the COSMAC VIP interpreter
+                                instead of a fixed number per frame
+    --trace <path>              Write every executed instruction to a trace file
+    --trace-format <format>     text or binary (default: text)
+    --trace-addresses <range>   Only trace instructions in a range of addresses, e.g. 200-2FF
+    --trace-frames <range>      Only trace instructions run during a range of frames, e.g. 60-
+    --trace-opcodes <names>     Only trace some opcodes, e.g. CallSubroutine,Return
+    --profile <path>            Count instructions and VIP cycles per address, opcode and
+                                subroutine, written when the emulator stops
+    --profile-format <format>   report (hot spots) or folded (stacks for flame graphs)
+                                (default: report)
+    --coverage <path>           Record which ROM bytes were executed, read and written,
+                                written when the emulator stops
+    --coverage-format <format>  listing (annotated disassembly) or lcov (default: listing)
+    --dynarec                   Translate the program to native code, with a fixed number
+                                of instructions per frame (x86-64 Linux only)
+    --dynarec-lockstep          Check every translated block against the interpreter
+    --watch <range>             Print the memory accesses to a range of addresses, e.g. 300-30F
+    --warn-code-writes          Print the instructions writing to code which was executed
+    --scale <factor>            Initial window scale, - and + change it at runtime and F10
+                                toggles fullscreen (default: 10)
+    --palette <palette>         classic, green, amber, lcd, high-contrast, octo or a list
+                                of 2 or 4 colors such as \"#000000,#FFFFFF\" (default: classic)
+    --phosphor[=<decay>]        Simulate phosphor persistence, cells keep <decay> of their
+                                brightness each frame once turned off (default: 0.6)
+    --blend                     Blend consecutive frames to reduce flicker
+    --scanlines                 Darken every other line
+    --record <path>             Record the session as an animated GIF
+    --capture-dir <dir>         Where F12 screenshots and F11 recordings are saved (default: .)
+    --gamepad[=<device>]        Read input from a joystick (Linux only)
+    --gamepad-profiles=<file>   Load per-ROM gamepad profiles from a file
+
+Hotkeys:
+    F1                          Show or hide the status overlay (window only)
+    Space                       Pause or resume
+    N                           Advance a single frame while paused
+    [ and ]                     Slow down or speed up (0.25x to 4x, then unlimited)
+    Tab                         Toggle unlimited speed
+    Backspace                   Back to normal speed (window only)
+    Page Up and Page Down       Change the instructions per frame, saved per ROM
+                                (+ and - in the terminal)
+
+VIP options, running the original interpreter on an emulated COSMAC VIP:
+    --monitor <file>            Image of the 512 bytes monitor ROM
+    --interpreter <file>        Image of the CHIP-8 interpreter, loaded at 0x0000
+    --frames <count>            Number of frames to run (default: 600)
+    --input <script>            Scripted input, lines of \"<frame> <down|up> <key>\"
+    --cross-check               Compare Chip8State with the VIP after every instruction
+    --fetch-address <hex>       Address of the interpreter fetch loop (default: 001B)
+    --dump-screen <path>        Write the final screen as PNG (.png) or ASCII (default: stdout)
+
+Trace diff options, comparing unfiltered text or binary traces instruction by instruction:
+    --context <count>           Instructions shown before the first divergence (default: 5)
+
+Bench options, measuring instructions per second with and without the decoded instruction
+cache and with the dynarec, on the ROMs of the roms directory unless given:
+    --frames <count>            Number of frames to run per ROM (default: 3000)
+    --ipf <count>               Instructions per frame (default: 1000)
+
+Recompile options, translating the code reachable from 0x200 to a Rust program:
+    -o, --output <path>         Where to write the program
+    --sources <path>            Path of src/chip8/mod.rs from the program, which is built with
+                                the recompiled feature (default: ../chip8/mod.rs, for src/bin)
+
+Cfg options, writing the control-flow graph from 0x200 as Graphviz DOT, with a cluster per
+subroutine and the ROM bytes no block covers:
+    -o, --output <path>         Where to write the graph, - for stdout (default: -)";
+
+pub enum Command {
+    Run(Box<RunOptions>),
+    Vip(VipOptions),
+    TraceDiff(TraceDiffOptions),
+    Bench(BenchOptions),
+    Recompile(RecompileOptions),
+    Cfg(CfgOptions),
+}
+
+#[derive(Clone, Copy)]
+pub enum TtyMode {
+    HalfBlocks,
+    Braille,
+}
+
+pub struct RunOptions {
+    pub rom_path: String,
+    pub headless: bool,
+    pub tty: bool,
+    pub tty_mode: TtyMode,
+    pub frames: u64,
+    pub instructions_per_frame: Option<usize>,
+    pub vip_timing: bool,
+    pub machine: Chip8Options,
+    pub trace_path: Option<String>,
+    pub trace_format: TraceFormat,
+    pub trace_filter: TraceFilter,
+    pub profile_path: Option<String>,
+    pub profile_format: ProfileFormat,
+    pub coverage_path: Option<String>,
+    pub coverage_format: CoverageFormat,
+    pub watch: Option<(usize, usize)>,
+    pub warn_code_writes: bool,
+    pub dynarec: bool,
+    pub dynarec_lockstep: bool,
+    pub input_script: Option<String>,
+    pub screen_dump: Option<String>,
+    pub state_dump: Option<String>,
+    pub record_path: Option<String>,
+    pub scale: usize,
+    pub palette: Palette,
+    pub crt: CrtOptions,
+    pub capture_dir: String,
+    pub gamepad_device: Option<String>,
+    pub gamepad_profiles: Option<String>,
+}
+
+impl RunOptions {
+    fn new(rom_path: String) -> RunOptions {
+        RunOptions {
+            rom_path,
+            headless: false,
+            tty: false,
+            tty_mode: TtyMode::HalfBlocks,
+            frames: 600,
+            instructions_per_frame: None,
+            vip_timing: false,
+            machine: Chip8Options::default(),
+            trace_path: None,
+            trace_format: TraceFormat::Text,
+            trace_filter: TraceFilter::default(),
+            profile_path: None,
+            profile_format: ProfileFormat::Report,
+            coverage_path: None,
+            coverage_format: CoverageFormat::Listing,
+            watch: None,
+            warn_code_writes: false,
+            dynarec: false,
+            dynarec_lockstep: false,
+            input_script: None,
+            screen_dump: None,
+            state_dump: None,
+            record_path: None,
+            scale: CELL_SIZE,
+            palette: Palette::default(),
+            crt: CrtOptions::default(),
+            capture_dir: String::from("."),
+            gamepad_device: None,
+            gamepad_profiles: None,
+        }
+    }
+}
+
+pub struct VipOptions {
+    pub rom_path: String,
+    pub monitor_path: String,
+    pub interpreter_path: String,
+    pub frames: u64,
+    pub input_script: Option<String>,
+    pub cross_check: bool,
+    pub fetch_address: u16,
+    pub screen_dump: Option<String>,
+}
+
+pub struct TraceDiffOptions {
+    pub trace_path: String,
+    pub reference_path: String,
+    pub context: usize,
+}
+
+pub struct BenchOptions {
+    pub rom_paths: Vec<String>,
+    pub frames: u64,
+    pub instructions_per_frame: usize,
+}
+
+pub struct RecompileOptions {
+    pub rom_path: String,
+    pub output_path: String,
+    // Emulator sources included by the program
+    pub sources_path: String,
+}
+
+pub struct CfgOptions {
+    pub rom_path: String,
+    pub output_path: String,
+}
+
+// Splits "--name=value" into its parts, or takes the value from the next argument
+fn option_value<I: Iterator<Item = String>>(
+    name: &str,
+    inline_value: Option<&str>,
+    args: &mut I,
+) -> Result<String, String> {
+    match inline_value {
+        Some(value) => Ok(value.to_owned()),
+        None => args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", name)),
+    }
+}
+
+// "<start>-<end>", either bound being optional
+fn parse_range(value: &str, radix: u32) -> Option<(u64, u64)> {
+    let mut bounds = value
+        .splitn(2, '-')
+        .map(|bound| bound.trim_start_matches("0x"));
+    let start = bounds.next()?;
+    let end = bounds.next()?;
+
+    let start = if start.is_empty() {
+        0
+    } else {
+        u64::from_str_radix(start, radix).ok()?
+    };
+    let end = if end.is_empty() {
+        u64::MAX
+    } else {
+        u64::from_str_radix(end, radix).ok()?
+    };
+
+    Some((start, end)).filter(|(start, end)| start <= end)
+}
+
+// Separates an option from its inline value
+fn split_option(arg: &str) -> (&str, Option<&str>) {
+    match arg.find('=') {
+        Some(index) => (&arg[..index], Some(&arg[index + 1..])),
+        None => (arg, None),
+    }
+}
+
+fn parse_vip<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut rom_path = None;
+    let mut monitor_path = None;
+    let mut interpreter_path = None;
+    let mut frames = 600;
+    let mut input_script = None;
+    let mut cross_check = false;
+    let mut fetch_address = DEFAULT_FETCH_ADDRESS;
+    let mut screen_dump = None;
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if rom_path.is_some() {
+                return Err(format!("Unexpected argument: {}", arg));
+            }
+
+            rom_path = Some(arg);
+            continue;
+        }
+
+        let (name, inline_value) = split_option(&arg);
+
+        match name {
+            "--monitor" => monitor_path = Some(option_value(name, inline_value, &mut args)?),
+            "--interpreter" => {
+                interpreter_path = Some(option_value(name, inline_value, &mut args)?)
+            }
+            "--frames" => {
+                let value = option_value(name, inline_value, &mut args)?;
+                frames = value
+                    .parse()
+                    .map_err(|_| format!("Invalid frame count: {}", value))?;
+            }
+            "--input" => input_script = Some(option_value(name, inline_value, &mut args)?),
+            "--cross-check" => cross_check = true,
+            "--fetch-address" => {
+                let value = option_value(name, inline_value, &mut args)?;
+                fetch_address = u16::from_str_radix(value.trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("Invalid address: {}", value))?;
+            }
+            "--dump-screen" => screen_dump = Some(option_value(name, inline_value, &mut args)?),
+            _ => return Err(format!("Unknown option: {}", name)),
+        }
+    }
+
+    Ok(Command::Vip(VipOptions {
+        rom_path: rom_path.ok_or_else(|| String::from("Missing ROM path"))?,
+        monitor_path: monitor_path.ok_or_else(|| String::from("Missing monitor ROM image"))?,
+        interpreter_path: interpreter_path
+            .ok_or_else(|| String::from("Missing interpreter image"))?,
+        frames,
+        input_script,
+        cross_check,
+        fetch_address,
+        screen_dump,
+    }))
+}
+
+fn parse_trace_diff<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut paths = Vec::new();
+    let mut context = 5;
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if paths.len() == 2 {
+                return Err(format!("Unexpected argument: {}", arg));
+            }
+
+            paths.push(arg);
+            continue;
+        }
+
+        let (name, inline_value) = split_option(&arg);
+
+        match name {
+            "--context" => {
+                let value = option_value(name, inline_value, &mut args)?;
+                context = value
+                    .parse()
+                    .map_err(|_| format!("Invalid context: {}", value))?;
+            }
+            _ => return Err(format!("Unknown option: {}", name)),
+        }
+    }
+
+    let mut paths = paths.into_iter();
+    Ok(Command::TraceDiff(TraceDiffOptions {
+        trace_path: paths
+            .next()
+            .ok_or_else(|| String::from("Missing trace path"))?,
+        reference_path: paths
+            .next()
+            .ok_or_else(|| String::from("Missing reference trace path"))?,
+        context,
+    }))
+}
+
+fn parse_bench<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut options = BenchOptions {
+        rom_paths: Vec::new(),
+        frames: 3000,
+        instructions_per_frame: MAX_INSTRUCTIONS_PER_FRAME,
+    };
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            options.rom_paths.push(arg);
+            continue;
+        }
+
+        let (name, inline_value) = split_option(&arg);
+
+        match name {
+            "--frames" => {
+                let value = option_value(name, inline_value, &mut args)?;
+                options.frames = value
+                    .parse()
+                    .map_err(|_| format!("Invalid frame count: {}", value))?;
+            }
+            "--ipf" => {
+                let value = option_value(name, inline_value, &mut args)?;
+                options.instructions_per_frame = value
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| format!("Invalid instructions per frame: {}", value))?;
+            }
+            _ => return Err(format!("Unknown option: {}", name)),
+        }
+    }
+
+    Ok(Command::Bench(options))
+}
+
+fn parse_recompile<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut rom_path = None;
+    let mut output_path = None;
+    let mut sources_path = String::from("../chip8/mod.rs");
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            if rom_path.is_some() {
+                return Err(format!("Unexpected argument: {}", arg));
+            }
+
+            rom_path = Some(arg);
+            continue;
+        }
+
+        let (name, inline_value) = split_option(&arg);
+
+        match name {
+            "-o" | "--output" => output_path = Some(option_value(name, inline_value, &mut args)?),
+            "--sources" => sources_path = option_value(name, inline_value, &mut args)?,
+            _ => return Err(format!("Unknown option: {}", name)),
+        }
+    }
+
+    Ok(Command::Recompile(RecompileOptions {
+        rom_path: rom_path.ok_or_else(|| String::from("Missing ROM path"))?,
+        output_path: output_path.ok_or_else(|| String::from("Missing output path"))?,
+        sources_path,
+    }))
+}
+
+fn parse_cfg<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut rom_path = None;
+    let mut output_path = String::from("-");
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            if rom_path.is_some() {
+                return Err(format!("Unexpected argument: {}", arg));
+            }
+
+            rom_path = Some(arg);
+            continue;
+        }
+
+        let (name, inline_value) = split_option(&arg);
+
+        match name {
+            "-o" | "--output" => output_path = option_value(name, inline_value, &mut args)?,
+            _ => return Err(format!("Unknown option: {}", name)),
+        }
+    }
+
+    Ok(Command::Cfg(CfgOptions {
+        rom_path: rom_path.ok_or_else(|| String::from("Missing ROM path"))?,
+        output_path,
+    }))
+}
+
+pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut args = args.peekable();
+
+    match args.peek().map(String::as_str) {
+        Some("run") => {
+            args.next();
+        }
+        Some("vip") => {
+            args.next();
+            return parse_vip(args);
+        }
+        Some("trace-diff") => {
+            args.next();
+            return parse_trace_diff(args);
+        }
+        Some("cfg") => {
+            args.next();
+            return parse_cfg(args);
+        }
+        Some("recompile") => {
+            args.next();
+            return parse_recompile(args);
+        }
+        Some("bench") => {
+            args.next();
+            return parse_bench(args);
+        }
+        _ => (),
+    }
+
+    let mut rom_path = None;
+    let mut options = RunOptions::new(String::new());
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if rom_path.is_some() {
+                return Err(format!("Unexpected argument: {}", arg));
+            }
+
+            rom_path = Some(arg);
+            continue;
+        }
+
+        let (name, inline_value) = split_option(&arg);
+
+        match name {
+            "--headless" => options.headless = true,
+            "--tty" => options.tty = true,
+            "--tty-mode" => {
+                options.tty_mode = match option_value(name, inline_value, &mut args)?.as_str() {
+                    "halfblocks" => TtyMode::HalfBlocks,
+                    "braille" => TtyMode::Braille,
+                    mode => return Err(format!("Unknown terminal mode: {}", mode)),
+                }
+            }
+            "--frames" => {
+                let value = option_value(name, inline_value, &mut args)?;
+                options.frames = value
+                    .parse()
+                    .map_err(|_| format!("Invalid frame count: {}", value))?;
+            }
+            "--ipf" => {
+                let value = option_value(name, inline_value, &mut args)?;
+                options.instructions_per_frame = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|count| (1..=MAX_INSTRUCTIONS_PER_FRAME).contains(count))
+                        .ok_or_else(|| format!("Invalid instructions per frame: {}", value))?,
+                );
+            }
+            "--vip-timing" => options.vip_timing = true,
+            "--stack-depth" => {
+                let value = option_value(name, inline_value, &mut args)?;
+                options.machine.stack_depth = match value.as_str() {
+                    "vip" => VIP_STACK_DEPTH,
+                    "schip" => SCHIP_STACK_DEPTH,
+                    _ => value
+                        .parse()
+                        .ok()
+                        .filter(|&depth| depth > 0)
+                        .ok_or_else(|| format!("Invalid stack depth: {}", value))?,
+                };
+            }
+            "--stack-in-memory" => options.machine.stack_in_memory = true,
+            "--vip-layout" => {
+                options.machine.stack_in_memory = true;
+                options.machine.vip_layout = true;
+            }
+            "--trace" => options.trace_path = Some(option_value(name, inline_value, &mut args)?),
+            "--trace-format" => {
+                options.trace_format = match option_value(name, inline_value, &mut args)?.as_str() {
+                    "text" => TraceFormat::Text,
+                    "binary" => TraceFormat::Binary,
+                    format => return Err(format!("Unknown trace format: {}", format)),
+                }
+            }
+            "--trace-addresses" => {
+                let value = option_value(name, inline_value, &mut args)?;
+                let (start, end) = parse_range(&value, 16)
+                    .ok_or_else(|| format!("Invalid address range: {}", value))?;
+                options.trace_filter.addresses = Some((start as usize, end as usize));
+            }
+            "--trace-frames" => {
+                let value = option_value(name, inline_value, &mut args)?;
+                options.trace_filter.frames = Some(
+                    parse_range(&value, 10)
+                        .ok_or_else(|| format!("Invalid frame range: {}", value))?,
+                );
+            }
+            "--trace-opcodes" => {
+                options.trace_filter.opcodes =
+                    trace::parse_opcode_names(&option_value(name, inline_value, &mut args)?)?
+            }
+            "--profile" => {
+                options.profile_path = Some(option_value(name, inline_value, &mut args)?)
+            }
+            "--profile-format" => {
+                options.profile_format = match option_value(name, inline_value, &mut args)?.as_str()
+                {
+                    "report" => ProfileFormat::Report,
+                    "folded" => ProfileFormat::Folded,
+                    format => return Err(format!("Unknown profile format: {}", format)),
+                }
+            }
+            "--coverage" => {
+                options.coverage_path = Some(option_value(name, inline_value, &mut args)?)
+            }
+            "--coverage-format" => {
+                options.coverage_format =
+                    match option_value(name, inline_value, &mut args)?.as_str() {
+                        "listing" => CoverageFormat::Listing,
+                        "lcov" => CoverageFormat::Lcov,
+                        format => return Err(format!("Unknown coverage format: {}", format)),
+                    }
+            }
+            "--warn-code-writes" => options.warn_code_writes = true,
+            "--dynarec" => options.dynarec = true,
+            "--dynarec-lockstep" => {
+                options.dynarec = true;
+                options.dynarec_lockstep = true;
+            }
+            "--watch" => {
+                let value = option_value(name, inline_value, &mut args)?;
+                let (start, end) = parse_range(&value, 16)
+                    .ok_or_else(|| format!("Invalid address range: {}", value))?;
+                options.watch = Some((start as usize, end as usize));
+            }
+            "--input" => options.input_script = Some(option_value(name, inline_value, &mut args)?),
+            "--dump-screen" => {
+                options.screen_dump = Some(option_value(name, inline_value, &mut args)?)
+            }
+            "--dump-state" => {
+                options.state_dump = Some(option_value(name, inline_value, &mut args)?)
+            }
+            "--scale" => {
+                let value = option_value(name, inline_value, &mut args)?;
+                options.scale = value
+                    .parse()
+                    .ok()
+                    .filter(|&scale| scale > 0)
+                    .ok_or_else(|| format!("Invalid scale: {}", value))?;
+            }
+            "--palette" => {
+                options.palette = Palette::parse(&option_value(name, inline_value, &mut args)?)?
+            }
+            "--phosphor" => {
+                let decay = match inline_value {
+                    Some(value) => value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|decay| (0.0..1.0).contains(decay))
+                        .ok_or_else(|| format!("Invalid phosphor decay: {}", value))?,
+                    None => 0.6,
+                };
+
+                options.crt.phosphor_decay = Some(decay);
+            }
+            "--blend" => options.crt.frame_blending = true,
+            "--scanlines" => options.crt.scanlines = true,
+            "--record" => options.record_path = Some(option_value(name, inline_value, &mut args)?),
+            "--capture-dir" => options.capture_dir = option_value(name, inline_value, &mut args)?,
+            "--gamepad" => options.gamepad_device = Some(inline_value.unwrap_or("").to_owned()),
+            "--gamepad-profiles" => {
+                options.gamepad_profiles = Some(option_value(name, inline_value, &mut args)?)
+            }
+            _ => return Err(format!("Unknown option: {}", name)),
+        }
+    }
+
+    options.rom_path = rom_path.ok_or_else(|| String::from("Missing ROM path"))?;
+
+    if options.machine.stack_in_memory && options.machine.stack_depth > VIP_STACK_MAX_DEPTH {
+        return Err(format!(
+            "The stack cannot be deeper than {} entries in memory",
+            VIP_STACK_MAX_DEPTH
+        ));
+    }
+
+    // Blocks run without going through the memory bus, and a whole frame at once
+    if options.dynarec {
+        if options.vip_timing || options.machine.stack_in_memory {
+            return Err(String::from(
+                "--dynarec cannot be used with --vip-timing, --stack-in-memory or --vip-layout",
+            ));
+        }
+
+        if options.trace_path.is_some()
+            || options.profile_path.is_some()
+            || options.coverage_path.is_some()
+            || options.watch.is_some()
+            || options.warn_code_writes
+        {
+            return Err(String::from(
+                "--dynarec cannot be used with --trace, --profile, --coverage, --watch or \
+                 --warn-code-writes",
+            ));
+        }
+    }
+
+    if options.headless && options.tty {
+        return Err(String::from("--headless and --tty are mutually exclusive"));
+    }
+
+    Ok(Command::Run(Box::new(options)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(|&arg| arg.to_owned()))
+    }
+
+    fn run_options(args: &[&str]) -> Box<RunOptions> {
+        match parse_args(args) {
+            Ok(Command::Run(options)) => options,
+            Ok(_) => panic!("{:?} is not a run command", args),
+            Err(e) => panic!("{:?}: {}", args, e),
+        }
+    }
+
+    fn error(args: &[&str]) -> String {
+        match parse_args(args) {
+            Ok(_) => panic!("{:?} was accepted", args),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn instructions_per_frame_are_limited() {
+        assert_eq!(run_options(&["rom"]).instructions_per_frame, None);
+        assert_eq!(
+            run_options(&["--ipf", "1", "rom"]).instructions_per_frame,
+            Some(1)
+        );
+        assert_eq!(
+            run_options(&["rom", "--ipf=1000"]).instructions_per_frame,
+            Some(MAX_INSTRUCTIONS_PER_FRAME)
+        );
+
+        for value in ["0", "1001", "-1", "many"] {
+            assert_eq!(
+                error(&["--ipf", value, "rom"]),
+                format!("Invalid instructions per frame: {}", value)
+            );
+        }
+        assert_eq!(error(&["rom", "--ipf"]), "Missing value for --ipf");
+
+        // Bench runs are not limited to what can be run at 60Hz
+        match parse_args(&["bench", "--ipf", "5000"]) {
+            Ok(Command::Bench(options)) => assert_eq!(options.instructions_per_frame, 5000),
+            _ => panic!("invalid bench options"),
+        }
+        assert_eq!(
+            error(&["bench", "--ipf", "0"]),
+            "Invalid instructions per frame: 0"
+        );
+    }
+
+    #[test]
+    fn stack_depths_fit_in_memory() {
+        assert_eq!(run_options(&["rom"]).machine.stack_depth, SCHIP_STACK_DEPTH);
+        assert_eq!(
+            run_options(&["--stack-depth", "vip", "rom"])
+                .machine
+                .stack_depth,
+            VIP_STACK_DEPTH
+        );
+        assert_eq!(
+            run_options(&["--stack-depth=64", "rom"])
+                .machine
+                .stack_depth,
+            64
+        );
+        assert_eq!(
+            error(&["--stack-depth", "0", "rom"]),
+            "Invalid stack depth: 0"
+        );
+        assert_eq!(
+            error(&["--stack-depth", "deep", "rom"]),
+            "Invalid stack depth: deep"
+        );
+
+        let options = run_options(&["--vip-layout", "--stack-depth=24", "rom"]);
+        assert!(options.machine.vip_layout && options.machine.stack_in_memory);
+        assert_eq!(
+            error(&["--stack-in-memory", "--stack-depth=25", "rom"]),
+            "The stack cannot be deeper than 24 entries in memory"
+        );
+    }
+
+    #[test]
+    fn output_formats_are_named() {
+        let options = run_options(&[
+            "--trace-format=binary",
+            "--profile-format=folded",
+            "--coverage-format=lcov",
+            "--tty-mode=braille",
+            "rom",
+        ]);
+        assert!(options.trace_format == TraceFormat::Binary);
+        assert!(options.profile_format == ProfileFormat::Folded);
+        assert!(options.coverage_format == CoverageFormat::Lcov);
+        assert!(matches!(options.tty_mode, TtyMode::Braille));
+
+        assert_eq!(
+            error(&["--trace-format=json", "rom"]),
+            "Unknown trace format: json"
+        );
+        assert_eq!(
+            error(&["--profile-format=svg", "rom"]),
+            "Unknown profile format: svg"
+        );
+        assert_eq!(
+            error(&["--coverage-format=html", "rom"]),
+            "Unknown coverage format: html"
+        );
+        assert_eq!(
+            error(&["--tty-mode=ascii", "rom"]),
+            "Unknown terminal mode: ascii"
+        );
+    }
+
+    #[test]
+    fn ranges_have_optional_bounds() {
+        let options = run_options(&[
+            "--trace-addresses=0x200-2FF",
+            "--trace-frames=60-",
+            "--watch=-30F",
+            "--trace-opcodes=callsubroutine,Return",
+            "rom",
+        ]);
+        assert_eq!(options.trace_filter.addresses, Some((0x200, 0x2FF)));
+        assert_eq!(options.trace_filter.frames, Some((60, u64::MAX)));
+        assert_eq!(options.watch, Some((0, 0x30F)));
+        assert_eq!(options.trace_filter.opcodes, ["CallSubroutine", "Return"]);
+
+        assert_eq!(
+            error(&["--trace-addresses=2FF-200", "rom"]),
+            "Invalid address range: 2FF-200"
+        );
+        assert_eq!(error(&["--watch=300", "rom"]), "Invalid address range: 300");
+        assert_eq!(
+            error(&["--trace-frames=1F-", "rom"]),
+            "Invalid frame range: 1F-"
+        );
+        assert!(error(&["--trace-opcodes=Jump,Fly", "rom"]).contains("Fly"));
+    }
+
+    #[test]
+    fn display_options_are_validated() {
+        let options = run_options(&[
+            "--scale=3",
+            "--palette=#000000,#FFFFFF",
+            "--phosphor",
+            "--blend",
+            "--scanlines",
+            "rom",
+        ]);
+        assert_eq!(options.scale, 3);
+        assert_eq!(options.palette.name(), "custom");
+        assert_eq!(options.crt.phosphor_decay, Some(0.6));
+        assert!(options.crt.frame_blending && options.crt.scanlines);
+
+        assert_eq!(
+            run_options(&["--phosphor=0.25", "rom"]).crt.phosphor_decay,
+            Some(0.25)
+        );
+        for value in ["1", "1.5", "-0.1", "slow"] {
+            assert_eq!(
+                error(&[&format!("--phosphor={}", value), "rom"]),
+                format!("Invalid phosphor decay: {}", value)
+            );
+        }
+        assert_eq!(error(&["--scale=0", "rom"]), "Invalid scale: 0");
+        assert!(error(&["--palette=sepia", "rom"]).starts_with("Unknown palette \"sepia\""));
+
+        assert_eq!(
+            run_options(&["--gamepad", "rom"]).gamepad_device.as_deref(),
+            Some("")
+        );
+        assert_eq!(
+            run_options(&["--gamepad=/dev/input/js1", "rom"])
+                .gamepad_device
+                .as_deref(),
+            Some("/dev/input/js1")
+        );
+    }
+
+    #[test]
+    fn conflicting_options_are_rejected() {
+        assert_eq!(
+            error(&["--dynarec", "--vip-timing", "rom"]),
+            "--dynarec cannot be used with --vip-timing, --stack-in-memory or --vip-layout"
+        );
+        assert!(error(&["--dynarec-lockstep", "--watch=300-301", "rom"])
+            .starts_with("--dynarec cannot be used with --trace"));
+        assert_eq!(
+            error(&["--headless", "--tty", "rom"]),
+            "--headless and --tty are mutually exclusive"
+        );
+        assert_eq!(error(&["--headless"]), "Missing ROM path");
+        assert_eq!(error(&["rom", "other"]), "Unexpected argument: other");
+        assert_eq!(error(&["--fast", "rom"]), "Unknown option: --fast");
+    }
+
+    #[test]
+    fn subcommands_have_their_own_options() {
+        match parse_args(&[
+            "vip",
+            "--monitor=monitor.bin",
+            "--interpreter",
+            "chip8.bin",
+            "--fetch-address=0x1C",
+            "rom",
+        ]) {
+            Ok(Command::Vip(options)) => {
+                assert_eq!(options.monitor_path, "monitor.bin");
+                assert_eq!(options.fetch_address, 0x1C);
+                assert!(!options.cross_check);
+            }
+            _ => panic!("invalid VIP options"),
+        }
+        assert_eq!(
+            error(&["vip", "--interpreter=chip8.bin", "rom"]),
+            "Missing monitor ROM image"
+        );
+
+        match parse_args(&["trace-diff", "--context=2", "a", "b"]) {
+            Ok(Command::TraceDiff(options)) => assert_eq!(options.context, 2),
+            _ => panic!("invalid trace diff options"),
+        }
+        assert_eq!(error(&["trace-diff", "a"]), "Missing reference trace path");
+
+        match parse_args(&["cfg", "rom"]) {
+            Ok(Command::Cfg(options)) => assert_eq!(options.output_path, "-"),
+            _ => panic!("invalid cfg options"),
+        }
+        assert_eq!(error(&["recompile", "rom"]), "Missing output path");
+    }
+}
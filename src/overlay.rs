@@ -0,0 +1,271 @@
+use crate::chip8::state::CHIP8_FONTSET;
+use std::time::{Duration, Instant};
+
+const MESSAGE_DURATION: Duration = Duration::from_secs(3);
+
+const GLYPH_WIDTH: usize = 4;
+const GLYPH_HEIGHT: usize = 5;
+
+const TEXT_COLOR: u32 = 0xFFFFFF;
+
+// Glyphs use the same 4x5 layout as the Chip-8 font, one byte per row with the pixels
+// in the high nibble. Digits and A-F come from the Chip-8 font itself.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    if let Some(digit) = c.to_digit(16) {
+        let start = digit as usize * GLYPH_HEIGHT;
+        let mut glyph = [0; GLYPH_HEIGHT];
+        glyph.copy_from_slice(&CHIP8_FONTSET[start..start + GLYPH_HEIGHT]);
+        return glyph;
+    }
+
+    match c.to_ascii_uppercase() {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00],
+        'G' => [0xF0, 0x80, 0xB0, 0x90, 0xF0],
+        'H' => [0x90, 0x90, 0xF0, 0x90, 0x90],
+        'I' => [0xE0, 0x40, 0x40, 0x40, 0xE0],
+        'J' => [0x70, 0x20, 0x20, 0xA0, 0xE0],
+        'K' => [0x90, 0xA0, 0xC0, 0xA0, 0x90],
+        'L' => [0x80, 0x80, 0x80, 0x80, 0xF0],
+        'M' => [0x90, 0xF0, 0xF0, 0x90, 0x90],
+        'N' => [0x90, 0xD0, 0xB0, 0x90, 0x90],
+        'O' => [0x60, 0x90, 0x90, 0x90, 0x60],
+        'P' => [0xE0, 0x90, 0xE0, 0x80, 0x80],
+        'Q' => [0x60, 0x90, 0x90, 0xB0, 0x70],
+        'R' => [0xE0, 0x90, 0xE0, 0xA0, 0x90],
+        'S' => [0x70, 0x80, 0x60, 0x10, 0xE0],
+        'T' => [0xE0, 0x40, 0x40, 0x40, 0x40],
+        'U' => [0x90, 0x90, 0x90, 0x90, 0xF0],
+        'V' => [0x90, 0x90, 0x90, 0xA0, 0x40],
+        'W' => [0x90, 0x90, 0xF0, 0xF0, 0x90],
+        'X' => [0x90, 0x90, 0x60, 0x90, 0x90],
+        'Y' => [0xA0, 0xA0, 0x40, 0x40, 0x40],
+        'Z' => [0xF0, 0x10, 0x60, 0x80, 0xF0],
+        '!' => [0x40, 0x40, 0x40, 0x00, 0x40],
+        '"' => [0xA0, 0xA0, 0x00, 0x00, 0x00],
+        '#' => [0xA0, 0xF0, 0xA0, 0xF0, 0xA0],
+        '%' => [0x90, 0x10, 0x20, 0x40, 0x90],
+        '\'' => [0x40, 0x40, 0x00, 0x00, 0x00],
+        '(' => [0x20, 0x40, 0x40, 0x40, 0x20],
+        ')' => [0x40, 0x20, 0x20, 0x20, 0x40],
+        '*' => [0x00, 0xA0, 0x40, 0xA0, 0x00],
+        '+' => [0x00, 0x40, 0xE0, 0x40, 0x00],
+        ',' => [0x00, 0x00, 0x00, 0x40, 0x80],
+        '-' => [0x00, 0x00, 0xE0, 0x00, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x40],
+        '/' => [0x10, 0x10, 0x20, 0x40, 0x80],
+        ':' => [0x00, 0x40, 0x00, 0x40, 0x00],
+        '<' => [0x20, 0x40, 0x80, 0x40, 0x20],
+        '=' => [0x00, 0xE0, 0x00, 0xE0, 0x00],
+        '>' => [0x80, 0x40, 0x20, 0x40, 0x80],
+        '[' => [0x60, 0x40, 0x40, 0x40, 0x60],
+        ']' => [0x60, 0x20, 0x20, 0x20, 0x60],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0xF0],
+        // Anything else is drawn as a question mark
+        _ => [0xE0, 0x10, 0x60, 0x00, 0x40],
+    }
+}
+
+fn darken(color: u32) -> u32 {
+    (color >> 2) & 0x3F3F3F
+}
+
+// Draws a line of text with a darkened background box, clipped to the buffer
+fn draw_text(
+    buffer: &mut [u32],
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    text: &str,
+    pixel_size: usize,
+) {
+    let advance = (GLYPH_WIDTH + 1) * pixel_size;
+    let box_width = text.chars().count() * advance + pixel_size;
+    let box_height = (GLYPH_HEIGHT + 2) * pixel_size;
+
+    for box_y in y..(y + box_height).min(height) {
+        for box_x in x..(x + box_width).min(width) {
+            let pixel = &mut buffer[box_y * width + box_x];
+            *pixel = darken(*pixel);
+        }
+    }
+
+    for (index, c) in text.chars().enumerate() {
+        let glyph_x = x + pixel_size + index * advance;
+
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (0x80 >> column) == 0 {
+                    continue;
+                }
+
+                let pixel_x = glyph_x + column * pixel_size;
+                let pixel_y = y + (row + 1) * pixel_size;
+
+                for py in pixel_y..(pixel_y + pixel_size).min(height) {
+                    for px in pixel_x..(pixel_x + pixel_size).min(width) {
+                        buffer[py * width + px] = TEXT_COLOR;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Status text drawn over the emulator output: status lines in the top left corner,
+// toggled by the user, and temporary messages in the bottom left corner
+#[derive(Default)]
+pub struct Overlay {
+    visible: bool,
+    message: Option<(String, Instant)>,
+}
+
+impl Overlay {
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+
+    pub fn show_message(&mut self, message: String) {
+        self.message = Some((message, Instant::now()));
+    }
+
+    // True if there is anything to draw, expired messages are discarded
+    pub fn update(&mut self) -> bool {
+        if self
+            .message
+            .as_ref()
+            .is_some_and(|(_, time)| time.elapsed() >= MESSAGE_DURATION)
+        {
+            self.message = None;
+        }
+
+        self.visible || self.message.is_some()
+    }
+
+    pub fn draw(&self, buffer: &mut [u32], width: usize, height: usize, status: &[String]) {
+        // Text is scaled along with the output, about 160 text pixels high
+        let pixel_size = (height / 160).max(1);
+        let line_height = (GLYPH_HEIGHT + 2) * pixel_size;
+
+        if self.visible {
+            for (index, line) in status.iter().enumerate() {
+                draw_text(
+                    buffer,
+                    width,
+                    height,
+                    0,
+                    index * line_height,
+                    line,
+                    pixel_size,
+                );
+            }
+        }
+
+        if let Some((message, _)) = &self.message {
+            if height >= line_height {
+                draw_text(
+                    buffer,
+                    width,
+                    height,
+                    0,
+                    height - line_height,
+                    message,
+                    pixel_size,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GREY: u32 = 0x808080;
+    const DARK_GREY: u32 = 0x202020;
+
+    // Pixels of the buffer, # for text, + for the darkened box and . for untouched pixels
+    fn rows(buffer: &[u32], width: usize) -> Vec<String> {
+        buffer
+            .chunks(width)
+            .map(|row| {
+                row.iter()
+                    .map(|&pixel| match pixel {
+                        TEXT_COLOR => '#',
+                        DARK_GREY => '+',
+                        _ => '.',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hexadecimal_digits_use_the_chip8_font() {
+        assert_eq!(glyph('0'), CHIP8_FONTSET[..5]);
+        assert_eq!(glyph('a'), CHIP8_FONTSET[50..55]);
+        assert_eq!(glyph('F'), CHIP8_FONTSET[75..80]);
+        assert_eq!(glyph('g'), glyph('G'));
+        assert_eq!(glyph('~'), glyph('?'));
+        assert_ne!(glyph('~'), glyph(' '));
+    }
+
+    #[test]
+    fn text_is_drawn_in_a_darkened_box() {
+        let mut buffer = vec![GREY; 8 * 8];
+        draw_text(&mut buffer, 8, 8, 1, 0, "1", 1);
+
+        assert_eq!(
+            rows(&buffer, 8),
+            [
+                ".++++++.", ".+++#++.", ".++##++.", ".+++#++.", ".+++#++.", ".++###+.", ".++++++.",
+                "........",
+            ]
+        );
+    }
+
+    #[test]
+    fn text_is_clipped_to_the_buffer() {
+        let mut buffer = vec![GREY; 6 * 4];
+        draw_text(&mut buffer, 6, 4, 2, 1, "HELLO", 2);
+
+        assert_eq!(rows(&buffer, 6), ["......", "..++++", "..++++", "..++##"]);
+    }
+
+    #[test]
+    fn status_lines_are_drawn_when_visible() {
+        let mut overlay = Overlay::default();
+        let status = [String::from("1")];
+        assert!(!overlay.update());
+
+        let mut buffer = vec![GREY; 8 * 16];
+        overlay.draw(&mut buffer, 8, 16, &status);
+        assert!(buffer.iter().all(|&pixel| pixel == GREY));
+
+        overlay.toggle();
+        assert!(overlay.update());
+        overlay.draw(&mut buffer, 8, 16, &status);
+        assert_eq!(rows(&buffer, 8)[1], "+++#++..");
+        assert_eq!(rows(&buffer, 8)[15], "........");
+    }
+
+    #[test]
+    fn messages_are_drawn_at_the_bottom() {
+        let mut overlay = Overlay::default();
+        overlay.show_message(String::from("1"));
+        assert!(overlay.update());
+
+        let mut buffer = vec![GREY; 8 * 16];
+        overlay.draw(&mut buffer, 8, 16, &[]);
+        let rows = rows(&buffer, 8);
+        assert_eq!(rows[8], "........");
+        assert_eq!(rows[9], "++++++..");
+        assert_eq!(rows[10], "+++#++..");
+        assert_eq!(rows[15], "++++++..");
+
+        // Too small for a line of text
+        let mut buffer = vec![GREY; 8 * 6];
+        overlay.draw(&mut buffer, 8, 6, &[]);
+        assert!(buffer.iter().all(|&pixel| pixel == GREY));
+    }
+}
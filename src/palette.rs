@@ -0,0 +1,169 @@
+// Colors are 0xRRGGBB, indexed by pixel value: 0 is the background, 1 the foreground.
+// XO-CHIP's second bitplane uses 2, and 3 where both planes overlap.
+const PALETTES: &[(&str, [u32; 4])] = &[
+    ("classic", [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555]),
+    ("green", [0x0A140A, 0x33FF66, 0x1A8033, 0xB3FFC6]),
+    ("amber", [0x140C00, 0xFFB000, 0x805800, 0xFFE0A0]),
+    ("lcd", [0x9BBC0F, 0x0F380F, 0x306230, 0x8BAC0F]),
+    ("high-contrast", [0x000000, 0xFFFF00, 0x00FFFF, 0xFFFFFF]),
+    ("octo", [0x996600, 0xFFCC00, 0xFF6600, 0x662200]),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    name: String,
+    colors: [u32; 4],
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::builtin(0)
+    }
+}
+
+impl Palette {
+    fn builtin(index: usize) -> Palette {
+        let (name, colors) = PALETTES[index];
+
+        Palette {
+            name: name.to_owned(),
+            colors,
+        }
+    }
+
+    // Accepts either a palette name or a comma-separated list of two or four colors
+    // such as "#000000,#FFFFFF"
+    pub fn parse(spec: &str) -> Result<Palette, String> {
+        if let Some(index) = PALETTES.iter().position(|&(name, _)| name == spec) {
+            return Ok(Palette::builtin(index));
+        }
+
+        if !spec.contains(',') {
+            let names: Vec<&str> = PALETTES.iter().map(|&(name, _)| name).collect();
+            return Err(format!(
+                "Unknown palette \"{}\", expected one of {} or a list of colors",
+                spec,
+                names.join(", ")
+            ));
+        }
+
+        let colors = spec
+            .split(',')
+            .map(|color| {
+                let hex = color.trim().trim_start_matches('#');
+                if hex.len() != 6 {
+                    return Err(format!("Invalid color: {}", color));
+                }
+
+                u32::from_str_radix(hex, 16).map_err(|_| format!("Invalid color: {}", color))
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+
+        let colors = match colors.len() {
+            // Without explicit bitplane colors, every plane uses the foreground color
+            2 => [colors[0], colors[1], colors[1], colors[1]],
+            4 => [colors[0], colors[1], colors[2], colors[3]],
+            count => return Err(format!("Expected 2 or 4 colors, got {}", count)),
+        };
+
+        Ok(Palette {
+            name: String::from("custom"),
+            colors,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn background(&self) -> u32 {
+        self.colors[0]
+    }
+
+    pub fn foreground(&self) -> u32 {
+        self.colors[1]
+    }
+
+    pub fn color(&self, index: u8) -> u32 {
+        self.colors[index as usize & 3]
+    }
+
+    pub fn colors(&self) -> &[u32; 4] {
+        &self.colors
+    }
+
+    // Cycles through the built-in palettes, a custom palette goes back to the first one
+    pub fn next(&self) -> Palette {
+        let next = PALETTES
+            .iter()
+            .position(|&(name, _)| name == self.name)
+            .map_or(0, |index| (index + 1) % PALETTES.len());
+
+        Palette::builtin(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_palettes_are_parsed_by_name() {
+        let palette = Palette::parse("amber").unwrap();
+
+        assert_eq!(palette.name(), "amber");
+        assert_eq!(palette.background(), 0x140C00);
+        assert_eq!(palette.foreground(), 0xFFB000);
+        assert_eq!(Palette::parse("classic").unwrap(), Palette::default());
+    }
+
+    #[test]
+    fn custom_palettes_are_lists_of_colors() {
+        let palette = Palette::parse("#102030, 405060").unwrap();
+        assert_eq!(palette.name(), "custom");
+        assert_eq!(palette.colors(), &[0x102030, 0x405060, 0x405060, 0x405060]);
+
+        let palette = Palette::parse("000000,FFFFFF,#ff0000,#00ff00").unwrap();
+        assert_eq!(palette.colors(), &[0x000000, 0xFFFFFF, 0xFF0000, 0x00FF00]);
+        // Pixel values wrap around the four colors
+        assert_eq!(palette.color(6), 0xFF0000);
+    }
+
+    #[test]
+    fn invalid_palettes_are_rejected() {
+        assert!(Palette::parse("sepia")
+            .unwrap_err()
+            .starts_with("Unknown palette \"sepia\", expected one of classic, green"));
+        assert_eq!(
+            Palette::parse("#000000,#FFFFF").unwrap_err(),
+            "Invalid color: #FFFFF"
+        );
+        assert_eq!(
+            Palette::parse("#000000,#GGGGGG").unwrap_err(),
+            "Invalid color: #GGGGGG"
+        );
+        assert_eq!(
+            Palette::parse("#000000,#111111,#222222").unwrap_err(),
+            "Expected 2 or 4 colors, got 3"
+        );
+    }
+
+    #[test]
+    fn next_cycles_through_the_builtin_palettes() {
+        let mut palette = Palette::default();
+        let mut names = Vec::new();
+        for _ in 0..PALETTES.len() {
+            palette = palette.next();
+            names.push(palette.name().to_owned());
+        }
+
+        assert_eq!(
+            names,
+            ["green", "amber", "lcd", "high-contrast", "octo", "classic"]
+        );
+        assert_eq!(
+            Palette::parse("#000000,#FFFFFF").unwrap().next(),
+            Palette::default()
+        );
+    }
+}
@@ -0,0 +1,366 @@
+use crate::cfg::{self, Cfg, Instruction};
+use crate::chip8::opcodes::Opcode;
+use crate::options::RecompileOptions;
+use std::error::Error;
+use std::fmt::Write;
+use std::fs;
+
+// Rust code for an instruction, either a statement or, for the last one, the result of the
+// block given the number of instructions run
+fn translate(opcode: &Opcode, address: usize, count: usize) -> String {
+    let exit = |next: String| format!("Ok(({}, {}))", next, count);
+
+    match *opcode {
+        Opcode::Add { r, value } => {
+            format!("rt.v[{}] = rt.v[{}].wrapping_add({:#04X});", r, r, value)
+        }
+        Opcode::AddAddress { r } => format!("rt.i += u16::from(rt.v[{}]);", r),
+        Opcode::Assign { dst, src } => format!("rt.v[{}] = rt.v[{}];", dst, src),
+        Opcode::BitOpAnd { r1, r2 } => format!("rt.v[{}] &= rt.v[{}];", r1, r2),
+        Opcode::BitOpOr { r1, r2 } => format!("rt.v[{}] |= rt.v[{}];", r1, r2),
+        Opcode::BitOpXor { r1, r2 } => format!("rt.v[{}] ^= rt.v[{}];", r1, r2),
+        Opcode::BitOpShiftL { r } => format!("rt.v[15] = rt.v[{}] >> 7; rt.v[{}] <<= 1;", r, r),
+        Opcode::BitOpShiftR { r } => format!("rt.v[15] = rt.v[{}] & 1; rt.v[{}] >>= 1;", r, r),
+        Opcode::CallSubroutine { address: target } => {
+            exit(format!("rt.call({:#05X}, {:#05X})?", address, target))
+        }
+        Opcode::CondEq { r, value } => exit(format!(
+            "if rt.v[{}] == {:#04X} {{ {:#05X} }} else {{ {:#05X} }}",
+            r,
+            value,
+            address + 4,
+            address + 2
+        )),
+        Opcode::CondNe { r, value } => exit(format!(
+            "if rt.v[{}] != {:#04X} {{ {:#05X} }} else {{ {:#05X} }}",
+            r,
+            value,
+            address + 4,
+            address + 2
+        )),
+        Opcode::CondVxVyEq { r1, r2 } => exit(format!(
+            "if rt.v[{}] == rt.v[{}] {{ {:#05X} }} else {{ {:#05X} }}",
+            r1,
+            r2,
+            address + 4,
+            address + 2
+        )),
+        Opcode::CondVxVyNe { r1, r2 } => exit(format!(
+            "if rt.v[{}] != rt.v[{}] {{ {:#05X} }} else {{ {:#05X} }}",
+            r1,
+            r2,
+            address + 4,
+            address + 2
+        )),
+        Opcode::GetDelayTimer { r } => format!("rt.v[{}] = rt.delay_timer();", r),
+        Opcode::Goto { address: target } => exit(format!("{:#05X}", target)),
+        Opcode::Increment { r1, r2 } => format!(
+            "let (value, carry) = rt.v[{}].overflowing_add(rt.v[{}]); \
+             rt.v[{}] = value; rt.v[15] = carry as u8;",
+            r1, r2, r1
+        ),
+        Opcode::LoadRegisters { r } => format!("rt.load_registers({:#05X}, {})?;", address, r),
+        Opcode::Return => exit(format!("rt.ret({:#05X})?", address)),
+        Opcode::Set { r, value } => format!("rt.v[{}] = {:#04X};", r, value),
+        Opcode::SetAddress { value } => format!("rt.i = {:#05X};", value),
+        Opcode::SetBCD { r } => format!("rt.store_bcd({:#05X}, {})?;", address, r),
+        Opcode::SetDelayTimer { r } => format!("rt.set_delay_timer(rt.v[{}]);", r),
+        Opcode::SetSprite { r } => format!("rt.i = u16::from(rt.v[{}]) * 5;", r),
+        Opcode::StoreRegisters { r } => {
+            format!("rt.store_registers({:#05X}, {})?;", address, r)
+        }
+        Opcode::Sub { r1, r2 } => format!(
+            "let (x, y) = (rt.v[{}], rt.v[{}]); rt.v[15] = (x > y) as u8; \
+             rt.v[{}] = x.wrapping_sub(y);",
+            r1, r2, r1
+        ),
+        Opcode::SubVyVx { r1, r2 } => format!(
+            "let (x, y) = (rt.v[{}], rt.v[{}]); rt.v[15] = (y > x) as u8; \
+             rt.v[{}] = y.wrapping_sub(x);",
+            r1, r2, r1
+        ),
+        // Display, keypad, random numbers, sound and computed jumps
+        _ if cfg::successors(opcode, address).is_some() => {
+            exit(format!("rt.interpret({:#05X})?", address))
+        }
+        _ => format!("rt.interpret({:#05X})?;", address),
+    }
+}
+
+// Straight-line code from the start of a block, going on through the blocks it falls into,
+// so that a function runs as far as the next branch
+fn straight_line(cfg: &Cfg, start: usize) -> (Vec<&Instruction>, usize) {
+    let mut instructions = Vec::new();
+    let mut block = &cfg.blocks[&start];
+
+    loop {
+        instructions.extend(&block.instructions);
+
+        let last = &block.instructions[block.instructions.len() - 1];
+        match cfg.blocks.get(&block.end) {
+            Some(next) if cfg::successors(&last.opcode, last.address).is_none() => block = next,
+            _ => return (instructions, block.end),
+        }
+    }
+}
+
+fn write_block(output: &mut String, cfg: &Cfg, start: usize) {
+    let (instructions, end) = straight_line(cfg, start);
+
+    writeln!(
+        output,
+        "fn block_{:03x}(rt: &mut Runtime) -> Result<(usize, usize), Chip8Error> {{",
+        start
+    )
+    .unwrap();
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        let Instruction { address, opcode } = instruction;
+        writeln!(
+            output,
+            "    {} // {:03X}: {}",
+            translate(opcode, *address, i + 1),
+            address,
+            opcode
+        )
+        .unwrap();
+
+        // The rest of the block may have been overwritten
+        if matches!(
+            opcode,
+            Opcode::StoreRegisters { .. } | Opcode::SetBCD { .. }
+        ) {
+            writeln!(
+                output,
+                "    if rt.code_modified() {{ return Ok(({:#05X}, {})); }}",
+                address + 2,
+                i + 1
+            )
+            .unwrap();
+        }
+    }
+
+    // The ROM ends without a jump, the dispatcher goes on with the interpreter
+    let last = instructions[instructions.len() - 1];
+    if cfg::successors(&last.opcode, last.address).is_none() {
+        writeln!(output, "    Ok(({:#05X}, {}))", end, instructions.len()).unwrap();
+    }
+
+    writeln!(output, "}}\n").unwrap();
+}
+
+fn generate(options: &RecompileOptions, rom: &[u8], cfg: &Cfg) -> String {
+    let mut output = String::new();
+
+    writeln!(
+        output,
+        "// Recompiled from {} by chip8 recompile",
+        options.rom_path
+    )
+    .unwrap();
+    writeln!(
+        output,
+        "//
+// The blocks found by following the control flow from 0x200 are Rust functions, the rest
+// runs in the interpreter. Built as a binary of the chip8 crate, which provides the emulator
+// sources, included from {}, and their dependencies, for instance in src/bin:
+//     cargo run --release --features recompiled --bin <name> [<frames>] [<ipf>]
+// It prints the screen once the frames have run, no key being pressed.",
+        options.sources_path
+    )
+    .unwrap();
+    output.push_str(
+        "#![allow(dead_code, unused_variables)]
+
+#[macro_use]
+extern crate num_derive;
+
+",
+    );
+    writeln!(
+        output,
+        "#[path = \"{}\"]\nmod chip8;\n",
+        options.sources_path.escape_default()
+    )
+    .unwrap();
+    output.push_str(
+        "use chip8::error::Chip8Error;
+use chip8::runtime::Runtime;
+use std::env;
+use std::process;
+
+",
+    );
+
+    writeln!(output, "const ROM: &[u8] = &[").unwrap();
+    for chunk in rom.chunks(16) {
+        let bytes: Vec<String> = chunk.iter().map(|b| format!("{:#04X}", b)).collect();
+        writeln!(output, "    {},", bytes.join(", ")).unwrap();
+    }
+    writeln!(output, "];\n").unwrap();
+
+    writeln!(output, "const CODE: &[(usize, usize)] = &[").unwrap();
+    for (start, end) in cfg.code_ranges() {
+        writeln!(output, "    ({:#05X}, {:#05X}),", start, end).unwrap();
+    }
+    writeln!(output, "];\n").unwrap();
+
+    for &start in cfg.blocks.keys() {
+        write_block(&mut output, cfg, start);
+    }
+
+    writeln!(
+        output,
+        "fn run_block(rt: &mut Runtime, address: usize) -> Result<(usize, usize), Chip8Error> {{"
+    )
+    .unwrap();
+    writeln!(output, "    match address {{").unwrap();
+    for &start in cfg.blocks.keys() {
+        writeln!(output, "        {:#05X} => block_{:03x}(rt),", start, start).unwrap();
+    }
+    writeln!(output, "        _ => Ok((rt.interpret(address)?, 1)),").unwrap();
+    writeln!(output, "    }}\n}}\n").unwrap();
+
+    output.push_str(
+        "fn main() {
+    let mut args = env::args().skip(1).map(|arg| arg.parse().ok());
+    let frames = args.next().flatten().unwrap_or(600);
+    let instructions_per_frame = args.next().flatten().unwrap_or(12) as usize;
+
+    let mut rt = Runtime::new(ROM.to_vec(), CODE);
+    if let Err(e) = rt.run(frames, instructions_per_frame, run_block) {
+        eprintln!(\"{}\", e);
+        process::exit(1);
+    }
+
+    print!(\"{}\", rt.screen());
+}
+",
+    );
+
+    output
+}
+
+pub fn run(options: &RecompileOptions) -> Result<(), Box<dyn Error>> {
+    let rom = fs::read(&options.rom_path).map_err(|e| format!("Failed to open file: {}", e))?;
+
+    let cfg = Cfg::new(&rom);
+
+    fs::write(&options.output_path, generate(options, &rom, &cfg))
+        .map_err(|e| format!("Failed to write {}: {}", options.output_path, e))?;
+
+    println!(
+        "Recompiled {} blocks ({} instructions) to {}",
+        cfg.blocks.len(),
+        cfg.instruction_count(),
+        options.output_path
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture;
+    use crate::chip8::state::{Chip8Options, Chip8State};
+    use std::env;
+    use std::path::Path;
+    use std::process::Command;
+
+    const FRAMES: u64 = 300;
+    const INSTRUCTIONS_PER_FRAME: usize = 12;
+
+    // Dependencies of the emulator sources, taken from the crate manifest
+    const SOURCE_DEPENDENCIES: &[&str] = &["libc", "rand", "num", "num-derive", "num-traits"];
+
+    fn interpreter_screen(rom: Vec<u8>) -> String {
+        let mut state = Chip8State::with_options(rom, Chip8Options::default());
+        for _ in 0..FRAMES {
+            for _ in 0..INSTRUCTIONS_PER_FRAME {
+                if state.is_waiting_for_key() {
+                    break;
+                }
+                state.tick().unwrap();
+            }
+            state.tick_timers();
+        }
+
+        capture::grid_to_ascii(&state.grid)
+    }
+
+    // Builds the program in its own crate, next to the build directory of this one
+    fn recompiled_screen(rom_path: &Path) -> String {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let project = root.join("target").join("recompile-test");
+        fs::create_dir_all(project.join("src")).unwrap();
+
+        let manifest = fs::read_to_string(root.join("Cargo.toml")).unwrap();
+        let dependencies: Vec<&str> = manifest
+            .lines()
+            .filter(|line| {
+                SOURCE_DEPENDENCIES
+                    .iter()
+                    .any(|name| line.split('=').next().unwrap().trim() == *name)
+            })
+            .collect();
+        fs::write(
+            project.join("Cargo.toml"),
+            format!(
+                "[package]\nname = \"recompile-test\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\n\
+                 [features]\ndefault = [\"recompiled\"]\nrecompiled = []\n\n\
+                 [dependencies]\n{}\n\n[workspace]\n",
+                dependencies.join("\n")
+            ),
+        )
+        .unwrap();
+        if let Ok(lock) = fs::read(root.join("Cargo.lock")) {
+            fs::write(project.join("Cargo.lock"), lock).unwrap();
+        }
+
+        let options = RecompileOptions {
+            rom_path: rom_path.to_str().unwrap().to_owned(),
+            output_path: project
+                .join("src")
+                .join("main.rs")
+                .to_str()
+                .unwrap()
+                .to_owned(),
+            sources_path: root
+                .join("src")
+                .join("chip8")
+                .join("mod.rs")
+                .to_str()
+                .unwrap()
+                .to_owned(),
+        };
+        run(&options).unwrap();
+
+        let output = Command::new(env::var("CARGO").unwrap_or_else(|_| String::from("cargo")))
+            .args(["run", "--quiet", "--"])
+            .arg(FRAMES.to_string())
+            .arg(INSTRUCTIONS_PER_FRAME.to_string())
+            .current_dir(&project)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn recompiled_rom_draws_the_interpreter_screen() {
+        let rom_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("roms")
+            .join("BC_test.ch8");
+
+        let expected = interpreter_screen(fs::read(&rom_path).unwrap());
+        assert!(expected.contains('#'));
+
+        assert_eq!(recompiled_screen(&rom_path), expected);
+    }
+}
@@ -0,0 +1,167 @@
+// Expands the display grid into a framebuffer using the largest integer scale fitting
+// the output, centered with black borders
+#[derive(Default)]
+pub struct Scaler {
+    output_width: usize,
+    output_height: usize,
+    grid_width: usize,
+    grid_height: usize,
+    scale: usize,
+    offset_x: usize,
+    offset_y: usize,
+    buffer: Vec<u32>,
+    row: Vec<u32>,
+}
+
+impl Scaler {
+    // Returns true if the layout changed, in which case the buffer must be rendered again
+    pub fn set_layout(
+        &mut self,
+        output_width: usize,
+        output_height: usize,
+        grid_width: usize,
+        grid_height: usize,
+    ) -> bool {
+        if (output_width, output_height, grid_width, grid_height)
+            == (
+                self.output_width,
+                self.output_height,
+                self.grid_width,
+                self.grid_height,
+            )
+        {
+            return false;
+        }
+
+        self.output_width = output_width;
+        self.output_height = output_height;
+        self.grid_width = grid_width;
+        self.grid_height = grid_height;
+
+        self.scale = if grid_width > 0 && grid_height > 0 {
+            (output_width / grid_width).min(output_height / grid_height)
+        } else {
+            0
+        };
+
+        self.offset_x = (output_width - grid_width * self.scale) / 2;
+        self.offset_y = (output_height - grid_height * self.scale) / 2;
+
+        self.buffer.clear();
+        self.buffer.resize(output_width * output_height, 0);
+        self.row.resize(grid_width * self.scale, 0);
+
+        true
+    }
+
+    pub fn output_size(&self) -> (usize, usize) {
+        (self.output_width, self.output_height)
+    }
+
+    pub fn buffer(&self) -> &[u32] {
+        &self.buffer
+    }
+
+    pub fn buffer_mut(&mut self) -> &mut [u32] {
+        &mut self.buffer
+    }
+
+    // cell_color gives the color of a cell from its index, line_filter can alter the color
+    // of every pixel depending on the output line it is drawn on
+    pub fn render<C, F>(&mut self, cell_color: C, line_filter: F)
+    where
+        C: Fn(usize) -> u32,
+        F: Fn(u32, usize) -> u32,
+    {
+        let scale = self.scale;
+        if scale == 0 {
+            return;
+        }
+
+        for cell_y in 0..self.grid_height {
+            // Cells are expanded horizontally once, then the row is copied scale times
+            for cell_x in 0..self.grid_width {
+                let color = cell_color(cell_y * self.grid_width + cell_x);
+                for pixel in &mut self.row[cell_x * scale..(cell_x + 1) * scale] {
+                    *pixel = color;
+                }
+            }
+
+            for line in 0..scale {
+                let y = cell_y * scale + line;
+                let start = (self.offset_y + y) * self.output_width + self.offset_x;
+
+                for (pixel, &color) in self.buffer[start..start + self.row.len()]
+                    .iter_mut()
+                    .zip(&self.row)
+                {
+                    *pixel = line_filter(color, y);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(output_width: usize, output_height: usize) -> (usize, usize, usize) {
+        let mut scaler = Scaler::default();
+        scaler.set_layout(output_width, output_height, 64, 32);
+        assert_eq!(scaler.buffer().len(), output_width * output_height);
+        (scaler.scale, scaler.offset_x, scaler.offset_y)
+    }
+
+    #[test]
+    fn largest_integer_scale_is_centered() {
+        assert_eq!(layout(640, 320), (10, 0, 0));
+        assert_eq!(layout(700, 400), (10, 30, 40));
+        // Limited by the width, letterboxed vertically
+        assert_eq!(layout(128, 200), (2, 0, 68));
+        assert_eq!(layout(129, 65), (2, 0, 0));
+        assert_eq!(layout(64, 32), (1, 0, 0));
+    }
+
+    #[test]
+    fn outputs_smaller_than_the_grid_stay_blank() {
+        for (width, height) in [(0, 0), (63, 32), (64, 31), (1, 1)] {
+            let mut scaler = Scaler::default();
+            scaler.set_layout(width, height, 64, 32);
+            scaler.render(|_| 0xFFFFFF, |color, _| color);
+
+            assert_eq!(scaler.scale, 0);
+            assert!(scaler.buffer().iter().all(|&pixel| pixel == 0));
+        }
+    }
+
+    #[test]
+    fn layout_changes_are_reported() {
+        let mut scaler = Scaler::default();
+
+        assert!(scaler.set_layout(640, 320, 64, 32));
+        assert!(!scaler.set_layout(640, 320, 64, 32));
+        assert!(scaler.set_layout(640, 320, 128, 64));
+        assert_eq!(scaler.scale, 5);
+        assert_eq!(scaler.output_size(), (640, 320));
+    }
+
+    #[test]
+    fn cells_are_expanded_inside_the_borders() {
+        let mut scaler = Scaler::default();
+        // 4x2 cells scaled 2 times in 10x6, one pixel of border all around
+        scaler.set_layout(10, 6, 4, 2);
+        scaler.render(|index| index as u32 + 1, |color, y| color | (y as u32) << 8);
+
+        #[rustfmt::skip]
+        let expected = [
+            0, 0x001, 0x001, 0x002, 0x002, 0x003, 0x003, 0x004, 0x004, 0,
+            0, 0x101, 0x101, 0x102, 0x102, 0x103, 0x103, 0x104, 0x104, 0,
+            0, 0x205, 0x205, 0x206, 0x206, 0x207, 0x207, 0x208, 0x208, 0,
+            0, 0x305, 0x305, 0x306, 0x306, 0x307, 0x307, 0x308, 0x308, 0,
+        ];
+        assert!(scaler.buffer()[..10].iter().all(|&pixel| pixel == 0));
+        assert_eq!(scaler.buffer()[10..50], expected);
+        assert!(scaler.buffer()[50..].iter().all(|&pixel| pixel == 0));
+    }
+}
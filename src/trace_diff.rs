@@ -0,0 +1,241 @@
+use crate::chip8::trace::{self, TraceEntry};
+use crate::options::TraceDiffOptions;
+use std::error::Error;
+
+fn apply_changes(registers: &mut [u8; 16], entry: &TraceEntry) {
+    for change in &entry.register_changes {
+        registers[change.register as usize] = change.value;
+    }
+}
+
+fn format_writes(writes: &[(usize, u8)]) -> String {
+    if writes.is_empty() {
+        return String::from("none");
+    }
+
+    let writes: Vec<String> = writes
+        .iter()
+        .map(|(address, value)| format!("[{:03X}]={:02X}", address, value))
+        .collect();
+    writes.join(" ")
+}
+
+// Differences after an instruction, registers being rebuilt from the changes since the start
+fn compare(
+    entry: &TraceEntry,
+    registers: &[u8; 16],
+    reference: &TraceEntry,
+    reference_registers: &[u8; 16],
+) -> Vec<String> {
+    let mut differences = Vec::new();
+
+    if entry.address != reference.address {
+        differences.push(format!(
+            "PC: {:03X}, reference {:03X}",
+            entry.address, reference.address
+        ));
+    }
+
+    if entry.opcode != reference.opcode {
+        differences.push(format!(
+            "opcode: {:04X}, reference {:04X}",
+            entry.opcode, reference.opcode
+        ));
+    }
+
+    if entry.index_register != reference.index_register {
+        differences.push(format!(
+            "I: {:03X}, reference {:03X}",
+            entry.index_register, reference.index_register
+        ));
+    }
+
+    for (index, (value, reference_value)) in registers.iter().zip(reference_registers).enumerate() {
+        if value != reference_value {
+            differences.push(format!(
+                "V{:X}: {:02X}, reference {:02X}",
+                index, value, reference_value
+            ));
+        }
+    }
+
+    let mut writes = entry.memory_writes.clone();
+    let mut reference_writes = reference.memory_writes.clone();
+    writes.sort_unstable();
+    reference_writes.sort_unstable();
+    if writes != reference_writes {
+        differences.push(format!(
+            "memory writes: {}, reference {}",
+            format_writes(&writes),
+            format_writes(&reference_writes)
+        ));
+    }
+
+    differences
+}
+
+fn print_context(name: &str, entries: &[TraceEntry], index: usize, context: usize) {
+    println!("{}:", name);
+    for (line_index, entry) in entries
+        .iter()
+        .enumerate()
+        .take(index + 1)
+        .skip(index.saturating_sub(context))
+    {
+        let marker = if line_index == index { '>' } else { ' ' };
+        println!("{} {:8} {}", marker, line_index + 1, entry);
+    }
+}
+
+// Index of the first instruction which differs and the differences, over the common length
+fn first_divergence(
+    entries: &[TraceEntry],
+    reference_entries: &[TraceEntry],
+) -> Option<(usize, Vec<String>)> {
+    let mut registers = [0u8; 16];
+    let mut reference_registers = [0u8; 16];
+
+    for (index, (entry, reference)) in entries.iter().zip(reference_entries).enumerate() {
+        apply_changes(&mut registers, entry);
+        apply_changes(&mut reference_registers, reference);
+
+        let differences = compare(entry, &registers, reference, &reference_registers);
+        if !differences.is_empty() {
+            return Some((index, differences));
+        }
+    }
+
+    None
+}
+
+// Traces are aligned by instruction count, so they must not be filtered
+pub fn run(options: &TraceDiffOptions) -> Result<(), Box<dyn Error>> {
+    let entries = trace::load_trace(&options.trace_path)?;
+    let reference_entries = trace::load_trace(&options.reference_path)?;
+
+    if let Some((index, differences)) = first_divergence(&entries, &reference_entries) {
+        println!("Traces diverge at instruction {}:", index + 1);
+        for difference in &differences {
+            println!("    {}", difference);
+        }
+        println!();
+        print_context(&options.trace_path, &entries, index, options.context);
+        print_context(
+            &options.reference_path,
+            &reference_entries,
+            index,
+            options.context,
+        );
+
+        return Err("The traces diverge".into());
+    }
+
+    let count = entries.len().min(reference_entries.len());
+    if entries.len() == reference_entries.len() {
+        println!("Traces match ({} instructions)", count);
+        return Ok(());
+    }
+
+    let (shorter, longer) = if entries.len() < reference_entries.len() {
+        (&options.trace_path, &options.reference_path)
+    } else {
+        (&options.reference_path, &options.trace_path)
+    };
+    println!(
+        "Traces match for {} instructions, then {} ends while {} goes on",
+        count, shorter, longer
+    );
+
+    Err("The traces have different lengths".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(lines: &[&str]) -> Vec<TraceEntry> {
+        lines
+            .iter()
+            .map(|line| TraceEntry::parse(line).unwrap())
+            .collect()
+    }
+
+    const REFERENCE: &[&str] = &[
+        "0 200 6012 I=000 V0:00>12",
+        "0 202 A300 I=300",
+        "0 204 F055 I=300 [300]=12",
+        "1 206 7001 I=300 V0:12>13",
+    ];
+
+    #[test]
+    fn identical_traces_do_not_diverge() {
+        assert_eq!(first_divergence(&trace(REFERENCE), &trace(REFERENCE)), None);
+    }
+
+    #[test]
+    fn register_mismatches_are_reported() {
+        let mut lines = REFERENCE.to_vec();
+        lines[0] = "0 200 6012 I=000 V0:00>13";
+        lines[3] = "1 206 7001 I=300 V0:13>14";
+
+        assert_eq!(
+            first_divergence(&trace(&lines), &trace(REFERENCE)),
+            Some((0, vec![String::from("V0: 13, reference 12")]))
+        );
+    }
+
+    #[test]
+    fn registers_are_rebuilt_from_the_changes() {
+        // The same final value, reached by a different instruction
+        let mut lines = REFERENCE.to_vec();
+        lines[0] = "0 200 6012 I=000";
+        lines[3] = "1 206 7001 I=300 V0:00>13";
+
+        assert_eq!(
+            first_divergence(&trace(&lines), &trace(REFERENCE)),
+            Some((0, vec![String::from("V0: 00, reference 12")]))
+        );
+    }
+
+    #[test]
+    fn memory_mismatches_are_reported() {
+        let mut lines = REFERENCE.to_vec();
+        lines[2] = "0 204 F055 I=300 [300]=13";
+
+        assert_eq!(
+            first_divergence(&trace(&lines), &trace(REFERENCE)),
+            Some((
+                2,
+                vec![String::from("memory writes: [300]=13, reference [300]=12")]
+            ))
+        );
+
+        lines[2] = "0 204 F055 I=300";
+        assert_eq!(
+            first_divergence(&trace(&lines), &trace(REFERENCE)),
+            Some((
+                2,
+                vec![String::from("memory writes: none, reference [300]=12")]
+            ))
+        );
+    }
+
+    #[test]
+    fn pc_mismatches_are_reported() {
+        let mut lines = REFERENCE.to_vec();
+        lines[3] = "1 208 7001 I=300 V0:12>13";
+
+        assert_eq!(
+            first_divergence(&trace(&lines), &trace(REFERENCE)),
+            Some((3, vec![String::from("PC: 208, reference 206")]))
+        );
+    }
+
+    #[test]
+    fn traces_are_compared_over_their_common_length() {
+        assert_eq!(
+            first_divergence(&trace(&REFERENCE[..2]), &trace(REFERENCE)),
+            None
+        );
+    }
+}
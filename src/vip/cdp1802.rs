@@ -0,0 +1,558 @@
+// RCA CDP1802 CPU. Timings are in machine cycles (8 clock cycles), most instructions take 2
+// of them and long branches and skips take 3.
+
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    // Ports 1 to 7, selected by the N lines
+    fn input(&mut self, port: u8) -> u8;
+    fn output(&mut self, port: u8, value: u8);
+}
+
+pub struct Cdp1802 {
+    pub r: [u16; 16],
+    pub p: u8,
+    pub x: u8,
+    pub d: u8,
+    pub df: bool,
+    pub t: u8,
+    pub ie: bool,
+    pub q: bool,
+    // EF1-EF4 input flags, true when asserted (the pins are active low)
+    pub ef: [bool; 4],
+    idle: bool,
+}
+
+impl Cdp1802 {
+    // State after a reset: X, P and R0 cleared, interrupts enabled
+    pub fn new() -> Cdp1802 {
+        Cdp1802 {
+            r: [0; 16],
+            p: 0,
+            x: 0,
+            d: 0,
+            df: false,
+            t: 0,
+            ie: true,
+            q: false,
+            ef: [false; 4],
+            idle: false,
+        }
+    }
+
+    pub fn program_counter(&self) -> u16 {
+        self.r[self.p as usize]
+    }
+
+    // Returns true if the interrupt was taken, which takes one machine cycle
+    pub fn interrupt(&mut self) -> bool {
+        if !self.ie {
+            return false;
+        }
+
+        self.t = (self.x << 4) | self.p;
+        self.x = 2;
+        self.p = 1;
+        self.ie = false;
+        self.idle = false;
+
+        true
+    }
+
+    // One DMA out cycle, reading the byte R0 points to
+    pub fn dma_out<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let value = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+
+        value
+    }
+
+    fn fetch<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let p = self.p as usize;
+        let value = bus.read(self.r[p]);
+        self.r[p] = self.r[p].wrapping_add(1);
+
+        value
+    }
+
+    fn rx(&self) -> u16 {
+        self.r[self.x as usize]
+    }
+
+    fn set_low(&mut self, register: usize, value: u8) {
+        self.r[register] = (self.r[register] & 0xFF00) | u16::from(value);
+    }
+
+    fn set_high(&mut self, register: usize, value: u8) {
+        self.r[register] = (self.r[register] & 0x00FF) | (u16::from(value) << 8);
+    }
+
+    // D = a + b + carry, DF set on carry. Subtractions add the complement.
+    fn add(&mut self, a: u8, b: u8, carry: bool) {
+        let result = u16::from(a) + u16::from(b) + carry as u16;
+        self.d = result as u8;
+        self.df = result > 0xFF;
+    }
+
+    fn short_branch<B: Bus>(&mut self, bus: &mut B, condition: bool) {
+        let target = self.fetch(bus);
+        if condition {
+            self.set_low(self.p as usize, target);
+        }
+    }
+
+    fn long_branch<B: Bus>(&mut self, bus: &mut B, condition: bool) {
+        let high = self.fetch(bus);
+        let low = self.fetch(bus);
+        if condition {
+            self.r[self.p as usize] = (u16::from(high) << 8) | u16::from(low);
+        }
+    }
+
+    fn long_skip(&mut self, condition: bool) {
+        if condition {
+            let p = self.p as usize;
+            self.r[p] = self.r[p].wrapping_add(2);
+        }
+    }
+
+    fn branch_condition(&self, n: u8) -> bool {
+        match n & 0x7 {
+            0 => true,
+            1 => self.q,
+            2 => self.d == 0,
+            3 => self.df,
+            flag => self.ef[(flag - 4) as usize],
+        }
+    }
+
+    // Runs one instruction and returns the machine cycles it took
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        if self.idle {
+            return 1;
+        }
+
+        let opcode = self.fetch(bus);
+        let n = opcode & 0x0F;
+        let register = n as usize;
+
+        match opcode >> 4 {
+            0x0 => {
+                if n == 0 {
+                    // IDL, until the next DMA or interrupt
+                    self.idle = true;
+                } else {
+                    self.d = bus.read(self.r[register]);
+                }
+            }
+            0x1 => self.r[register] = self.r[register].wrapping_add(1),
+            0x2 => self.r[register] = self.r[register].wrapping_sub(1),
+            0x3 => {
+                if n == 0x8 {
+                    // SKP
+                    let p = self.p as usize;
+                    self.r[p] = self.r[p].wrapping_add(1);
+                } else {
+                    let condition = self.branch_condition(n);
+                    self.short_branch(bus, condition == (n < 0x8));
+                }
+            }
+            0x4 => {
+                self.d = bus.read(self.r[register]);
+                self.r[register] = self.r[register].wrapping_add(1);
+            }
+            0x5 => bus.write(self.r[register], self.d),
+            0x6 => match n {
+                0x0 => self.r[self.x as usize] = self.rx().wrapping_add(1),
+                0x1..=0x7 => {
+                    let value = bus.read(self.rx());
+                    self.r[self.x as usize] = self.rx().wrapping_add(1);
+                    bus.output(n, value);
+                }
+                // Undefined on the 1802
+                0x8 => (),
+                _ => {
+                    let value = bus.input(n - 8);
+                    bus.write(self.rx(), value);
+                    self.d = value;
+                }
+            },
+            0x7 => match n {
+                // RET, DIS
+                0x0 | 0x1 => {
+                    let value = bus.read(self.rx());
+                    self.r[self.x as usize] = self.rx().wrapping_add(1);
+                    self.x = value >> 4;
+                    self.p = value & 0x0F;
+                    self.ie = n == 0x0;
+                }
+                // LDXA
+                0x2 => {
+                    self.d = bus.read(self.rx());
+                    self.r[self.x as usize] = self.rx().wrapping_add(1);
+                }
+                // STXD
+                0x3 => {
+                    bus.write(self.rx(), self.d);
+                    self.r[self.x as usize] = self.rx().wrapping_sub(1);
+                }
+                // ADC
+                0x4 => {
+                    let value = bus.read(self.rx());
+                    self.add(value, self.d, self.df);
+                }
+                // SDB
+                0x5 => {
+                    let value = bus.read(self.rx());
+                    self.add(value, !self.d, self.df);
+                }
+                // SHRC
+                0x6 => {
+                    let carry = self.d & 0x01 != 0;
+                    self.d = (self.d >> 1) | ((self.df as u8) << 7);
+                    self.df = carry;
+                }
+                // SMB
+                0x7 => {
+                    let value = bus.read(self.rx());
+                    self.add(self.d, !value, self.df);
+                }
+                // SAV
+                0x8 => bus.write(self.rx(), self.t),
+                // MARK
+                0x9 => {
+                    self.t = (self.x << 4) | self.p;
+                    bus.write(self.r[2], self.t);
+                    self.x = self.p;
+                    self.r[2] = self.r[2].wrapping_sub(1);
+                }
+                // REQ, SEQ
+                0xA => self.q = false,
+                0xB => self.q = true,
+                // ADCI
+                0xC => {
+                    let value = self.fetch(bus);
+                    self.add(value, self.d, self.df);
+                }
+                // SDBI
+                0xD => {
+                    let value = self.fetch(bus);
+                    self.add(value, !self.d, self.df);
+                }
+                // SHLC
+                0xE => {
+                    let carry = self.d & 0x80 != 0;
+                    self.d = (self.d << 1) | self.df as u8;
+                    self.df = carry;
+                }
+                // SMBI
+                _ => {
+                    let value = self.fetch(bus);
+                    self.add(self.d, !value, self.df);
+                }
+            },
+            0x8 => self.d = self.r[register] as u8,
+            0x9 => self.d = (self.r[register] >> 8) as u8,
+            0xA => self.set_low(register, self.d),
+            0xB => self.set_high(register, self.d),
+            0xC => {
+                match n {
+                    // NOP
+                    0x4 => (),
+                    // LSNQ, LSNZ, LSNF
+                    0x5..=0x7 => {
+                        let condition = self.branch_condition(n - 0x4);
+                        self.long_skip(!condition);
+                    }
+                    // LSKP
+                    0x8 => self.long_skip(true),
+                    // LSIE
+                    0xC => self.long_skip(self.ie),
+                    // LSQ, LSZ, LSDF
+                    0xD..=0xF => {
+                        let condition = self.branch_condition(n - 0xC);
+                        self.long_skip(condition);
+                    }
+                    // LBR, LBQ, LBZ, LBDF and their negations
+                    _ => {
+                        let condition = self.branch_condition(n);
+                        self.long_branch(bus, condition == (n < 0x8));
+                    }
+                }
+
+                return 3;
+            }
+            0xD => self.p = n,
+            0xE => self.x = n,
+            _ => match n {
+                // SHR
+                0x6 => {
+                    self.df = self.d & 0x01 != 0;
+                    self.d >>= 1;
+                }
+                // SHL
+                0xE => {
+                    self.df = self.d & 0x80 != 0;
+                    self.d <<= 1;
+                }
+                _ => {
+                    // Immediate variants read their operand after the opcode
+                    let value = if n >= 0x8 {
+                        self.fetch(bus)
+                    } else {
+                        bus.read(self.rx())
+                    };
+
+                    match n & 0x7 {
+                        // LDX, LDI
+                        0x0 => self.d = value,
+                        // OR, ORI
+                        0x1 => self.d |= value,
+                        // AND, ANI
+                        0x2 => self.d &= value,
+                        // XOR, XRI
+                        0x3 => self.d ^= value,
+                        // ADD, ADI
+                        0x4 => self.add(value, self.d, false),
+                        // SD, SDI
+                        0x5 => self.add(value, !self.d, true),
+                        // SM, SMI
+                        _ => self.add(self.d, !value, true),
+                    }
+                }
+            },
+        }
+
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Memory {
+        bytes: Vec<u8>,
+    }
+
+    impl Bus for Memory {
+        fn read(&mut self, address: u16) -> u8 {
+            self.bytes[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.bytes[address as usize] = value;
+        }
+
+        fn input(&mut self, _port: u8) -> u8 {
+            0
+        }
+
+        fn output(&mut self, _port: u8, _value: u8) {}
+    }
+
+    // Program at 0x0000 run with P = 0, X = 1 pointing to a scratch byte at 0x0100
+    fn setup(program: &[u8]) -> (Cdp1802, Memory) {
+        let mut bytes = vec![0; 0x10000];
+        bytes[..program.len()].copy_from_slice(program);
+
+        let mut cpu = Cdp1802::new();
+        cpu.x = 1;
+        cpu.r[1] = 0x0100;
+
+        (cpu, Memory { bytes })
+    }
+
+    fn run(cpu: &mut Cdp1802, memory: &mut Memory, steps: usize) -> u32 {
+        (0..steps).map(|_| cpu.step(memory)).sum()
+    }
+
+    #[test]
+    fn add_sets_the_carry() {
+        // LDI 0xF0, ADI 0x20
+        let (mut cpu, mut memory) = setup(&[0xF8, 0xF0, 0xFC, 0x20]);
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x10, true));
+
+        // LDI 0x10, ADI 0x20
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x10, 0xFC, 0x20]);
+        cpu.df = true;
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x30, false));
+    }
+
+    #[test]
+    fn add_with_carry_adds_df() {
+        // LDI 0xFF, ADCI 0x00
+        let (mut cpu, mut memory) = setup(&[0xF8, 0xFF, 0x7C, 0x00]);
+        cpu.df = true;
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x00, true));
+
+        // LDI 0x01, ADC with M(R1) = 0x02
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x01, 0x74]);
+        memory.bytes[0x0100] = 0x02;
+        cpu.df = true;
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x04, false));
+    }
+
+    #[test]
+    fn subtractions_clear_df_on_borrow() {
+        // LDI 0x05, SMI 0x03: D - immediate
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x05, 0xFF, 0x03]);
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x02, true));
+
+        // LDI 0x03, SMI 0x05
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x03, 0xFF, 0x05]);
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0xFE, false));
+
+        // LDI 0x03, SD with M(R1) = 0x05: memory - D
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x03, 0xF5]);
+        memory.bytes[0x0100] = 0x05;
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x02, true));
+
+        // LDI 0x05, SDI 0x03
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x05, 0xFD, 0x03]);
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0xFE, false));
+    }
+
+    #[test]
+    fn subtractions_with_borrow_use_df() {
+        // LDI 0x05, SMBI 0x03 with a pending borrow
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x05, 0x7F, 0x03]);
+        cpu.df = false;
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x01, true));
+
+        // LDI 0x05, SDBI 0x05 with a pending borrow
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x05, 0x7D, 0x05]);
+        cpu.df = false;
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0xFF, false));
+
+        // LDI 0x05, SMB with M(R1) = 0x03 and no borrow
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x05, 0x77]);
+        memory.bytes[0x0100] = 0x03;
+        cpu.df = true;
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x02, true));
+    }
+
+    #[test]
+    fn shifts_move_bits_through_df() {
+        // LDI 0x81, SHL
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x81, 0xFE]);
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x02, true));
+
+        // LDI 0x81, SHR
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x81, 0xF6]);
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x40, true));
+
+        // LDI 0x80, SHLC, SHLC
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x80, 0x7E, 0x7E]);
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x00, true));
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.d, cpu.df), (0x01, false));
+
+        // LDI 0x01, SHRC, SHRC
+        let (mut cpu, mut memory) = setup(&[0xF8, 0x01, 0x76, 0x76]);
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x00, true));
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.d, cpu.df), (0x80, false));
+    }
+
+    #[test]
+    fn short_branches_stay_in_the_page() {
+        // BR 0x10
+        let (mut cpu, mut memory) = setup(&[0x30, 0x10]);
+        assert_eq!(run(&mut cpu, &mut memory, 1), 2);
+        assert_eq!(cpu.program_counter(), 0x0010);
+
+        // BZ 0x10 taken with D = 0, BNZ 0x10 not taken
+        let (mut cpu, mut memory) = setup(&[0x32, 0x10]);
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.program_counter(), 0x0010);
+        let (mut cpu, mut memory) = setup(&[0x3A, 0x10]);
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.program_counter(), 0x0002);
+
+        // BDF and BNF
+        let (mut cpu, mut memory) = setup(&[0x33, 0x10]);
+        cpu.df = true;
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.program_counter(), 0x0010);
+        let (mut cpu, mut memory) = setup(&[0x3B, 0x10]);
+        cpu.df = true;
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.program_counter(), 0x0002);
+
+        // B3 follows EF3, the VIP key flag
+        let (mut cpu, mut memory) = setup(&[0x36, 0x10]);
+        cpu.ef[2] = true;
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.program_counter(), 0x0010);
+
+        // A short branch whose operand is the last byte of a page lands in the next one
+        let (mut cpu, mut memory) = setup(&[]);
+        memory.bytes[0x01FE] = 0x30;
+        memory.bytes[0x01FF] = 0x10;
+        cpu.r[0] = 0x01FE;
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.program_counter(), 0x0210);
+    }
+
+    #[test]
+    fn long_branches_and_skips_take_three_cycles() {
+        // LBR 0x1234
+        let (mut cpu, mut memory) = setup(&[0xC0, 0x12, 0x34]);
+        assert_eq!(run(&mut cpu, &mut memory, 1), 3);
+        assert_eq!(cpu.program_counter(), 0x1234);
+
+        // LBNZ 0x1234 not taken with D = 0
+        let (mut cpu, mut memory) = setup(&[0xCA, 0x12, 0x34]);
+        assert_eq!(run(&mut cpu, &mut memory, 1), 3);
+        assert_eq!(cpu.program_counter(), 0x0003);
+
+        // LBQ taken after SEQ
+        let (mut cpu, mut memory) = setup(&[0x7B, 0xC1, 0x12, 0x34]);
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!(cpu.program_counter(), 0x1234);
+
+        // LSZ skips two bytes, LSNZ does not with D = 0
+        let (mut cpu, mut memory) = setup(&[0xCE]);
+        assert_eq!(run(&mut cpu, &mut memory, 1), 3);
+        assert_eq!(cpu.program_counter(), 0x0003);
+        let (mut cpu, mut memory) = setup(&[0xC6]);
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.program_counter(), 0x0001);
+
+        // LSDF and LSNF
+        let (mut cpu, mut memory) = setup(&[0xCF]);
+        cpu.df = true;
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.program_counter(), 0x0003);
+        let (mut cpu, mut memory) = setup(&[0xC7]);
+        cpu.df = true;
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.program_counter(), 0x0001);
+    }
+
+    #[test]
+    fn sep_switches_the_program_counter_register() {
+        // SEP R3
+        let (mut cpu, mut memory) = setup(&[0xD3]);
+        cpu.r[3] = 0x0200;
+        run(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.p, cpu.program_counter()), (3, 0x0200));
+    }
+}
@@ -0,0 +1,91 @@
+// RCA CDP1861 video chip, as wired on the COSMAC VIP. Each frame is 262 lines of 14 machine
+// cycles. The 128 displayed lines are fetched from memory by 8 DMA out cycles each, the
+// interrupt routine of the VIP resets R0 between lines to repeat each row of pixels.
+
+pub const CYCLES_PER_LINE: u32 = 14;
+pub const LINES_PER_FRAME: u32 = 262;
+pub const CYCLES_PER_FRAME: u32 = CYCLES_PER_LINE * LINES_PER_FRAME;
+
+pub const DISPLAY_WIDTH: usize = 64;
+pub const DISPLAY_LINES: usize = 128;
+pub const BYTES_PER_LINE: usize = DISPLAY_WIDTH / 8;
+
+const FIRST_DISPLAY_LINE: u32 = 64;
+const FIRST_DMA_CYCLE: u32 = FIRST_DISPLAY_LINE * CYCLES_PER_LINE;
+
+// The interrupt is requested 29 cycles before the first DMA, leaving the interrupt routine
+// time to set R0
+const INTERRUPT_CYCLE: u32 = FIRST_DMA_CYCLE - 29;
+
+pub struct Cdp1861 {
+    enabled: bool,
+    frame: Vec<u8>,
+    next_line: usize,
+}
+
+impl Cdp1861 {
+    pub fn new() -> Cdp1861 {
+        Cdp1861 {
+            enabled: false,
+            frame: vec![0; BYTES_PER_LINE * DISPLAY_LINES],
+            next_line: 0,
+        }
+    }
+
+    // Enabled by an input on port 1, disabled by an output on port 1
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn start_frame(&mut self) {
+        self.next_line = 0;
+
+        if !self.enabled {
+            for byte in &mut self.frame {
+                *byte = 0;
+            }
+        }
+    }
+
+    pub fn interrupt_requested(&self, cycle: u32) -> bool {
+        self.enabled && (INTERRUPT_CYCLE..FIRST_DMA_CYCLE).contains(&cycle)
+    }
+
+    // EF1 is asserted during the 4 lines before the display and the last 4 displayed lines
+    pub fn efx(&self, cycle: u32) -> bool {
+        let line = cycle / CYCLES_PER_LINE;
+        let last_line = FIRST_DISPLAY_LINE + DISPLAY_LINES as u32;
+
+        self.enabled
+            && ((FIRST_DISPLAY_LINE - 4..FIRST_DISPLAY_LINE).contains(&line)
+                || (last_line - 4..last_line).contains(&line))
+    }
+
+    // DMA starts at the beginning of each displayed line, once the current instruction ends
+    pub fn dma_due(&self, cycle: u32) -> bool {
+        self.enabled
+            && self.next_line < DISPLAY_LINES
+            && cycle >= FIRST_DMA_CYCLE + self.next_line as u32 * CYCLES_PER_LINE
+    }
+
+    pub fn store_line(&mut self, bytes: &[u8; BYTES_PER_LINE]) {
+        let start = self.next_line * BYTES_PER_LINE;
+        self.frame[start..start + BYTES_PER_LINE].copy_from_slice(bytes);
+        self.next_line += 1;
+    }
+
+    // The last frame, sampled as a 64x32 grid (one line out of four)
+    pub fn grid(&self) -> Vec<bool> {
+        let rows = DISPLAY_LINES / 4;
+        let mut grid = Vec::with_capacity(DISPLAY_WIDTH * rows);
+
+        for row in 0..rows {
+            let line = &self.frame[row * 4 * BYTES_PER_LINE..(row * 4 + 1) * BYTES_PER_LINE];
+            for x in 0..DISPLAY_WIDTH {
+                grid.push(line[x / 8] & (0x80 >> (x % 8)) != 0);
+            }
+        }
+
+        grid
+    }
+}
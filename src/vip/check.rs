@@ -0,0 +1,322 @@
+use super::Vip;
+use crate::capture;
+use crate::chip8::state::{Chip8Options, Chip8State, ResyncState, VIP_STACK_DEPTH};
+use crate::frontend::headless::{self, InputEvent};
+use crate::frontend::CELL_SIZE;
+use crate::options::VipOptions;
+use crate::palette::Palette;
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+pub struct Divergence {
+    pub instruction: u64,
+    pub address: usize,
+    pub opcode: u16,
+    pub differences: Vec<String>,
+}
+
+pub struct CheckReport {
+    pub instructions: u64,
+    // Instructions after which Chip8State was resynchronised with the VIP
+    pub resyncs: u64,
+    pub divergence: Option<Divergence>,
+}
+
+// Differences between the state of the original interpreter and Chip8State
+fn compare(vip: &Vip, state: &Chip8State) -> Vec<String> {
+    let mut differences = Vec::new();
+
+    if vip.chip8_program_counter() != state.program_counter() {
+        differences.push(format!(
+            "PC: VIP {:03X}, Chip8State {:03X}",
+            vip.chip8_program_counter(),
+            state.program_counter()
+        ));
+    }
+
+    // The VIP font is in the monitor ROM, I cannot match when pointing to it
+    if vip.chip8_index_register() < 0x1000 && vip.chip8_index_register() != state.index_register() {
+        differences.push(format!(
+            "I: VIP {:03X}, Chip8State {:03X}",
+            vip.chip8_index_register(),
+            state.index_register()
+        ));
+    }
+
+    for (index, (vip_value, value)) in vip
+        .chip8_registers()
+        .iter()
+        .zip(state.registers())
+        .enumerate()
+    {
+        if vip_value != value {
+            differences.push(format!(
+                "V{:X}: VIP {:02X}, Chip8State {:02X}",
+                index, vip_value, value
+            ));
+        }
+    }
+
+    let vip_memory = vip.chip8_program_memory();
+    let memory = &state.memory()[0x200..0x200 + vip_memory.len()];
+    if let Some(offset) = vip_memory.iter().zip(memory).position(|(a, b)| a != b) {
+        differences.push(format!(
+            "memory at {:03X}: VIP {:02X}, Chip8State {:02X}",
+            0x200 + offset,
+            vip_memory[offset],
+            memory[offset]
+        ));
+    }
+
+    if vip.chip8_grid() != state.grid {
+        differences.push(String::from("display contents differ"));
+    }
+
+    differences
+}
+
+// Runs Chip8State in lockstep with the VIP, comparing them each time the interpreter goes
+// back to its fetch loop
+pub fn cross_check(
+    vip: &mut Vip,
+    state: &mut Chip8State,
+    fetch_address: u16,
+    frames: u64,
+    input_events: &[InputEvent],
+) -> CheckReport {
+    let mut report = CheckReport {
+        instructions: 0,
+        resyncs: 0,
+        divergence: None,
+    };
+
+    // The interpreter runs a couple of instructions of its own before jumping to 0x200
+    let mut started = false;
+    let mut interrupt_count = vip.interrupt_count();
+    // An interrupt taken in the fetch loop returns to it without an instruction being run
+    let mut ignore_next_fetch = false;
+
+    let keys = Rc::new(RefCell::new([false; 16]));
+    let callback_keys = Rc::clone(&keys);
+    state.set_key_callback(Box::new(move |key| callback_keys.borrow()[key as usize]));
+
+    let mut next_event = input_events.iter().peekable();
+
+    while vip.frame_count() < frames {
+        while let Some(event) = next_event.peek() {
+            if event.frame > vip.frame_count() {
+                break;
+            }
+
+            vip.set_key(event.key, event.down);
+            keys.borrow_mut()[event.key as usize] = event.down;
+            if event.down {
+                state.on_key_pressed(event.key);
+            }
+
+            next_event.next();
+        }
+
+        let was_at_fetch = vip.cpu().program_counter() == fetch_address;
+        vip.step();
+
+        if vip.interrupt_count() != interrupt_count {
+            interrupt_count = vip.interrupt_count();
+            ignore_next_fetch = was_at_fetch;
+            if started {
+                state.tick_timers();
+            }
+            continue;
+        }
+
+        if was_at_fetch || vip.cpu().program_counter() != fetch_address {
+            continue;
+        }
+
+        if ignore_next_fetch {
+            ignore_next_fetch = false;
+            continue;
+        }
+
+        if !started {
+            started = vip.chip8_program_counter() == 0x200;
+            continue;
+        }
+
+        let address = state.program_counter();
+        let memory = state.memory();
+        let opcode = match (memory.get(address), memory.get(address + 1)) {
+            (Some(&high), Some(&low)) => u16::from(high) << 8 | u16::from(low),
+            _ => 0,
+        };
+
+        report.instructions += 1;
+
+        let mut differences = match state.tick() {
+            Ok(()) => Vec::new(),
+            Err(e) => vec![format!("Chip8State error: {}", e)],
+        };
+
+        // Random numbers and machine code cannot be reproduced
+        let is_machine_code = opcode & 0xF000 == 0x0000 && opcode != 0x00E0 && opcode != 0x00EE;
+        if differences.is_empty() && (is_machine_code || opcode & 0xF000 == 0xC000) {
+            state.resync(ResyncState {
+                program_memory: vip.chip8_program_memory(),
+                registers: vip.chip8_registers(),
+                index_register: vip.chip8_index_register(),
+                program_counter: vip.chip8_program_counter(),
+                grid: vip.chip8_grid(),
+                stack: vip.chip8_stack(),
+                delay_timer: vip.chip8_delay_timer(),
+            });
+            report.resyncs += 1;
+            continue;
+        }
+
+        differences.extend(compare(vip, state));
+        if !differences.is_empty() {
+            report.divergence = Some(Divergence {
+                instruction: report.instructions,
+                address,
+                opcode,
+                differences,
+            });
+            break;
+        }
+    }
+
+    report
+}
+
+pub fn run(options: &VipOptions) -> Result<(), Box<dyn Error>> {
+    let read = |path: &str| fs::read(path).map_err(|e| format!("Failed to open {}: {}", path, e));
+
+    let monitor = read(&options.monitor_path)?;
+    let interpreter = read(&options.interpreter_path)?;
+    let program = read(&options.rom_path)?;
+
+    let input_events = match &options.input_script {
+        Some(path) => headless::load_input_script(path)?,
+        None => Vec::new(),
+    };
+
+    let mut vip = Vip::new(&monitor, &interpreter, &program)?;
+
+    if options.cross_check {
+        // Same stack as the VIP interpreter, so deep calls and stack contents match
+        let mut state = Chip8State::with_options(
+            program,
+            Chip8Options {
+                stack_depth: VIP_STACK_DEPTH,
+                stack_in_memory: true,
+                ..Chip8Options::default()
+            },
+        );
+        let report = cross_check(
+            &mut vip,
+            &mut state,
+            options.fetch_address,
+            options.frames,
+            &input_events,
+        );
+
+        println!(
+            "{} instructions compared, {} resynchronisations",
+            report.instructions, report.resyncs
+        );
+
+        if let Some(divergence) = report.divergence {
+            println!(
+                "Divergence after instruction {} ({:04X} at {:03X}):",
+                divergence.instruction, divergence.opcode, divergence.address
+            );
+            for difference in &divergence.differences {
+                println!("    {}", difference);
+            }
+
+            return Err("Chip8State does not match the VIP interpreter".into());
+        }
+    } else {
+        let mut next_event = input_events.iter().peekable();
+
+        while vip.frame_count() < options.frames {
+            while let Some(event) = next_event.peek() {
+                if event.frame > vip.frame_count() {
+                    break;
+                }
+
+                vip.set_key(event.key, event.down);
+                next_event.next();
+            }
+
+            vip.run_frame();
+        }
+    }
+
+    let grid = vip.grid();
+    match &options.screen_dump {
+        Some(path) if path.ends_with(".png") => {
+            capture::save_png(&grid, CELL_SIZE, &Palette::default(), Path::new(path))?
+        }
+        Some(path) => fs::write(path, capture::grid_to_ascii(&grid))?,
+        None => io::stdout().write_all(capture::grid_to_ascii(&grid).as_bytes())?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vip::DEFAULT_FETCH_ADDRESS;
+    use std::env;
+
+    // The monitor ROM and the interpreter are not redistributable, the test reads them from
+    // CHIP8_VIP_MONITOR and CHIP8_VIP_INTERPRETER
+    #[test]
+    #[ignore = "needs the VIP images in CHIP8_VIP_MONITOR and CHIP8_VIP_INTERPRETER"]
+    fn chip8_state_matches_the_vip_interpreter() {
+        let read = |variable: &str| {
+            let path = env::var(variable).unwrap_or_else(|_| panic!("{} is not set", variable));
+            fs::read(&path).unwrap_or_else(|e| panic!("Failed to open {}: {}", path, e))
+        };
+        let monitor = read("CHIP8_VIP_MONITOR");
+        let interpreter = read("CHIP8_VIP_INTERPRETER");
+        let program = fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("roms")
+                .join("MAZE"),
+        )
+        .unwrap();
+
+        let mut vip = Vip::new(&monitor, &interpreter, &program).unwrap();
+        let mut state = Chip8State::with_options(
+            program,
+            Chip8Options {
+                stack_depth: VIP_STACK_DEPTH,
+                stack_in_memory: true,
+                ..Chip8Options::default()
+            },
+        );
+
+        let report = cross_check(&mut vip, &mut state, DEFAULT_FETCH_ADDRESS, 120, &[]);
+
+        if let Some(divergence) = report.divergence {
+            panic!(
+                "Divergence after instruction {} ({:04X} at {:03X}): {:?}",
+                divergence.instruction,
+                divergence.opcode,
+                divergence.address,
+                divergence.differences
+            );
+        }
+        assert!(report.instructions > 100);
+        assert_eq!(&vip.chip8_registers(), state.registers());
+        assert_eq!(vip.chip8_grid(), state.grid);
+        assert!(state.grid.contains(&true));
+    }
+}
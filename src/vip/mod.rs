@@ -0,0 +1,226 @@
+pub mod cdp1802;
+pub mod cdp1861;
+pub mod check;
+
+use self::cdp1802::{Bus, Cdp1802};
+use self::cdp1861::{Cdp1861, BYTES_PER_LINE, CYCLES_PER_FRAME};
+use crate::chip8::keys::Key;
+
+pub const RAM_SIZE: usize = 4096;
+pub const MONITOR_SIZE: usize = 512;
+
+const MONITOR_ADDRESS: u16 = 0x8000;
+
+// Address of the fetch loop of the original CHIP-8 interpreter, reached between instructions
+pub const DEFAULT_FETCH_ADDRESS: u16 = 0x001B;
+
+// Everything on the VIP board but the CPU
+struct Board {
+    ram: Vec<u8>,
+    monitor: Vec<u8>,
+    // After a reset the monitor is also mapped at 0x0000, until the first access with A15 set
+    monitor_at_zero: bool,
+    video: Cdp1861,
+    keys: [bool; 16],
+    key_latch: u8,
+}
+
+impl Bus for Board {
+    fn read(&mut self, address: u16) -> u8 {
+        if address >= MONITOR_ADDRESS {
+            self.monitor_at_zero = false;
+            self.monitor[address as usize % MONITOR_SIZE]
+        } else if self.monitor_at_zero {
+            self.monitor[address as usize % MONITOR_SIZE]
+        } else {
+            self.ram[address as usize % RAM_SIZE]
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address < MONITOR_ADDRESS {
+            self.ram[address as usize % RAM_SIZE] = value;
+        }
+    }
+
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 {
+            self.video.set_enabled(true);
+        }
+
+        0
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.video.set_enabled(false),
+            // Selects the key EF3 reports
+            2 => self.key_latch = value & 0x0F,
+            _ => (),
+        }
+    }
+}
+
+// COSMAC VIP running its monitor ROM and the CHIP-8 interpreter from RAM
+pub struct Vip {
+    cpu: Cdp1802,
+    board: Board,
+    frame_cycle: u32,
+    frame_count: u64,
+    interrupt_count: u64,
+}
+
+impl Vip {
+    // The interpreter image is loaded at 0x0000 and the CHIP-8 program at 0x0200
+    pub fn new(monitor: &[u8], interpreter: &[u8], program: &[u8]) -> Result<Vip, String> {
+        if monitor.len() != MONITOR_SIZE {
+            return Err(format!(
+                "The monitor ROM must be {} bytes, got {}",
+                MONITOR_SIZE,
+                monitor.len()
+            ));
+        }
+
+        if interpreter.len() > RAM_SIZE || 0x200 + program.len() > RAM_SIZE {
+            return Err(String::from(
+                "The interpreter or program does not fit in memory",
+            ));
+        }
+
+        let mut ram = vec![0; RAM_SIZE];
+        ram[..interpreter.len()].copy_from_slice(interpreter);
+        ram[0x200..0x200 + program.len()].copy_from_slice(program);
+
+        Ok(Vip {
+            cpu: Cdp1802::new(),
+            board: Board {
+                ram,
+                monitor: monitor.to_vec(),
+                monitor_at_zero: true,
+                video: Cdp1861::new(),
+                keys: [false; 16],
+                key_latch: 0,
+            },
+            frame_cycle: 0,
+            frame_count: 0,
+            interrupt_count: 0,
+        })
+    }
+
+    pub fn cpu(&self) -> &Cdp1802 {
+        &self.cpu
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Display interrupts taken, the interpreter decrements its timers in each of them
+    pub fn interrupt_count(&self) -> u64 {
+        self.interrupt_count
+    }
+
+    pub fn set_key(&mut self, key: Key, down: bool) {
+        self.board.keys[key as usize] = down;
+    }
+
+    // What the 1861 displayed during the last frame
+    pub fn grid(&self) -> Vec<bool> {
+        self.board.video.grid()
+    }
+
+    // Runs one instruction, or a DMA burst or an interrupt when the 1861 requests it
+    pub fn step(&mut self) {
+        let cycles = if self.board.video.dma_due(self.frame_cycle) {
+            let mut line = [0; BYTES_PER_LINE];
+            for byte in line.iter_mut() {
+                *byte = self.cpu.dma_out(&mut self.board);
+            }
+            self.board.video.store_line(&line);
+
+            BYTES_PER_LINE as u32
+        } else if self.board.video.interrupt_requested(self.frame_cycle) && self.cpu.interrupt() {
+            self.interrupt_count += 1;
+            1
+        } else {
+            self.cpu.ef[0] = self.board.video.efx(self.frame_cycle);
+            self.cpu.ef[2] = self.board.keys[self.board.key_latch as usize];
+            self.cpu.step(&mut self.board)
+        };
+
+        self.frame_cycle += cycles;
+        if self.frame_cycle >= CYCLES_PER_FRAME {
+            self.frame_cycle -= CYCLES_PER_FRAME;
+            self.frame_count += 1;
+            self.board.video.start_frame();
+        }
+    }
+
+    pub fn run_frame(&mut self) {
+        let frame = self.frame_count;
+        while self.frame_count == frame {
+            self.step();
+        }
+    }
+
+    // The interpreter keeps the CHIP-8 program counter in R5 and I in RA. The display page
+    // is in RB, with the variables (V0-VF at 0xF0) in the page below it.
+    pub fn chip8_program_counter(&self) -> usize {
+        self.cpu.r[0x5] as usize
+    }
+
+    pub fn chip8_index_register(&self) -> u16 {
+        self.cpu.r[0xA]
+    }
+
+    fn display_page(&self) -> usize {
+        (self.cpu.r[0xB] >> 8) as usize % (RAM_SIZE >> 8)
+    }
+
+    fn variables_page(&self) -> usize {
+        (self.display_page() + (RAM_SIZE >> 8) - 1) % (RAM_SIZE >> 8)
+    }
+
+    pub fn chip8_registers(&self) -> [u8; 16] {
+        let start = self.variables_page() << 8 | 0xF0;
+
+        let mut registers = [0; 16];
+        registers.copy_from_slice(&self.board.ram[start..start + 16]);
+        registers
+    }
+
+    // Return addresses, the last one being the top of the stack. The stack grows down from
+    // 0xCF in the variables page, R2 pointing below its last entry.
+    pub fn chip8_stack(&self) -> Vec<u16> {
+        let top = self.variables_page() << 8 | 0xCF;
+        let depth = top.saturating_sub(self.cpu.r[0x2] as usize % RAM_SIZE) / 2;
+
+        (0..depth)
+            .map(|i| {
+                let address = top - 2 * i;
+                u16::from(self.board.ram[address - 1]) << 8 | u16::from(self.board.ram[address])
+            })
+            .collect()
+    }
+
+    // The interrupt routine decrements it in R8.1, and the sound timer in R8.0
+    pub fn chip8_delay_timer(&self) -> u8 {
+        (self.cpu.r[0x8] >> 8) as u8
+    }
+
+    // The display memory seen by the interpreter, one bit per pixel
+    pub fn chip8_grid(&self) -> Vec<bool> {
+        let start = self.display_page() << 8;
+
+        self.board.ram[start..start + 256]
+            .iter()
+            .flat_map(|&byte| (0..8).map(move |bit| byte & (0x80 >> bit) != 0))
+            .collect()
+    }
+
+    // Interpreter variables, stack and display occupy the top of memory
+    pub fn chip8_program_memory(&self) -> &[u8] {
+        let end = self.variables_page() << 8 | 0xA0;
+        &self.board.ram[0x200..end.max(0x200)]
+    }
+}